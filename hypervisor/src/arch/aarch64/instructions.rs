@@ -18,6 +18,12 @@ pub fn irqs_disabled() -> bool {
     DAIF.matches_all(DAIF::I::Masked)
 }
 
+/// ID of the current CPU, the affinity fields of MPIDR_EL1.
+#[inline]
+pub fn cpu_id() -> usize {
+    (MPIDR_EL1.get() & 0xff_ffff) as usize
+}
+
 #[inline]
 pub fn thread_pointer() -> usize {
     TPIDR_EL1.get() as _
@@ -2,11 +2,13 @@
 //! TODO: split GIC to GICC, GICD, GICH and GICV.
 #![allow(dead_code)]
 
+use rvm::arch::{choose_list_register, ListRegister, LrChoice};
 use spin::Mutex;
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_structs;
 use tock_registers::registers::{ReadOnly, ReadWrite, WriteOnly};
 
+use super::intc::{GicVersion, IntrController, PPI_BASE, SPI_BASE};
 use crate::mm::{PhysAddr, VirtAddr};
 // use crate::sync::LazyInit;
 // use crate::utils::irq_handler::{IrqHandler, IrqHandlerTable};
@@ -16,20 +18,30 @@ const GICD_BASE: PhysAddr = GIC_BASE;
 const GICC_BASE: PhysAddr = GIC_BASE + 0x10000;
 const GICH_BASE: PhysAddr = GIC_BASE + 0x30000;
 const GICV_BASE: PhysAddr = GIC_BASE + 0x40000;
-
-const PPI_BASE: usize = 16;
-const SPI_BASE: usize = 32;
+/// Size of each of the GICD, GICC, GICH and GICV frames.
+const GIC_FRAME_SIZE: usize = 0x10000;
 
 const IRQ_COUNT: usize = 1024;
-const TIMER_IRQ: usize = 30;
+/// Largest number of CPUs, which have one bit each in ITARGETSR.
+const MAX_CPUS: usize = 8;
 
 // mask
 const LR_VIRTIRQ_MASK: usize = 0x3ff;
 const LR_PHYSIRQ_MASK: usize = 0x3ff << 10;
+const LR_CPUID_MASK: usize = 0b111 << 10;
 
+const LR_PRIORITY_SHIFT: u32 = 23;
 const LR_PENDING_BIT: u32 = 1 << 28;
+const SPURIOUS_IRQ: u32 = 1020;
 const LR_HW_BIT: u32 = 1 << 31;
 
+// GICH_HCR
+const HCR_EN: u32 = 1 << 0;
+/// Underflow: at most one list register is in use.
+const HCR_UIE: u32 = 1 << 1;
+/// No list register holds a pending interrupt.
+const HCR_NPIE: u32 = 1 << 3;
+
 static GIC: Mutex<Gic> = Mutex::new(Gic::new(GICD_BASE, GICC_BASE, GICH_BASE, GICV_BASE));
 // static HANDLERS: IrqHandlerTable<IRQ_COUNT> = IrqHandlerTable::new();
 
@@ -238,6 +250,53 @@ impl Gic {
         }
     }
 
+    fn irq_bit(&self, vector: usize) -> (usize, u32) {
+        assert!(vector < self.max_irqs);
+        (vector / 32, 1 << (vector % 32))
+    }
+
+    fn set_pending(&self, vector: usize, pending: bool) {
+        let (reg, mask) = self.irq_bit(vector);
+        if pending {
+            self.gicd().ISPENDR[reg].set(mask);
+        } else {
+            self.gicd().ICPENDR[reg].set(mask);
+        }
+    }
+
+    fn set_active(&self, vector: usize, active: bool) {
+        let (reg, mask) = self.irq_bit(vector);
+        if active {
+            self.gicd().ISACTIVER[reg].set(mask);
+        } else {
+            self.gicd().ICACTIVER[reg].set(mask);
+        }
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        let (reg, mask) = self.irq_bit(vector);
+        self.gicd().ISPENDR[reg].get() & mask != 0
+    }
+
+    fn is_active(&self, vector: usize) -> bool {
+        let (reg, mask) = self.irq_bit(vector);
+        self.gicd().ISACTIVER[reg].get() & mask != 0
+    }
+
+    /// Set the byte of `vector` in the byte-per-interrupt registers `regs`.
+    fn set_irq_byte(&self, regs: &[ReadWrite<u32>], vector: usize, val: u8) {
+        assert!(vector < self.max_irqs);
+        let shift = (vector % 4) * 8;
+        let reg = &regs[vector / 4];
+        reg.set(reg.get() & !(0xff << shift) | (val as u32) << shift);
+    }
+
+    fn send_sgi(&self, sgi: usize, cpu_mask: u8) {
+        assert!(sgi < PPI_BASE);
+        // TargetListFilter 0: forward to the CPUs in the target list.
+        self.gicd().SGIR.set((cpu_mask as u32) << 16 | sgi as u32);
+    }
+
     fn lr_num(&self) -> usize {
         (self.gich().VTR.get() as usize & 0b11111) + 1
     }
@@ -260,45 +319,76 @@ impl Gic {
         }
     }
 
-    fn inject_irq(&self, irq_id: usize) {
+    fn guest_irq_pending(&self) -> bool {
+        let elsr = (self.gich().ELSR1.get() as u64) << 32 | self.gich().ELSR0.get() as u64;
+        let lr_pending = (0..self.lr_num())
+            .any(|i| elsr & (1 << i) == 0 && self.read_lr(i) & LR_PENDING_BIT != 0);
+        // Interrupts of the guest not forwarded by the hypervisor are pending
+        // in the physical CPU interface.
+        lr_pending || (self.gicc().HPPIR.get() & 0x3ff) < SPURIOUS_IRQ
+    }
+
+    /// Write the virtual interrupt `irq_id` with `priority` to a free list
+    /// register. `source` is the requesting CPU of a virtual SGI. Returns
+    /// `false` if all list registers are in use.
+    fn inject_irq(&self, irq_id: usize, hw: bool, source: usize, priority: u8) -> bool {
+        // The CPUID field is part of the physical ID of hardware interrupts.
+        let source = if hw {
+            0
+        } else {
+            ((source << 10) & LR_CPUID_MASK) >> 10
+        };
         let elsr: u64 = (self.gich().ELSR1.get() as u64) << 32 | self.gich().ELSR0.get() as u64;
-        let lr_num = self.lr_num();
-        let mut lr_idx = -1 as isize;
-        for i in 0..lr_num {
-            if (1 << i) & elsr > 0 {
-                if lr_idx == -1 {
-                    lr_idx = i as isize;
-                }
-                continue;
+        let lrs = (0..self.lr_num()).map(|i| {
+            if (1 << i) & elsr != 0 {
+                return None;
             }
-
-            // overlap
-            let lr_val = self.read_lr(i) as usize;
-            if (i & LR_VIRTIRQ_MASK) == irq_id {
-                return;
+            let lr_val = self.read_lr(i);
+            let hw = lr_val & LR_HW_BIT != 0;
+            Some(ListRegister {
+                irq: lr_val as usize & LR_VIRTIRQ_MASK,
+                hw,
+                source: if hw {
+                    0
+                } else {
+                    (lr_val as usize & LR_CPUID_MASK) >> 10
+                },
+            })
+        });
+        let lr_idx = match choose_list_register(lrs, irq_id, hw, source) {
+            LrChoice::Free(i) => i,
+            LrChoice::Merge(i) => {
+                // Only purely virtual interrupts can be pending and active.
+                let lr_val = self.read_lr(i);
+                if lr_val & LR_HW_BIT == 0 {
+                    self.write_lr(i, lr_val | LR_PENDING_BIT);
+                }
+                if hw {
+                    self.gicc().DIR.set(irq_id as _);
+                }
+                return true;
             }
+            LrChoice::Full => return false,
+        };
+        // The list registers hold the 5 most significant bits of the priority.
+        let mut val = (irq_id | source << 10) as u32
+            | LR_PENDING_BIT
+            | ((priority >> 3) as u32) << LR_PRIORITY_SHIFT;
+        if hw {
+            val |= ((irq_id << 10) & LR_PHYSIRQ_MASK) as u32;
+            val |= LR_HW_BIT;
         }
-        debug!("To Inject IRQ {}, find lr {}", irq_id, lr_idx);
+        debug!("To write lr {} val {:#x}", lr_idx, val);
+        self.write_lr(lr_idx, val);
+        true
+    }
 
-        if lr_idx == -1 {
-            return;
+    fn set_refill_irq(&self, enable: bool) {
+        let hcr = self.gich().HCR.get();
+        if enable {
+            self.gich().HCR.set(hcr | HCR_UIE | HCR_NPIE);
         } else {
-            let mut val = 0;
-
-            val = irq_id as u32;
-            val |= LR_PENDING_BIT;
-
-            if false
-            /* sgi */
-            {
-                todo!()
-            } else {
-                val |= ((irq_id << 10) & LR_PHYSIRQ_MASK) as u32;
-                val |= LR_HW_BIT;
-            }
-
-            debug!("To write lr {} val {}", lr_idx, val);
-            self.write_lr(lr_idx as usize, val);
+            self.gich().HCR.set(hcr & !(HCR_UIE | HCR_NPIE));
         }
     }
 
@@ -306,15 +396,15 @@ impl Gic {
         self.gicc().EOIR.set(vector as _);
     }
 
+    fn deactivate(&self, vector: usize) {
+        self.gicc().DIR.set(vector as _);
+    }
+
     fn init(&mut self) {
         self.max_irqs = ((self.gicd().TYPER.get() as usize & 0b11111) + 1) * 32;
 
         let gicd = self.gicd();
-        let gicc = self.gicc();
-        let gich = self.gich();
-        let gicv = self.gicv();
-
-        for i in (0..self.max_irqs).step_by(32) {
+        for i in (SPI_BASE..self.max_irqs).step_by(32) {
             gicd.ICENABLER[i / 32].set(u32::MAX);
             gicd.ICPENDR[i / 32].set(u32::MAX);
         }
@@ -331,32 +421,129 @@ impl Gic {
 
         // enable GIC
         gicd.CTLR.set(1);
+    }
+
+    fn init_percpu(&self) {
+        let gicd = self.gicd();
+        let gicc = self.gicc();
+        let gich = self.gich();
+
+        // SGIs and PPIs are banked.
+        gicd.ICENABLER[0].set(u32::MAX);
+        gicd.ICPENDR[0].set(u32::MAX);
+
         gicc.CTLR.set(0x201); // EOIMode | En
         gich.VMCR.set(0x201);
-        gich.HCR.set(1);
+        gich.HCR.set(HCR_EN);
         // unmask interrupts at all priority levels
         gicc.PMR.set(0xff);
     }
 }
 
-pub fn set_enable(vector: usize, enable: bool) {
-    GIC.lock().set_enable(vector, enable);
-}
+/// The GICv2 of the platform.
+pub struct Gicv2;
 
-pub fn gicv_enabled() -> bool {
-    GIC.lock().gicv().CTLR.get() & 0x1 != 0
-}
+impl IntrController for Gicv2 {
+    fn version(&self) -> GicVersion {
+        GicVersion::V2
+    }
 
-pub fn pending_irq() -> Option<usize> {
-    GIC.lock().pending_irq()
-}
+    fn max_irqs(&self) -> usize {
+        GIC.lock().max_irqs
+    }
+
+    fn distributor_iidr(&self) -> u32 {
+        GIC.lock().gicd().IIDR.get()
+    }
+
+    fn vcpu_interface_base(&self) -> Option<PhysAddr> {
+        Some(GICV_BASE)
+    }
+
+    fn init(&self) {
+        for base in [GICD_BASE, GICC_BASE, GICH_BASE, GICV_BASE] {
+            crate::mm::map_device(base, GIC_FRAME_SIZE).unwrap();
+        }
+        GIC.lock().init();
+    }
+
+    fn init_percpu(&self) {
+        GIC.lock().init_percpu();
+    }
+
+    fn set_enable(&self, vector: usize, enable: bool) {
+        GIC.lock().set_enable(vector, enable);
+    }
+
+    fn set_pending(&self, vector: usize, pending: bool) {
+        GIC.lock().set_pending(vector, pending);
+    }
+
+    fn set_active(&self, vector: usize, active: bool) {
+        GIC.lock().set_active(vector, active);
+    }
+
+    fn irq_pending(&self, vector: usize) -> bool {
+        GIC.lock().is_pending(vector)
+    }
+
+    fn irq_active(&self, vector: usize) -> bool {
+        GIC.lock().is_active(vector)
+    }
+
+    fn set_priority(&self, vector: usize, priority: u8) {
+        let gic = GIC.lock();
+        gic.set_irq_byte(&gic.gicd().IPRIORITYR, vector, priority);
+    }
 
-pub fn inject_irq(irq_id: usize) {
-    GIC.lock().inject_irq(irq_id)
+    fn set_edge_triggered(&self, vector: usize, edge: bool) {
+        let tm = if edge {
+            TriggerMode::Edge
+        } else {
+            TriggerMode::Level
+        };
+        GIC.lock()
+            .configure_interrupt(vector, tm, Polarity::ActiveHigh);
+    }
+
+    fn route_spi(&self, vector: usize, cpu_id: usize) {
+        assert!(vector >= SPI_BASE && cpu_id < MAX_CPUS);
+        let gic = GIC.lock();
+        gic.set_irq_byte(&gic.gicd().ITARGETSR, vector, 1 << cpu_id);
+    }
+
+    fn send_sgi(&self, sgi: usize, cpu_id: usize) {
+        assert!(cpu_id < MAX_CPUS);
+        GIC.lock().send_sgi(sgi, 1 << cpu_id);
+    }
+
+    fn pending_irq(&self) -> Option<usize> {
+        GIC.lock().pending_irq()
+    }
+
+    fn eoi(&self, vector: usize) {
+        GIC.lock().eoi(vector)
+    }
+
+    fn deactivate(&self, vector: usize) {
+        GIC.lock().deactivate(vector)
+    }
+
+    fn inject(&self, irq_id: usize, hw: bool, source: usize, priority: u8) -> bool {
+        GIC.lock().inject_irq(irq_id, hw, source, priority)
+    }
+
+    fn set_refill_irq(&self, enable: bool) {
+        GIC.lock().set_refill_irq(enable)
+    }
+
+    fn guest_irq_pending(&self) -> bool {
+        GIC.lock().guest_irq_pending()
+    }
 }
 
-pub fn deactivate_irq(irq_id: usize) {
-    GIC.lock().eoi(irq_id)
+pub fn gicv_enabled() -> bool {
+    GIC.lock().gicv().CTLR.get() & 0x1 != 0
 }
 
 // pub fn pending_irq() -> Option<>
@@ -373,14 +560,3 @@ pub fn handle_irq(_vector: usize) {
 // pub fn register_handler(vector: usize, handler: IrqHandler) {
 //     // HANDLERS.register_handler(vector, handler);
 // }
-
-pub fn init() {
-    GIC.lock().init();
-    // ENABLE TIMER IRQ
-    // TODO: move this to timer::init
-    set_enable(TIMER_IRQ, true);
-    // set_enable(27, true);
-    // let gic = Gic::new(GICD_BASE.into_kvaddr(), GICC_BASE.into_kvaddr());
-    // gic.init();
-    // GIC.init_by(gic);
-}
@@ -0,0 +1,560 @@
+//! ARM Generic Interrupt Controller v3.
+//!
+//! The distributor and the per-CPU redistributors are memory-mapped, the CPU
+//! interface and the virtual CPU interface are the ICC_* and ICH_* system
+//! registers. SPIs are routed by CPU affinity, so unlike a GICv2 the number of
+//! CPUs is not limited to eight.
+
+use core::arch::asm;
+
+use rvm::arch::{choose_list_register, ListRegister, LrChoice};
+use spin::Mutex;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::register_structs;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+use super::intc::{GicVersion, IntrController, PPI_BASE, SPI_BASE};
+use crate::arch::instructions;
+use crate::config::CPU_NUM;
+use crate::mm::{PhysAddr, VirtAddr};
+
+const GICD_BASE: PhysAddr = 0x0800_0000;
+const GICD_SIZE: usize = 0x10000;
+/// The redistributors, each with an RD_base and an SGI_base frame.
+const GICR_BASE: PhysAddr = 0x080a_0000;
+const GICR_STRIDE: usize = 0x20000;
+
+/// Largest number of SPIs and PPIs, special INTIDs start at 1020.
+const MAX_IRQS: usize = 1020;
+
+// GICD_CTLR, non-secure view or single security state.
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// GICR_WAKER
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+// GICR_TYPER
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_TYPER_AFFINITY_SHIFT: u32 = 32;
+
+// ICC_SRE_EL2
+const ICC_SRE_SRE: u64 = 1 << 0;
+const ICC_SRE_DFB: u64 = 1 << 1;
+const ICC_SRE_DIB: u64 = 1 << 2;
+/// EL1 may access ICC_SRE_EL1.
+const ICC_SRE_ENABLE: u64 = 1 << 3;
+// ICC_CTLR_EL1
+const ICC_CTLR_EOIMODE: u64 = 1 << 1;
+
+// ICH_HCR_EL2
+const ICH_HCR_EN: u64 = 1 << 0;
+/// Underflow: at most one list register is in use.
+const ICH_HCR_UIE: u64 = 1 << 1;
+/// No list register holds a pending interrupt.
+const ICH_HCR_NPIE: u64 = 1 << 3;
+// ICH_VMCR_EL2
+const ICH_VMCR_VENG1: u64 = 1 << 1;
+const ICH_VMCR_VPMR_SHIFT: u32 = 24;
+
+// ICH_LR<n>_EL2
+const LR_VINTID_MASK: u64 = 0xffff_ffff;
+const LR_PINTID_SHIFT: u32 = 32;
+const LR_PRIORITY_SHIFT: u32 = 48;
+const LR_GROUP1: u64 = 1 << 60;
+const LR_HW: u64 = 1 << 61;
+const LR_PENDING: u64 = 1 << 62;
+
+static GIC: Mutex<Gic> = Mutex::new(Gic::new(GICD_BASE, GICR_BASE));
+
+register_structs! {
+    #[allow(non_snake_case)]
+    GicDistributorRegs {
+        /// Distributor Control Register.
+        (0x0000 => CTLR: ReadWrite<u32>),
+        /// Interrupt Controller Type Register.
+        (0x0004 => TYPER: ReadOnly<u32>),
+        /// Distributor Implementer Identification Register.
+        (0x0008 => IIDR: ReadOnly<u32>),
+        (0x000c => _reserved_0),
+        /// Interrupt Group Registers.
+        (0x0080 => IGROUPR: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Set-Enable Registers.
+        (0x0100 => ISENABLER: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Clear-Enable Registers.
+        (0x0180 => ICENABLER: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Set-Pending Registers.
+        (0x0200 => ISPENDR: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Clear-Pending Registers.
+        (0x0280 => ICPENDR: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Set-Active Registers.
+        (0x0300 => ISACTIVER: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Clear-Active Registers.
+        (0x0380 => ICACTIVER: [ReadWrite<u32>; 0x20]),
+        /// Interrupt Priority Registers.
+        (0x0400 => IPRIORITYR: [ReadWrite<u32>; 0x100]),
+        (0x0800 => _reserved_1),
+        /// Interrupt Configuration Registers.
+        (0x0c00 => ICFGR: [ReadWrite<u32>; 0x40]),
+        (0x0d00 => _reserved_2),
+        /// Interrupt Routing Registers, of SPIs.
+        (0x6000 => IROUTER: [ReadWrite<u64>; 0x400]),
+        (0x8000 => @END),
+    }
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    GicRedistributorRegs {
+        /// Redistributor Control Register.
+        (0x0000 => CTLR: ReadWrite<u32>),
+        /// Redistributor Implementer Identification Register.
+        (0x0004 => IIDR: ReadOnly<u32>),
+        /// Redistributor Type Register.
+        (0x0008 => TYPER: ReadOnly<u64>),
+        (0x0010 => _reserved_0),
+        /// Redistributor Wake Register.
+        (0x0014 => WAKER: ReadWrite<u32>),
+        (0x0018 => _reserved_1),
+        // The SGI_base frame, with the registers of SGIs and PPIs.
+        /// Interrupt Group Register 0.
+        (0x10080 => IGROUPR0: ReadWrite<u32>),
+        (0x10084 => _reserved_2),
+        /// Interrupt Set-Enable Register 0.
+        (0x10100 => ISENABLER0: ReadWrite<u32>),
+        (0x10104 => _reserved_3),
+        /// Interrupt Clear-Enable Register 0.
+        (0x10180 => ICENABLER0: ReadWrite<u32>),
+        (0x10184 => _reserved_4),
+        /// Interrupt Set-Pending Register 0.
+        (0x10200 => ISPENDR0: ReadWrite<u32>),
+        (0x10204 => _reserved_5),
+        /// Interrupt Clear-Pending Register 0.
+        (0x10280 => ICPENDR0: ReadWrite<u32>),
+        (0x10284 => _reserved_6),
+        /// Interrupt Set-Active Register 0.
+        (0x10300 => ISACTIVER0: ReadWrite<u32>),
+        (0x10304 => _reserved_7),
+        /// Interrupt Clear-Active Register 0.
+        (0x10380 => ICACTIVER0: ReadWrite<u32>),
+        (0x10384 => _reserved_8),
+        /// Interrupt Priority Registers.
+        (0x10400 => IPRIORITYR: [ReadWrite<u32>; 8]),
+        (0x10420 => _reserved_9),
+        /// Interrupt Configuration Registers.
+        (0x10c00 => ICFGR: [ReadWrite<u32>; 2]),
+        (0x10c08 => _reserved_10),
+        (0x20000 => @END),
+    }
+}
+
+macro_rules! read_sysreg {
+    ($name:literal) => {{
+        let val: u64;
+        unsafe { asm!(concat!("mrs {}, ", $name), out(reg) val) };
+        val
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($name:literal, $val:expr) => {
+        unsafe { asm!(concat!("msr ", $name, ", {}"), in(reg) $val as u64) }
+    };
+}
+
+fn read_lr(id: usize) -> u64 {
+    let val: u64;
+    macro_rules! mrs_lr {
+        ($($i:literal),*) => {
+            match id {
+                $($i => unsafe { asm!(concat!("mrs {}, ich_lr", $i, "_el2"), out(reg) val) },)*
+                _ => panic!("invalid list register {}", id),
+            }
+        };
+    }
+    mrs_lr!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    val
+}
+
+fn write_lr(id: usize, val: u64) {
+    macro_rules! msr_lr {
+        ($($i:literal),*) => {
+            match id {
+                $($i => unsafe { asm!(concat!("msr ich_lr", $i, "_el2, {}"), in(reg) val) },)*
+                _ => panic!("invalid list register {}", id),
+            }
+        };
+    }
+    msr_lr!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+}
+
+struct Gic {
+    gicd_base: VirtAddr,
+    gicr_base: VirtAddr,
+    /// The redistributor of each CPU, found by its affinity.
+    cpu_gicr: [Option<VirtAddr>; CPU_NUM],
+    max_irqs: usize,
+}
+
+impl Gic {
+    const fn new(gicd_base: VirtAddr, gicr_base: VirtAddr) -> Self {
+        Self {
+            gicd_base,
+            gicr_base,
+            cpu_gicr: [None; CPU_NUM],
+            max_irqs: 0,
+        }
+    }
+
+    const fn gicd(&self) -> &GicDistributorRegs {
+        unsafe { &*(self.gicd_base as *const _) }
+    }
+
+    /// The redistributor of the current CPU.
+    fn gicr(&self) -> &GicRedistributorRegs {
+        let base = self.cpu_gicr[instructions::cpu_id()].expect("no redistributor");
+        unsafe { &*(base as *const _) }
+    }
+
+    /// Find the redistributor whose affinity is the CPU ID `cpu_id`.
+    fn find_gicr(&self, cpu_id: usize) -> Option<VirtAddr> {
+        for i in 0..CPU_NUM {
+            let base = self.gicr_base + i * GICR_STRIDE;
+            let typer = unsafe { &*(base as *const GicRedistributorRegs) }
+                .TYPER
+                .get();
+            if (typer >> GICR_TYPER_AFFINITY_SHIFT) as usize == cpu_id {
+                return Some(base);
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+        }
+        None
+    }
+
+    fn wait_for_rwp(&self) {
+        while self.gicd().CTLR.get() & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn irq_bit(&self, vector: usize) -> (usize, u32) {
+        assert!(vector < self.max_irqs);
+        (vector / 32, 1 << (vector % 32))
+    }
+
+    /// Write `mask` to the set or clear register of SGIs and PPIs in the
+    /// redistributor, or of SPIs in the distributor.
+    fn set_or_clear(
+        &self,
+        vector: usize,
+        set: bool,
+        private: [&ReadWrite<u32>; 2],
+        shared: [&[ReadWrite<u32>]; 2],
+    ) {
+        let (reg, mask) = self.irq_bit(vector);
+        let i = if set { 0 } else { 1 };
+        if vector < SPI_BASE {
+            private[i].set(mask);
+        } else {
+            shared[i][reg].set(mask);
+        }
+    }
+
+    fn set_enable(&self, vector: usize, enable: bool) {
+        let (gicd, gicr) = (self.gicd(), self.gicr());
+        self.set_or_clear(
+            vector,
+            enable,
+            [&gicr.ISENABLER0, &gicr.ICENABLER0],
+            [&gicd.ISENABLER, &gicd.ICENABLER],
+        );
+    }
+
+    fn set_pending(&self, vector: usize, pending: bool) {
+        let (gicd, gicr) = (self.gicd(), self.gicr());
+        self.set_or_clear(
+            vector,
+            pending,
+            [&gicr.ISPENDR0, &gicr.ICPENDR0],
+            [&gicd.ISPENDR, &gicd.ICPENDR],
+        );
+    }
+
+    fn set_active(&self, vector: usize, active: bool) {
+        let (gicd, gicr) = (self.gicd(), self.gicr());
+        self.set_or_clear(
+            vector,
+            active,
+            [&gicr.ISACTIVER0, &gicr.ICACTIVER0],
+            [&gicd.ISACTIVER, &gicd.ICACTIVER],
+        );
+    }
+
+    fn is_pending(&self, vector: usize) -> bool {
+        let (reg, mask) = self.irq_bit(vector);
+        let val = if vector < SPI_BASE {
+            self.gicr().ISPENDR0.get()
+        } else {
+            self.gicd().ISPENDR[reg].get()
+        };
+        val & mask != 0
+    }
+
+    fn is_active(&self, vector: usize) -> bool {
+        let (reg, mask) = self.irq_bit(vector);
+        let val = if vector < SPI_BASE {
+            self.gicr().ISACTIVER0.get()
+        } else {
+            self.gicd().ISACTIVER[reg].get()
+        };
+        val & mask != 0
+    }
+
+    fn set_priority(&self, vector: usize, priority: u8) {
+        assert!(vector < self.max_irqs);
+        let reg = if vector < SPI_BASE {
+            &self.gicr().IPRIORITYR[vector / 4]
+        } else {
+            &self.gicd().IPRIORITYR[vector / 4]
+        };
+        let shift = (vector % 4) * 8;
+        reg.set(reg.get() & !(0xff << shift) | (priority as u32) << shift);
+    }
+
+    fn set_edge_triggered(&self, vector: usize, edge: bool) {
+        // The trigger mode of SGIs and PPIs is fixed by the hardware.
+        assert!(vector >= SPI_BASE && vector < self.max_irqs);
+        // Two bits per interrupt, the most significant one is the trigger mode.
+        let reg = &self.gicd().ICFGR[vector / 16];
+        let bit = 1 << ((vector % 16) * 2 + 1);
+        if edge {
+            reg.set(reg.get() | bit);
+        } else {
+            reg.set(reg.get() & !bit);
+        }
+    }
+
+    fn lr_num(&self) -> usize {
+        (read_sysreg!("ich_vtr_el2") as usize & 0b11111) + 1
+    }
+
+    fn guest_irq_pending(&self) -> bool {
+        let elrsr = read_sysreg!("ich_elrsr_el2");
+        let lr_pending =
+            (0..self.lr_num()).any(|i| elrsr & (1 << i) == 0 && read_lr(i) & LR_PENDING != 0);
+        // Interrupts of the guest not forwarded by the hypervisor are pending
+        // in the physical CPU interface.
+        lr_pending || (read_sysreg!("icc_hppir1_el1") as usize & 0xff_ffff) < MAX_IRQS
+    }
+
+    fn inject_irq(&self, irq_id: usize, hw: bool, priority: u8) -> bool {
+        let elrsr = read_sysreg!("ich_elrsr_el2");
+        let lrs = (0..self.lr_num()).map(|i| {
+            if elrsr & (1 << i) != 0 {
+                return None;
+            }
+            let lr_val = read_lr(i);
+            Some(ListRegister {
+                irq: (lr_val & LR_VINTID_MASK) as usize,
+                hw: lr_val & LR_HW != 0,
+                source: 0,
+            })
+        });
+        let lr_idx = match choose_list_register(lrs, irq_id, hw, 0) {
+            LrChoice::Free(i) => i,
+            LrChoice::Merge(i) => {
+                // Only purely virtual interrupts can be pending and active.
+                let lr_val = read_lr(i);
+                if lr_val & LR_HW == 0 {
+                    write_lr(i, lr_val | LR_PENDING);
+                }
+                if hw {
+                    write_sysreg!("icc_dir_el1", irq_id);
+                }
+                return true;
+            }
+            LrChoice::Full => return false,
+        };
+        let mut val =
+            irq_id as u64 | LR_PENDING | LR_GROUP1 | (priority as u64) << LR_PRIORITY_SHIFT;
+        if hw {
+            val |= (irq_id as u64) << LR_PINTID_SHIFT | LR_HW;
+        }
+        debug!("To write lr {} val {:#x}", lr_idx, val);
+        write_lr(lr_idx, val);
+        true
+    }
+
+    fn set_refill_irq(&self, enable: bool) {
+        let hcr = read_sysreg!("ich_hcr_el2");
+        if enable {
+            write_sysreg!("ich_hcr_el2", hcr | ICH_HCR_UIE | ICH_HCR_NPIE);
+        } else {
+            write_sysreg!("ich_hcr_el2", hcr & !(ICH_HCR_UIE | ICH_HCR_NPIE));
+        }
+    }
+
+    fn init(&mut self) {
+        let it_lines = (self.gicd().TYPER.get() as usize & 0b11111) + 1;
+        self.max_irqs = (it_lines * 32).min(MAX_IRQS);
+
+        let gicd = self.gicd();
+
+        gicd.CTLR.set(0);
+        self.wait_for_rwp();
+        for i in (SPI_BASE..self.max_irqs).step_by(32) {
+            gicd.ICENABLER[i / 32].set(u32::MAX);
+            gicd.ICPENDR[i / 32].set(u32::MAX);
+            // Non-secure group 1, taken as IRQs.
+            gicd.IGROUPR[i / 32].set(u32::MAX);
+        }
+        for i in SPI_BASE..self.max_irqs {
+            // Route to the primary CPU, and set edge-triggered.
+            gicd.IROUTER[i].set(0);
+            self.set_edge_triggered(i, true);
+        }
+        self.wait_for_rwp();
+        gicd.CTLR.set(GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+        self.wait_for_rwp();
+    }
+
+    fn init_percpu(&mut self) {
+        let cpu_id = instructions::cpu_id();
+        self.cpu_gicr[cpu_id] = self.find_gicr(cpu_id);
+        let gicr = self.gicr();
+
+        gicr.WAKER
+            .set(gicr.WAKER.get() & !GICR_WAKER_PROCESSOR_SLEEP);
+        while gicr.WAKER.get() & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+        gicr.ICENABLER0.set(u32::MAX);
+        gicr.ICPENDR0.set(u32::MAX);
+        gicr.IGROUPR0.set(u32::MAX);
+
+        // System register interface for EL2, and for EL1 in guests.
+        write_sysreg!(
+            "icc_sre_el2",
+            ICC_SRE_SRE | ICC_SRE_DFB | ICC_SRE_DIB | ICC_SRE_ENABLE
+        );
+        unsafe { asm!("isb") };
+        // unmask interrupts at all priority levels
+        write_sysreg!("icc_pmr_el1", 0xff);
+        write_sysreg!("icc_bpr1_el1", 0);
+        write_sysreg!("icc_ctlr_el1", ICC_CTLR_EOIMODE);
+        write_sysreg!("icc_igrpen1_el1", 1);
+
+        write_sysreg!("ich_vmcr_el2", 0xff << ICH_VMCR_VPMR_SHIFT | ICH_VMCR_VENG1);
+        write_sysreg!("ich_hcr_el2", ICH_HCR_EN);
+        unsafe { asm!("isb") };
+    }
+}
+
+/// The GICv3 of the platform.
+pub struct Gicv3;
+
+impl IntrController for Gicv3 {
+    fn version(&self) -> GicVersion {
+        GicVersion::V3
+    }
+
+    fn max_irqs(&self) -> usize {
+        GIC.lock().max_irqs
+    }
+
+    fn distributor_iidr(&self) -> u32 {
+        GIC.lock().gicd().IIDR.get()
+    }
+
+    fn vcpu_interface_base(&self) -> Option<PhysAddr> {
+        None
+    }
+
+    fn init(&self) {
+        crate::mm::map_device(GICD_BASE, GICD_SIZE).unwrap();
+        crate::mm::map_device(GICR_BASE, CPU_NUM * GICR_STRIDE).unwrap();
+        GIC.lock().init();
+    }
+
+    fn init_percpu(&self) {
+        GIC.lock().init_percpu();
+    }
+
+    fn set_enable(&self, vector: usize, enable: bool) {
+        GIC.lock().set_enable(vector, enable);
+    }
+
+    fn set_pending(&self, vector: usize, pending: bool) {
+        GIC.lock().set_pending(vector, pending);
+    }
+
+    fn set_active(&self, vector: usize, active: bool) {
+        GIC.lock().set_active(vector, active);
+    }
+
+    fn irq_pending(&self, vector: usize) -> bool {
+        GIC.lock().is_pending(vector)
+    }
+
+    fn irq_active(&self, vector: usize) -> bool {
+        GIC.lock().is_active(vector)
+    }
+
+    fn set_priority(&self, vector: usize, priority: u8) {
+        GIC.lock().set_priority(vector, priority);
+    }
+
+    fn set_edge_triggered(&self, vector: usize, edge: bool) {
+        GIC.lock().set_edge_triggered(vector, edge);
+    }
+
+    fn route_spi(&self, vector: usize, cpu_id: usize) {
+        let gic = GIC.lock();
+        assert!(vector >= SPI_BASE && vector < gic.max_irqs);
+        // Aff2.Aff1.Aff0, as in the CPU ID.
+        gic.gicd().IROUTER[vector].set(cpu_id as u64);
+    }
+
+    fn send_sgi(&self, sgi: usize, cpu_id: usize) {
+        assert!(sgi < PPI_BASE);
+        let (aff0, aff1, aff2) = (cpu_id & 0xff, (cpu_id >> 8) & 0xff, (cpu_id >> 16) & 0xff);
+        // TargetList holds Aff0 values 16 * RS to 16 * RS + 15.
+        let val = (aff2 as u64) << 32
+            | ((aff0 / 16) as u64) << 44
+            | (sgi as u64) << 24
+            | (aff1 as u64) << 16
+            | 1 << (aff0 % 16);
+        write_sysreg!("icc_sgi1r_el1", val);
+        unsafe { asm!("isb") };
+    }
+
+    fn pending_irq(&self) -> Option<usize> {
+        let iar = read_sysreg!("icc_iar1_el1") as usize & 0xff_ffff;
+        // Special INTIDs, e.g. spurious.
+        (iar < MAX_IRQS).then_some(iar)
+    }
+
+    fn eoi(&self, vector: usize) {
+        write_sysreg!("icc_eoir1_el1", vector);
+    }
+
+    fn deactivate(&self, vector: usize) {
+        write_sysreg!("icc_dir_el1", vector);
+    }
+
+    fn inject(&self, irq_id: usize, hw: bool, _source: usize, priority: u8) -> bool {
+        // A virtual SGI has no source in the list registers of a GICv3.
+        GIC.lock().inject_irq(irq_id, hw, priority)
+    }
+
+    fn set_refill_irq(&self, enable: bool) {
+        GIC.lock().set_refill_irq(enable)
+    }
+
+    fn guest_irq_pending(&self) -> bool {
+        GIC.lock().guest_irq_pending()
+    }
+}
@@ -0,0 +1,104 @@
+//! The interrupt controller, abstracted over the GIC version.
+//!
+//! The GICv3 driver is selected by the `gicv3` feature, the GICv2 one
+//! otherwise. The rest of the hypervisor only uses [`IntrController`].
+
+use spin::Once;
+
+use crate::mm::PhysAddr;
+
+pub const PPI_BASE: usize = 16;
+pub const SPI_BASE: usize = 32;
+
+/// PPI of the EL1 physical timer.
+const TIMER_IRQ: usize = 30;
+/// PPI of the EL2 physical timer, which wakes up CPUs of blocked vCPUs.
+pub const HYP_TIMER_IRQ: usize = 26;
+/// PPI of the maintenance interrupt of the virtual CPU interface.
+pub const MAINTENANCE_IRQ: usize = 25;
+
+/// Architecture version of the GIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    V2,
+    V3,
+}
+
+/// A GIC with virtualization extensions: the distributor, the CPU interface
+/// and the virtual CPU interface of the current CPU.
+///
+/// SGIs and PPIs are banked, and accessed on the current CPU.
+pub trait IntrController: Sync {
+    fn version(&self) -> GicVersion;
+    /// Number of interrupt lines of the distributor, including SGIs and PPIs.
+    fn max_irqs(&self) -> usize;
+    /// Value of the GICD_IIDR of the distributor.
+    fn distributor_iidr(&self) -> u32;
+    /// Physical address of the memory-mapped virtual CPU interface (GICV) of
+    /// a GICv2, which the guests access as their CPU interface.
+    fn vcpu_interface_base(&self) -> Option<PhysAddr>;
+
+    /// Initialize the distributor, once for all CPUs.
+    fn init(&self);
+    /// Initialize the banked interrupts, the CPU interface and the virtual CPU
+    /// interface of the current CPU.
+    fn init_percpu(&self);
+
+    fn set_enable(&self, irq: usize, enable: bool);
+    fn set_pending(&self, irq: usize, pending: bool);
+    fn set_active(&self, irq: usize, active: bool);
+    fn irq_pending(&self, irq: usize) -> bool;
+    fn irq_active(&self, irq: usize) -> bool;
+    fn set_priority(&self, irq: usize, priority: u8);
+    /// Configure the SPI `irq` as edge-triggered or level-sensitive.
+    fn set_edge_triggered(&self, irq: usize, edge: bool);
+    /// Route the SPI `irq` to the physical CPU `cpu_id`.
+    fn route_spi(&self, irq: usize, cpu_id: usize);
+    /// Send the SGI `sgi` to the physical CPU `cpu_id`.
+    fn send_sgi(&self, sgi: usize, cpu_id: usize);
+
+    /// Acknowledge the highest priority pending interrupt, if any.
+    fn pending_irq(&self) -> Option<usize>;
+    /// Priority drop of `irq`, which stays active until deactivated.
+    fn eoi(&self, irq: usize);
+    /// Deactivate `irq` after its priority drop, once handled by the
+    /// hypervisor rather than by a guest.
+    fn deactivate(&self, irq: usize);
+
+    /// Write the virtual interrupt `irq` with `priority` to a free list
+    /// register. A physical interrupt (`hw`) is deactivated by the guest EOI,
+    /// `source` is the requesting CPU of a virtual SGI. An interrupt already in
+    /// a list register is merged into it, a physical one being deactivated
+    /// right away. Returns `false` if all list registers are in use.
+    fn inject(&self, irq: usize, hw: bool, source: usize, priority: u8) -> bool;
+    /// Enable or disable the maintenance interrupt raised when the list
+    /// registers run out of pending interrupts, to refill them.
+    fn set_refill_irq(&self, enable: bool);
+    /// Whether an interrupt is pending for the guest running on this CPU, in
+    /// the list registers or in the physical CPU interface.
+    fn guest_irq_pending(&self) -> bool;
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "gicv3")] {
+        static INTC: super::gicv3::Gicv3 = super::gicv3::Gicv3;
+    } else {
+        static INTC: super::gicv2::Gicv2 = super::gicv2::Gicv2;
+    }
+}
+
+/// The interrupt controller of the platform.
+pub fn intc() -> &'static dyn IntrController {
+    &INTC
+}
+
+/// Initialize the interrupt controller on the current CPU, and the distributor
+/// on the first call.
+pub fn init() {
+    static DIST_INIT: Once<()> = Once::new();
+    DIST_INIT.call_once(|| INTC.init());
+    INTC.init_percpu();
+    // ENABLE TIMER IRQ
+    // TODO: move this to timer::init
+    INTC.set_enable(TIMER_IRQ, true);
+}
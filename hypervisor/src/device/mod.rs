@@ -1,12 +1,14 @@
 pub mod gicv2;
+pub mod gicv3;
+pub mod intc;
 pub mod pl011;
 pub mod smmu;
 
-pub use gicv2 as intr;
+pub use intc as intr;
 pub use pl011 as uart;
 pub use smmu as iommu;
 
-pub use gicv2::{handle_irq, inject_irq, pending_irq};
+pub use intc::intc;
 pub use pl011::{console_getchar, console_putchar};
 
 pub fn init_early() {
@@ -14,6 +16,7 @@ pub fn init_early() {
 }
 
 pub fn init() {
-    gicv2::init();
+    pl011::init_late();
+    intc::init();
     // smmu::init();
 }
@@ -8,6 +8,7 @@ use crate::mm::{PhysAddr, VirtAddr};
 use spin::Mutex;
 
 const UART_BASE: PhysAddr = 0x0900_0000;
+const UART_SIZE: usize = 0x1000;
 const UART_IRQ_NUM: usize = 33;
 
 static UART: Mutex<Pl011Uart> = Mutex::new(Pl011Uart::new(UART_BASE));
@@ -76,3 +77,9 @@ pub fn console_getchar() -> Option<u8> {
 pub fn init() {
     UART.lock().init()
 }
+
+/// Map the UART in the kernel page table, it is accessed through the boot
+/// page table until then.
+pub fn init_late() {
+    crate::mm::map_device(UART_BASE, UART_SIZE).unwrap();
+}
@@ -15,6 +15,7 @@ use crate::utils::LazyInit;
 use super::smmu_queue::SmmuQueue;
 
 const SMMU_BASE: usize = 0x905_0000;
+const SMMU_SIZE: usize = 0x20000;
 
 static SMMUV3: LazyInit<Smmu> = LazyInit::new();
 
@@ -167,6 +168,7 @@ impl Smmu {
 
 pub fn init() {
     info!("Initializaing smmu.");
+    crate::mm::map_device(SMMU_BASE, SMMU_SIZE).unwrap();
     let mut smmu = Smmu::new(SMMU_BASE);
     smmu.init();
     SMMUV3.init_by(smmu);
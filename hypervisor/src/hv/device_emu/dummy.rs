@@ -1,6 +1,6 @@
 use rvm::RvmResult;
 
-use crate::hv::gpm::GuestPhysMemorySet;
+use crate::hv::{GuestPhysMemorySet, RvmHalImpl};
 
 use super::MMIODevice;
 
@@ -19,16 +19,16 @@ impl Dummy {
     }
 }
 
-impl MMIODevice for Dummy {
+impl MMIODevice<RvmHalImpl> for Dummy {
     fn mem_range(&self) -> core::ops::Range<usize> {
         self.base_vaddr..self.base_vaddr + self.dummy_size
     }
 
-    fn read(&self, _: usize, _: u8) -> RvmResult<u32> {
+    fn read(&self, _: usize, _: usize, _: u8) -> RvmResult<u32> {
         Ok(0)
     }
 
-    fn write(&self, _: usize, _: u32, _: u8, _: &GuestPhysMemorySet) -> RvmResult {
+    fn write(&self, _: usize, _: usize, _: u32, _: u8, _: &GuestPhysMemorySet) -> RvmResult {
         Ok(())
     }
 }
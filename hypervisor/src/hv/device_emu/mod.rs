@@ -1,8 +1,11 @@
-use alloc::{sync::Arc, vec, vec::Vec};
+use alloc::sync::Arc;
 
-use crate::config::VM_NUM;
+use rvm::arch::SysReg;
+use rvm::RvmResult;
 
-use super::gpm::GuestPhysMemorySet;
+use super::gconfig::GuestConfig;
+use super::{irq, Vm};
+use crate::device::intc::GicVersion;
 
 mod dummy;
 mod pl011;
@@ -10,59 +13,43 @@ mod vgic;
 mod virtio;
 // mod virt_queue;
 
-pub trait MMIODevice: Send + Sync {
-    fn mem_range(&self) -> core::ops::Range<usize>;
-    fn read(&self, addr: usize, access_size: u8) -> rvm::RvmResult<u32>;
-    fn write(
-        &self,
-        addr: usize,
-        val: u32,
-        access_size: u8,
-        gpm: &GuestPhysMemorySet,
-    ) -> rvm::RvmResult;
-}
-
-pub struct VirtDeviceList {
-    mmio_devices: Vec<Arc<dyn MMIODevice>>,
-}
-
-lazy_static::lazy_static! {
-    static ref VIRT_DEVICES: [VirtDeviceList; VM_NUM] = [
-        VirtDeviceList {
-            mmio_devices: vec![
-                Arc::new(pl011::Pl011::new(0x0900_0000)),
-                Arc::new(vgic::Vgic::new(0x0800_0000)),
-                Arc::new(dummy::Dummy::new(0x0a00_0000, 0x3e00)),
-                Arc::new(virtio::Virtio::new(0x0a00_3e00)),
-        ]},
-
-        // VirtDeviceList {
-        //     mmio_devices: vec![
-        //         Arc::new(pl011::Pl011::new(0x0900_0000)),
-        //         Arc::new(vgic::Vgic::new(0x0800_0000)),
-        //         Arc::new(dummy::Dummy::new(0x0a00_0000, 0x3c00)),
-        //         Arc::new(virtio::Virtio::new(0x0a00_3c00)),
-        //         Arc::new(dummy::Dummy::new(0x0a00_3e00, 0x200)),
-        //     ]
-        // },
-
-        // VirtDeviceList {
-        //     mmio_devices: vec![
-        //         Arc::new(pl011::Pl011::new(0x0900_0000)),
-        //         Arc::new(vgic::Vgic::new(0x0800_0000)),
-        //     ]
-        // },
-     ];
-}
-
-impl VirtDeviceList {
-    pub fn find_mmio_device(&self, addr: usize) -> Option<&Arc<dyn MMIODevice>> {
-        self.mmio_devices
-            .iter()
-            .find(|dev| dev.mem_range().contains(&addr))
+pub use rvm::MmioDevice as MMIODevice;
+pub use vgic::Vgic;
+
+/// Add the emulated devices of the guest described by `config` to `vm`. The
+/// interrupts of the guest are assigned to it.
+///
+/// The GIC has the version of the physical one. Returns it, physical
+/// interrupts are routed to the vCPUs through it.
+pub fn add_virt_devices(vm: &mut Vm, config: &GuestConfig) -> RvmResult<Arc<Vgic>> {
+    let vgic = Arc::new(Vgic::new(config.cpus, config.owned_irqs)?);
+    irq::assign_vm_irqs(&vgic)?;
+    let devices = vm.devices_mut();
+    devices.add_mmio_device(Arc::new(pl011::Pl011::new(config.uart_gpa)))?;
+    match vgic.version() {
+        GicVersion::V2 => {
+            devices.add_mmio_device(Arc::new(vgic::Gicv2Distributor::new(
+                config.gicd_gpa,
+                vgic.clone(),
+            )))?;
+        }
+        GicVersion::V3 => {
+            devices.add_mmio_device(Arc::new(vgic::Gicv3Distributor::new(
+                config.gicd_gpa,
+                vgic.clone(),
+            )))?;
+            devices.add_mmio_device(Arc::new(vgic::Gicv3Redistributors::new(
+                config.gicr_gpa,
+                vgic.clone(),
+            )))?;
+            devices.register_sysreg_handler(
+                SysReg::ICC_SGI1R_EL1,
+                Arc::new(vgic::IccSgi1r::new(vgic.clone())),
+            );
+        }
     }
-}
-
-pub fn all_virt_devices(vm_id: usize) -> &'static VirtDeviceList {
-    &VIRT_DEVICES[vm_id]
+    let dummy = &config.dummy_virtio;
+    devices.add_mmio_device(Arc::new(dummy::Dummy::new(dummy.start, dummy.len())))?;
+    devices.add_mmio_device(Arc::new(virtio::Virtio::new(dummy.end)))?;
+    Ok(vgic)
 }
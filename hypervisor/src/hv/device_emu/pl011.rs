@@ -3,7 +3,7 @@ use spin::Mutex;
 
 use crate::{
     device::{console_getchar, console_putchar},
-    hv::gpm::GuestPhysMemorySet,
+    hv::{GuestPhysMemorySet, RvmHalImpl},
 };
 
 use super::MMIODevice;
@@ -82,12 +82,12 @@ impl Pl011 {
     }
 }
 
-impl MMIODevice for Pl011 {
+impl MMIODevice<RvmHalImpl> for Pl011 {
     fn mem_range(&self) -> core::ops::Range<usize> {
         self.base_vaddr..self.base_vaddr + 0x1000
     }
 
-    fn read(&self, addr: usize, access_size: u8) -> RvmResult<u32> {
+    fn read(&self, _: usize, addr: usize, access_size: u8) -> RvmResult<u32> {
         debug!("pl011 read mock, addr: {:#x}", addr);
         let ret = match addr - self.base_vaddr {
             PL011_DR => {
@@ -121,7 +121,14 @@ impl MMIODevice for Pl011 {
         Ok(ret as u32)
     }
 
-    fn write(&self, addr: usize, val: u32, access_size: u8, _: &GuestPhysMemorySet) -> RvmResult {
+    fn write(
+        &self,
+        _: usize,
+        addr: usize,
+        val: u32,
+        access_size: u8,
+        _: &GuestPhysMemorySet,
+    ) -> RvmResult {
         debug!("pl011 write mock, addr: {:#x}", addr);
         match addr - self.base_vaddr {
             PL011_DR => console_putchar(val as u8),
@@ -0,0 +1,577 @@
+//! Emulated GIC of a VM, of the same version as the physical one.
+//!
+//! Each VM has its own interrupt state, with SGIs and PPIs banked per vCPU. It
+//! is shared by the emulated register frames of the GIC version: the GICv2
+//! distributor, or the GICv3 distributor and redistributors.
+//!
+//! The interrupts owned by the VM are passed through: guest writes to their
+//! registers are also applied to the physical GIC, with vCPU targets
+//! translated to the physical CPUs running the vCPUs. The other interrupts
+//! only exist in the virtual GIC, and are never seen by the hardware. SGIs are
+//! always virtual, sent between the vCPUs of the VM.
+
+mod v2;
+mod v3;
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use rvm::{RvmError, RvmResult};
+use spin::Mutex;
+
+pub use self::v2::Gicv2Distributor;
+pub use self::v3::{Gicv3Distributor, Gicv3Redistributors, IccSgi1r};
+use crate::device::intc::{intc, GicVersion, PPI_BASE, SPI_BASE};
+use crate::hv::irq::kick_cpu;
+
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ISPENDR: usize = 0x200;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_ISACTIVER: usize = 0x300;
+const GICD_ICACTIVER: usize = 0x380;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+
+/// Largest number of interrupts, special INTIDs start at 1020.
+const MAX_IRQS: usize = 1020;
+/// Largest number of vCPUs of a GICv2, which have one bit each in ITARGETSR.
+const GICV2_MAX_VCPUS: usize = 8;
+/// Largest number of vCPUs, which have one bit each in the target sets.
+const MAX_VCPUS: usize = 64;
+
+/// The group enable bits of GICD_CTLR, in both versions.
+const CTLR_ENABLE_GRPS: u32 = 0b11;
+/// ICFGR value of SGIs, which are always edge-triggered.
+const SGI_CONFIG: u32 = 0b10;
+
+/// A field of the per-interrupt state, in the registers with a fixed number of
+/// bits per interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IrqField {
+    Enabled,
+    Pending,
+    Active,
+    Priority,
+    Targets,
+    Config,
+}
+
+impl IrqField {
+    const fn bits(self) -> usize {
+        match self {
+            Self::Enabled | Self::Pending | Self::Active => 1,
+            Self::Priority | Self::Targets => 8,
+            Self::Config => 2,
+        }
+    }
+}
+
+/// How a guest write to a per-interrupt register changes the field.
+#[derive(Debug, Clone, Copy)]
+enum WriteOp {
+    /// Set the field of interrupts whose bit is 1.
+    Set,
+    /// Clear the field of interrupts whose bit is 1.
+    Clear,
+    /// Write the value to the field.
+    Assign,
+}
+
+/// The per-interrupt registers, with their base offset in the distributor.
+const IRQ_REGS: [(usize, IrqField, WriteOp); 9] = [
+    (GICD_ISENABLER, IrqField::Enabled, WriteOp::Set),
+    (GICD_ICENABLER, IrqField::Enabled, WriteOp::Clear),
+    (GICD_ISPENDR, IrqField::Pending, WriteOp::Set),
+    (GICD_ICPENDR, IrqField::Pending, WriteOp::Clear),
+    (GICD_ISACTIVER, IrqField::Active, WriteOp::Set),
+    (GICD_ICACTIVER, IrqField::Active, WriteOp::Clear),
+    (GICD_IPRIORITYR, IrqField::Priority, WriteOp::Assign),
+    (GICD_ITARGETSR, IrqField::Targets, WriteOp::Assign),
+    (GICD_ICFGR, IrqField::Config, WriteOp::Assign),
+];
+
+/// A per-interrupt register, at some offset.
+#[derive(Debug, Clone, Copy)]
+struct IrqReg {
+    field: IrqField,
+    op: WriteOp,
+    /// The first interrupt described at the offset.
+    first_irq: usize,
+}
+
+/// The per-interrupt register containing `offset`, in the layout of the
+/// distributor. The GICv3 redistributors use the same layout for SGIs and
+/// PPIs.
+fn irq_reg(offset: usize) -> Option<IrqReg> {
+    IRQ_REGS.iter().find_map(|&(base, field, op)| {
+        let size = 1024 * field.bits() / 8;
+        (base..base + size).contains(&offset).then_some(IrqReg {
+            field,
+            op,
+            first_irq: (offset - base) * 8 / field.bits(),
+        })
+    })
+}
+
+/// Check that the vCPU `vcpu_id` of a VM with `num_vcpus` does a naturally
+/// aligned access of 1, 2 or 4 bytes.
+fn check_access(vcpu_id: usize, num_vcpus: usize, offset: usize, access_size: u8) -> RvmResult {
+    if vcpu_id >= num_vcpus {
+        return Err(RvmError::InvalidParam);
+    }
+    if !matches!(access_size, 1 | 2 | 4) || offset % access_size as usize != 0 {
+        return Err(RvmError::Unsupported);
+    }
+    Ok(())
+}
+
+/// State of an interrupt in the virtual GIC.
+#[derive(Debug, Default, Clone, Copy)]
+struct VirqState {
+    enabled: bool,
+    pending: bool,
+    active: bool,
+    priority: u8,
+    /// vCPUs targeted by an SPI, one bit per vCPU.
+    targets: u64,
+    config: u8,
+}
+
+impl VirqState {
+    fn get(&self, field: IrqField) -> u32 {
+        match field {
+            IrqField::Enabled => self.enabled as u32,
+            IrqField::Pending => self.pending as u32,
+            IrqField::Active => self.active as u32,
+            IrqField::Priority => self.priority as u32,
+            IrqField::Targets => self.targets as u32,
+            IrqField::Config => self.config as u32,
+        }
+    }
+
+    fn set(&mut self, field: IrqField, val: u32) {
+        match field {
+            IrqField::Enabled => self.enabled = val != 0,
+            IrqField::Pending => self.pending = val != 0,
+            IrqField::Active => self.active = val != 0,
+            IrqField::Priority => self.priority = val as u8,
+            IrqField::Targets => self.targets = val as u64,
+            IrqField::Config => self.config = val as u8,
+        }
+    }
+}
+
+/// An interrupt pending for a vCPU, waiting for a list register.
+#[derive(Debug, Clone, Copy)]
+struct PendingIrq {
+    irq: usize,
+    /// Whether it is a physical interrupt, deactivated by the guest EOI.
+    hw: bool,
+    /// The requesting vCPU of an SGI, 0 for other interrupts.
+    source: usize,
+    priority: u8,
+}
+
+struct VgicInner {
+    /// GICD_CTLR, with the group enable bits.
+    ctlr: u32,
+    /// SGIs and PPIs, banked per vCPU.
+    private: Vec<[VirqState; SPI_BASE]>,
+    spis: Vec<VirqState>,
+    /// Interrupts pending for each vCPU, by decreasing priority, to be written
+    /// to the list registers by its CPU.
+    pending: Vec<Vec<PendingIrq>>,
+}
+
+impl VgicInner {
+    fn irq(&self, vcpu_id: usize, irq: usize) -> &VirqState {
+        if irq < SPI_BASE {
+            &self.private[vcpu_id][irq]
+        } else {
+            &self.spis[irq - SPI_BASE]
+        }
+    }
+
+    fn irq_mut(&mut self, vcpu_id: usize, irq: usize) -> &mut VirqState {
+        if irq < SPI_BASE {
+            &mut self.private[vcpu_id][irq]
+        } else {
+            &mut self.spis[irq - SPI_BASE]
+        }
+    }
+
+    /// Queue `irq` to the vCPU `vcpu_id`, after the interrupts of higher or
+    /// equal priority. Does nothing if it is already queued, an SGI is pending
+    /// once for each requesting vCPU `source`.
+    fn queue_irq(&mut self, vcpu_id: usize, irq: usize, hw: bool, source: usize) {
+        let state = self.irq_mut(vcpu_id, irq);
+        state.pending = true;
+        let priority = state.priority;
+        let queue = &mut self.pending[vcpu_id];
+        if queue.iter().any(|p| p.irq == irq && p.source == source) {
+            return;
+        }
+        // Lower values are higher priorities.
+        let pos = queue
+            .iter()
+            .position(|p| p.priority > priority)
+            .unwrap_or(queue.len());
+        queue.insert(
+            pos,
+            PendingIrq {
+                irq,
+                hw,
+                source,
+                priority,
+            },
+        );
+    }
+
+    /// Remove `irq` from the queue of the vCPU `vcpu_id`, and from the queues
+    /// of all vCPUs if it is an SPI.
+    fn dequeue_irq(&mut self, vcpu_id: usize, irq: usize) {
+        self.irq_mut(vcpu_id, irq).pending = false;
+        for (i, queue) in self.pending.iter_mut().enumerate() {
+            if irq >= SPI_BASE || i == vcpu_id {
+                queue.retain(|p| p.irq != irq);
+            }
+        }
+    }
+
+    /// Whether `irq` can be signaled to the vCPU `vcpu_id`.
+    fn deliverable(&self, vcpu_id: usize, irq: usize) -> bool {
+        self.ctlr & CTLR_ENABLE_GRPS != 0 && self.irq(vcpu_id, irq).enabled
+    }
+}
+
+/// The virtual GIC of a VM.
+pub struct Vgic {
+    version: GicVersion,
+    /// The physical CPU running each vCPU.
+    cpus: Vec<usize>,
+    num_irqs: usize,
+    iidr: u32,
+    /// Interrupts owned by the VM, one bit per interrupt.
+    owned: Vec<u32>,
+    inner: Mutex<VgicInner>,
+}
+
+impl Vgic {
+    /// Create the virtual GIC of a VM whose vCPU `i` runs on the physical CPU
+    /// `cpus[i]`. The interrupts in `owned_irqs` are owned by the VM, and
+    /// passed through to the physical GIC.
+    ///
+    /// The VM has the version and as many interrupt lines as the physical GIC.
+    pub fn new(cpus: &[usize], owned_irqs: &[Range<usize>]) -> RvmResult<Self> {
+        let version = intc().version();
+        let max_vcpus = match version {
+            GicVersion::V2 => GICV2_MAX_VCPUS,
+            GicVersion::V3 => MAX_VCPUS,
+        };
+        if cpus.is_empty() || cpus.len() > max_vcpus {
+            warn!("A {:?} GIC supports up to {} vCPUs", version, max_vcpus);
+            return Err(RvmError::InvalidParam);
+        }
+        let num_irqs = intc().max_irqs().min(MAX_IRQS);
+        let mut owned = vec![0; (num_irqs + 31) / 32];
+        for irq in owned_irqs.iter().flat_map(|r| r.clone()) {
+            // SGIs are always virtual, between the vCPUs of the VM.
+            if irq < PPI_BASE || irq >= num_irqs {
+                warn!("Interrupt {} can not be owned", irq);
+                return Err(RvmError::InvalidParam);
+            }
+            owned[irq / 32] |= 1 << (irq % 32);
+        }
+
+        let mut banked = [VirqState::default(); SPI_BASE];
+        for sgi in banked.iter_mut().take(PPI_BASE) {
+            sgi.enabled = true;
+            sgi.config = SGI_CONFIG as u8;
+        }
+        Ok(Self {
+            version,
+            cpus: cpus.to_vec(),
+            num_irqs,
+            iidr: intc().distributor_iidr(),
+            owned,
+            inner: Mutex::new(VgicInner {
+                ctlr: 0,
+                private: vec![banked; cpus.len()],
+                spis: vec![VirqState::default(); num_irqs - SPI_BASE],
+                pending: vec![Vec::new(); cpus.len()],
+            }),
+        })
+    }
+
+    pub fn version(&self) -> GicVersion {
+        self.version
+    }
+
+    pub fn num_vcpus(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// The physical CPU running the vCPU `vcpu_id`.
+    pub fn vcpu_cpu(&self, vcpu_id: usize) -> usize {
+        self.cpus[vcpu_id]
+    }
+
+    /// Whether the physical interrupt `irq` is owned by the VM.
+    pub fn owns(&self, irq: usize) -> bool {
+        irq < self.num_irqs && self.owned[irq / 32] & (1 << (irq % 32)) != 0
+    }
+
+    /// The physical interrupts owned by the VM.
+    pub fn owned_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_irqs).filter(|&irq| self.owns(irq))
+    }
+
+    /// The vCPU that handles `irq` if it becomes pending on the vCPU
+    /// `current`. SGIs and PPIs go to the current vCPU, SPIs to one of their
+    /// targets, preferably the current one.
+    fn target_vcpu(&self, inner: &VgicInner, irq: usize, current: Option<usize>) -> Option<usize> {
+        if irq < SPI_BASE {
+            return current;
+        }
+        let targets = inner.spis[irq - SPI_BASE].targets;
+        match current {
+            Some(vcpu_id) if targets & (1 << vcpu_id) != 0 => Some(vcpu_id),
+            _ if targets == 0 => None,
+            _ => Some(targets.trailing_zeros() as usize),
+        }
+    }
+
+    /// Route the owned physical interrupt `irq`, taken on the CPU of the vCPU
+    /// `current`, to the vCPU that will handle it.
+    ///
+    /// Returns the target vCPU, whose CPU injects the interrupt on the next VM
+    /// entry, or `None` if the SPI targets no vCPU.
+    pub fn route_irq(&self, irq: usize, current: Option<usize>) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let target = self.target_vcpu(&inner, irq, current)?;
+        inner.queue_irq(target, irq, true, 0);
+        Some(target)
+    }
+
+    /// Make the purely virtual interrupt `irq` pending for the vCPU `vcpu_id`,
+    /// e.g. for emulated devices.
+    pub fn inject_virq(&self, vcpu_id: usize, irq: usize) {
+        self.inner.lock().queue_irq(vcpu_id, irq, false, 0);
+    }
+
+    /// Whether interrupts that can be signaled to the vCPU `vcpu_id` are
+    /// pending, but not written to the list registers yet.
+    pub fn has_pending_irqs(&self, vcpu_id: usize) -> bool {
+        let inner = self.inner.lock();
+        inner.pending[vcpu_id]
+            .iter()
+            .any(|p| inner.deliverable(vcpu_id, p.irq))
+    }
+
+    /// Write the pending interrupts of the vCPU `vcpu_id`, which must be run
+    /// by the current CPU, to free list registers by decreasing priority.
+    ///
+    /// The interrupts left when all list registers are in use are written on
+    /// the maintenance interrupt raised once the guest has handled the ones in
+    /// the list registers. Disabled interrupts stay pending until enabled.
+    pub fn flush_pending_irqs(&self, vcpu_id: usize) {
+        let mut inner = self.inner.lock();
+        let mut lr_full = false;
+        let mut i = 0;
+        while i < inner.pending[vcpu_id].len() {
+            let p = inner.pending[vcpu_id][i];
+            if !inner.deliverable(vcpu_id, p.irq) {
+                i += 1;
+                continue;
+            }
+            if !intc().inject(p.irq, p.hw, p.source, p.priority) {
+                lr_full = true;
+                break;
+            }
+            let queue = &mut inner.pending[vcpu_id];
+            queue.remove(i);
+            if !queue.iter().any(|q| q.irq == p.irq) {
+                inner.irq_mut(vcpu_id, p.irq).pending = false;
+            }
+        }
+        intc().set_refill_irq(lr_full);
+    }
+
+    fn ctlr(&self) -> u32 {
+        self.inner.lock().ctlr
+    }
+
+    fn set_ctlr(&self, ctlr: u32) {
+        self.inner.lock().ctlr = ctlr;
+    }
+
+    fn vcpu_mask(&self) -> u64 {
+        u64::MAX >> (64 - self.num_vcpus())
+    }
+
+    /// The SPI targets of `irq`, one bit per vCPU.
+    fn spi_targets(&self, irq: usize) -> u64 {
+        self.inner.lock().spis[irq - SPI_BASE].targets
+    }
+
+    /// Emulate a read of the per-interrupt register `reg` by the vCPU
+    /// `vcpu_id`, with the SGIs and PPIs of the vCPU `bank`. Only the
+    /// interrupts in `irqs` are implemented, the others read as zero.
+    fn read_irq_reg(
+        &self,
+        vcpu_id: usize,
+        bank: usize,
+        reg: IrqReg,
+        access_size: u8,
+        irqs: Range<usize>,
+    ) -> u32 {
+        let mut inner = self.inner.lock();
+        let bits = reg.field.bits();
+        let count = access_size as usize * 8 / bits;
+        (reg.first_irq..reg.first_irq + count)
+            .enumerate()
+            .filter(|(_, irq)| irqs.contains(irq) && *irq < self.num_irqs)
+            .fold(0, |val, (i, irq)| {
+                val | self.read_irq(&mut inner, vcpu_id, bank, irq, reg.field) << (i * bits)
+            })
+    }
+
+    /// Emulate a write of `val` to the per-interrupt register `reg` by the vCPU
+    /// `vcpu_id`, with the SGIs and PPIs of the vCPU `bank`. Only the
+    /// interrupts in `irqs` are implemented, the others ignore writes.
+    fn write_irq_reg(
+        &self,
+        vcpu_id: usize,
+        bank: usize,
+        reg: IrqReg,
+        access_size: u8,
+        irqs: Range<usize>,
+        val: u32,
+    ) {
+        let mut inner = self.inner.lock();
+        let bits = reg.field.bits();
+        let count = access_size as usize * 8 / bits;
+        let field_mask = (1 << bits) - 1;
+        for (i, irq) in (reg.first_irq..reg.first_irq + count).enumerate() {
+            if !irqs.contains(&irq) || irq >= self.num_irqs {
+                continue;
+            }
+            let v = (val >> (i * bits)) & field_mask;
+            let v = match reg.op {
+                WriteOp::Assign => v,
+                _ if v == 0 => continue,
+                WriteOp::Set => 1,
+                WriteOp::Clear => 0,
+            };
+            self.write_irq(&mut inner, vcpu_id, bank, irq, reg.field, v as u64);
+        }
+    }
+
+    /// Set the vCPUs targeted by the SPI `irq`.
+    fn set_spi_targets(&self, irq: usize, targets: u64) {
+        let mut inner = self.inner.lock();
+        self.write_irq(&mut inner, 0, 0, irq, IrqField::Targets, targets);
+    }
+
+    fn read_irq(
+        &self,
+        inner: &mut VgicInner,
+        vcpu_id: usize,
+        bank: usize,
+        irq: usize,
+        field: IrqField,
+    ) -> u32 {
+        // The vCPU runs on the current CPU, so the banked registers of the
+        // physical GIC are the ones of the vCPU.
+        if self.owns(irq) && (irq >= SPI_BASE || bank == vcpu_id) {
+            match field {
+                IrqField::Pending => return intc().irq_pending(irq) as u32,
+                IrqField::Active => return intc().irq_active(irq) as u32,
+                _ => {}
+            }
+        }
+        if irq < SPI_BASE && field == IrqField::Targets {
+            // Reads the CPU doing the access.
+            return 1 << vcpu_id;
+        }
+        inner.irq_mut(bank, irq).get(field)
+    }
+
+    fn write_irq(
+        &self,
+        inner: &mut VgicInner,
+        vcpu_id: usize,
+        bank: usize,
+        irq: usize,
+        field: IrqField,
+        val: u64,
+    ) {
+        let val = match field {
+            // Read-only for SGIs and PPIs.
+            IrqField::Targets if irq < SPI_BASE => return,
+            IrqField::Targets => val & self.vcpu_mask(),
+            // SGIs are always enabled and edge-triggered.
+            IrqField::Enabled | IrqField::Config if irq < PPI_BASE => return,
+            _ => val,
+        };
+        let state = inner.irq_mut(bank, irq);
+        if field == IrqField::Targets {
+            state.targets = val;
+        } else {
+            state.set(field, val as u32);
+        }
+        if !self.owns(irq) {
+            if field == IrqField::Pending {
+                self.set_virq_pending(inner, bank, irq, val != 0);
+            }
+            return;
+        }
+        // The banked registers of another vCPU are not on this CPU.
+        if irq < SPI_BASE && bank != vcpu_id {
+            return;
+        }
+        match field {
+            IrqField::Enabled => intc().set_enable(irq, val != 0),
+            IrqField::Pending => intc().set_pending(irq, val != 0),
+            IrqField::Active => intc().set_active(irq, val != 0),
+            IrqField::Priority => intc().set_priority(irq, val as u8),
+            // Physical SPIs are routed to the CPU of their first target.
+            IrqField::Targets if val != 0 => {
+                intc().route_spi(irq, self.vcpu_cpu(val.trailing_zeros() as usize))
+            }
+            IrqField::Targets => {}
+            // The trigger mode of PPIs is fixed by the hardware.
+            IrqField::Config if irq >= SPI_BASE => {
+                intc().set_edge_triggered(irq, val as u32 & SGI_CONFIG != 0)
+            }
+            IrqField::Config => {}
+        }
+    }
+
+    /// Make the interrupt `irq`, not owned by the VM, pending or not on a
+    /// guest write by the vCPU `vcpu_id`.
+    fn set_virq_pending(&self, inner: &mut VgicInner, vcpu_id: usize, irq: usize, pending: bool) {
+        if !pending {
+            inner.dequeue_irq(vcpu_id, irq);
+        } else if let Some(target) = self.target_vcpu(inner, irq, Some(vcpu_id)) {
+            inner.queue_irq(target, irq, false, 0);
+        }
+    }
+
+    /// Send the SGI `sgi` from the vCPU `vcpu_id` to the vCPUs in `targets`:
+    /// it is made pending for them, with `vcpu_id` as the source. The CPUs of
+    /// the other targets are kicked to inject it.
+    fn send_sgi(&self, vcpu_id: usize, sgi: usize, targets: u64) {
+        let targets = targets & self.vcpu_mask();
+        trace!("vCPU {} sends SGI {} to {:#x}", vcpu_id, sgi, targets);
+        let mut inner = self.inner.lock();
+        for target in (0..self.num_vcpus()).filter(|&i| targets & (1 << i) != 0) {
+            inner.queue_irq(target, sgi, false, vcpu_id);
+            if target != vcpu_id {
+                kick_cpu(self.vcpu_cpu(target));
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Emulated GICv2 distributor. The CPU interface is not emulated, the frame
+//! of the virtual CPU interface (GICV) is mapped as the guest GICC.
+
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use rvm::RvmResult;
+
+use super::{check_access, irq_reg, Vgic};
+use crate::hv::device_emu::MMIODevice;
+use crate::hv::{GuestPhysMemorySet, RvmHalImpl};
+
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IIDR: usize = 0x008;
+const GICD_SGIR: usize = 0xf00;
+const GICD_ICPIDR2: usize = 0xfe8;
+
+/// Size of the register frame of the distributor.
+const GICD_FRAME_SIZE: usize = 0x10000;
+
+const CTLR_ENABLE: u32 = 1 << 0;
+/// ArchRev field of ICPIDR2, for GICv2.
+const ICPIDR2_GICV2: u32 = 0x2 << 4;
+
+/// The virtual GICv2 distributor of a VM.
+pub struct Gicv2Distributor {
+    base_gpa: usize,
+    vgic: Arc<Vgic>,
+}
+
+impl Gicv2Distributor {
+    pub fn new(base_gpa: usize, vgic: Arc<Vgic>) -> Self {
+        Self { base_gpa, vgic }
+    }
+
+    fn typer(&self) -> u32 {
+        // ITLinesNumber and CPUNumber.
+        (((self.vgic.num_irqs + 31) / 32 - 1) | (self.vgic.num_vcpus() - 1) << 5) as u32
+    }
+
+    /// Emulate a write to GICD_SGIR by the vCPU `vcpu_id`.
+    fn write_sgir(&self, vcpu_id: usize, val: u32) {
+        let sgi = (val & 0xf) as usize;
+        // TargetListFilter and CPUTargetList.
+        let targets = match (val >> 24) & 0b11 {
+            0 => ((val >> 16) & 0xff) as u64,
+            1 => self.vgic.vcpu_mask() & !(1 << vcpu_id),
+            2 => 1 << vcpu_id,
+            _ => return,
+        };
+        self.vgic.send_sgi(vcpu_id, sgi, targets);
+    }
+}
+
+impl MMIODevice<RvmHalImpl> for Gicv2Distributor {
+    fn mem_range(&self) -> Range<usize> {
+        self.base_gpa..self.base_gpa + GICD_FRAME_SIZE
+    }
+
+    fn read(&self, vcpu_id: usize, addr: usize, access_size: u8) -> RvmResult<u32> {
+        trace!("GICD read addr {:#x}, access size {}", addr, access_size);
+        let offset = addr - self.base_gpa;
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        let val = match offset {
+            GICD_CTLR => self.vgic.ctlr(),
+            GICD_TYPER => self.typer(),
+            GICD_IIDR => self.vgic.iidr,
+            GICD_ICPIDR2 => ICPIDR2_GICV2,
+            _ => match irq_reg(offset) {
+                Some(reg) => self.vgic.read_irq_reg(
+                    vcpu_id,
+                    vcpu_id,
+                    reg,
+                    access_size,
+                    0..self.vgic.num_irqs,
+                ),
+                // Including IGROUPR, all interrupts are in group 0.
+                None => 0,
+            },
+        };
+        Ok(val)
+    }
+
+    fn write(
+        &self,
+        vcpu_id: usize,
+        addr: usize,
+        val: u32,
+        access_size: u8,
+        _: &GuestPhysMemorySet,
+    ) -> RvmResult {
+        trace!(
+            "GICD write addr {:#x}, access size {}, value {:#x}",
+            addr,
+            access_size,
+            val
+        );
+        let offset = addr - self.base_gpa;
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        match offset {
+            GICD_CTLR => self.vgic.set_ctlr(val & CTLR_ENABLE),
+            GICD_SGIR => self.write_sgir(vcpu_id, val),
+            _ => {
+                // Read-only or unimplemented otherwise.
+                if let Some(reg) = irq_reg(offset) {
+                    let irqs = 0..self.vgic.num_irqs;
+                    self.vgic
+                        .write_irq_reg(vcpu_id, vcpu_id, reg, access_size, irqs, val);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! Emulated GICv3 distributor and redistributors, with affinity routing
+//! always enabled. The guest uses the virtual CPU interface through the ICC_*
+//! system registers, except for ICC_SGI1R_EL1 which is trapped.
+//!
+//! The vCPU `i` has the affinity 0.0.0.`i`, as in its MPIDR_EL1.
+
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use rvm::arch::SysReg;
+use rvm::{RvmError, RvmResult, SysRegHandler};
+
+use super::{check_access, irq_reg, IrqField, Vgic, CTLR_ENABLE_GRPS};
+use crate::device::intc::SPI_BASE;
+use crate::hv::device_emu::MMIODevice;
+use crate::hv::{GuestPhysMemorySet, RvmHalImpl, Vcpu};
+
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IIDR: usize = 0x0008;
+const GICD_IGROUPR: Range<usize> = 0x0080..0x0100;
+const GICD_IROUTER: Range<usize> = 0x6000..0x8000;
+const GICD_PIDR2: usize = 0xffe8;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_IIDR: usize = 0x0004;
+const GICR_TYPER: usize = 0x0008;
+const GICR_TYPER_HIGH: usize = 0x000c;
+const GICR_WAKER: usize = 0x0014;
+const GICR_PIDR2: usize = 0xffe8;
+/// The SGI_base frame, with the registers of SGIs and PPIs.
+const GICR_SGI_BASE: usize = 0x10000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+
+/// Size of the register frame of the distributor.
+const GICD_FRAME_SIZE: usize = 0x10000;
+/// Size of the RD_base and SGI_base frames of a redistributor.
+const GICR_FRAME_SIZE: usize = 0x20000;
+
+const CTLR_ARE: u32 = 1 << 4;
+/// Disable Security: the guest sees a single security state.
+const CTLR_DS: u32 = 1 << 6;
+/// IDbits field of GICD_TYPER, INTIDs have 10 bits.
+const TYPER_IDBITS: u32 = 9 << 19;
+/// Interrupt Routing Mode of IROUTER: to any participating vCPU.
+const IROUTER_IRM: u32 = 1 << 31;
+const GICR_TYPER_LAST: u32 = 1 << 4;
+/// ArchRev field of PIDR2, for GICv3.
+const PIDR2_GICV3: u32 = 0x3 << 4;
+
+/// The virtual GICv3 distributor of a VM, for SPIs only.
+pub struct Gicv3Distributor {
+    base_gpa: usize,
+    vgic: Arc<Vgic>,
+}
+
+impl Gicv3Distributor {
+    pub fn new(base_gpa: usize, vgic: Arc<Vgic>) -> Self {
+        Self { base_gpa, vgic }
+    }
+
+    fn typer(&self) -> u32 {
+        // ITLinesNumber and CPUNumber, which only matters without affinity
+        // routing.
+        let cpu_number = (self.vgic.num_vcpus() - 1).min(7);
+        ((self.vgic.num_irqs + 31) / 32 - 1 | cpu_number << 5) as u32 | TYPER_IDBITS
+    }
+
+    /// Emulate a read of the 32-bit half of IROUTER at `offset`.
+    fn read_irouter(&self, offset: usize) -> u32 {
+        let irq = (offset - GICD_IROUTER.start) / 8;
+        // The high half holds Aff3.
+        if irq < SPI_BASE || irq >= self.vgic.num_irqs || offset % 8 != 0 {
+            return 0;
+        }
+        let targets = self.vgic.spi_targets(irq);
+        match targets.count_ones() {
+            0 => 0,
+            // Aff0 of the target.
+            1 => targets.trailing_zeros(),
+            _ => IROUTER_IRM,
+        }
+    }
+
+    /// Emulate a write to the 32-bit half of IROUTER at `offset`.
+    fn write_irouter(&self, offset: usize, val: u32) {
+        let irq = (offset - GICD_IROUTER.start) / 8;
+        if irq < SPI_BASE || irq >= self.vgic.num_irqs || offset % 8 != 0 {
+            return;
+        }
+        let targets = if val & IROUTER_IRM != 0 {
+            self.vgic.vcpu_mask()
+        } else if val & 0xff_ff00 == 0 && (val & 0xff) < self.vgic.num_vcpus() as u32 {
+            1 << (val & 0xff)
+        } else {
+            // Not an affinity of the VM.
+            0
+        };
+        self.vgic.set_spi_targets(irq, targets);
+    }
+}
+
+impl MMIODevice<RvmHalImpl> for Gicv3Distributor {
+    fn mem_range(&self) -> Range<usize> {
+        self.base_gpa..self.base_gpa + GICD_FRAME_SIZE
+    }
+
+    fn read(&self, vcpu_id: usize, addr: usize, access_size: u8) -> RvmResult<u32> {
+        trace!("GICD read addr {:#x}, access size {}", addr, access_size);
+        let offset = addr - self.base_gpa;
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        let irqs = SPI_BASE..self.vgic.num_irqs;
+        let val = match offset {
+            GICD_CTLR => self.vgic.ctlr() | CTLR_ARE | CTLR_DS,
+            GICD_TYPER => self.typer(),
+            GICD_IIDR => self.vgic.iidr,
+            GICD_PIDR2 => PIDR2_GICV3,
+            // All interrupts are in group 1.
+            _ if GICD_IGROUPR.contains(&offset) => u32::MAX,
+            _ if GICD_IROUTER.contains(&offset) => self.read_irouter(offset),
+            _ => match irq_reg(offset) {
+                // ITARGETSR is not used with affinity routing.
+                Some(reg) if reg.field != IrqField::Targets => {
+                    self.vgic
+                        .read_irq_reg(vcpu_id, vcpu_id, reg, access_size, irqs)
+                }
+                _ => 0,
+            },
+        };
+        Ok(val)
+    }
+
+    fn write(
+        &self,
+        vcpu_id: usize,
+        addr: usize,
+        val: u32,
+        access_size: u8,
+        _: &GuestPhysMemorySet,
+    ) -> RvmResult {
+        trace!(
+            "GICD write addr {:#x}, access size {}, value {:#x}",
+            addr,
+            access_size,
+            val
+        );
+        let offset = addr - self.base_gpa;
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        let irqs = SPI_BASE..self.vgic.num_irqs;
+        match offset {
+            GICD_CTLR => self.vgic.set_ctlr(val & CTLR_ENABLE_GRPS),
+            _ if GICD_IROUTER.contains(&offset) => self.write_irouter(offset, val),
+            _ => match irq_reg(offset) {
+                Some(reg) if reg.field != IrqField::Targets => {
+                    self.vgic
+                        .write_irq_reg(vcpu_id, vcpu_id, reg, access_size, irqs, val)
+                }
+                // Read-only or unimplemented.
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The virtual GICv3 redistributors of a VM, one for each vCPU in order.
+pub struct Gicv3Redistributors {
+    base_gpa: usize,
+    vgic: Arc<Vgic>,
+}
+
+impl Gicv3Redistributors {
+    pub fn new(base_gpa: usize, vgic: Arc<Vgic>) -> Self {
+        Self { base_gpa, vgic }
+    }
+
+    /// The vCPU of the redistributor at `addr`, and the offset in its frames.
+    fn locate(&self, addr: usize) -> (usize, usize) {
+        let offset = addr - self.base_gpa;
+        (offset / GICR_FRAME_SIZE, offset % GICR_FRAME_SIZE)
+    }
+
+    /// The low half of GICR_TYPER of the vCPU `rd_vcpu`, the high half is its
+    /// affinity.
+    fn typer(&self, rd_vcpu: usize) -> u32 {
+        // Processor_Number, and Last for the last redistributor.
+        let mut typer = (rd_vcpu as u32) << 8;
+        if rd_vcpu == self.vgic.num_vcpus() - 1 {
+            typer |= GICR_TYPER_LAST;
+        }
+        typer
+    }
+}
+
+impl MMIODevice<RvmHalImpl> for Gicv3Redistributors {
+    fn mem_range(&self) -> Range<usize> {
+        self.base_gpa..self.base_gpa + self.vgic.num_vcpus() * GICR_FRAME_SIZE
+    }
+
+    fn read(&self, vcpu_id: usize, addr: usize, access_size: u8) -> RvmResult<u32> {
+        trace!("GICR read addr {:#x}, access size {}", addr, access_size);
+        let (rd_vcpu, offset) = self.locate(addr);
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        let val = match offset {
+            GICR_IIDR => self.vgic.iidr,
+            GICR_TYPER => self.typer(rd_vcpu),
+            GICR_TYPER_HIGH => rd_vcpu as u32,
+            GICR_PIDR2 => PIDR2_GICV3,
+            // Without LPIs, and always awake.
+            GICR_CTLR | GICR_WAKER => 0,
+            // All SGIs and PPIs are in group 1.
+            GICR_IGROUPR0 => u32::MAX,
+            _ if offset < GICR_SGI_BASE => 0,
+            _ => match irq_reg(offset - GICR_SGI_BASE) {
+                Some(reg) if reg.field != IrqField::Targets => {
+                    self.vgic
+                        .read_irq_reg(vcpu_id, rd_vcpu, reg, access_size, 0..SPI_BASE)
+                }
+                _ => 0,
+            },
+        };
+        Ok(val)
+    }
+
+    fn write(
+        &self,
+        vcpu_id: usize,
+        addr: usize,
+        val: u32,
+        access_size: u8,
+        _: &GuestPhysMemorySet,
+    ) -> RvmResult {
+        trace!(
+            "GICR write addr {:#x}, access size {}, value {:#x}",
+            addr,
+            access_size,
+            val
+        );
+        let (rd_vcpu, offset) = self.locate(addr);
+        check_access(vcpu_id, self.vgic.num_vcpus(), offset, access_size)?;
+        // The registers of RD_base are read-only or ignore writes.
+        if offset < GICR_SGI_BASE {
+            return Ok(());
+        }
+        if let Some(reg) = irq_reg(offset - GICR_SGI_BASE) {
+            if reg.field != IrqField::Targets {
+                self.vgic
+                    .write_irq_reg(vcpu_id, rd_vcpu, reg, access_size, 0..SPI_BASE, val);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Emulates ICC_SGI1R_EL1, to send SGIs between the vCPUs of a VM.
+pub struct IccSgi1r {
+    vgic: Arc<Vgic>,
+}
+
+impl IccSgi1r {
+    pub fn new(vgic: Arc<Vgic>) -> Self {
+        Self { vgic }
+    }
+}
+
+impl SysRegHandler<RvmHalImpl> for IccSgi1r {
+    fn read(&self, _: &mut Vcpu, _: SysReg) -> RvmResult<u64> {
+        // Write-only.
+        Err(RvmError::Unsupported)
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, _: SysReg, val: u64) -> RvmResult {
+        let vcpu_id = (vcpu.mpidr() & 0xff) as usize;
+        let sgi = ((val >> 24) & 0xf) as usize;
+        let targets = if val & (1 << 40) != 0 {
+            // Interrupt Routing Mode: all vCPUs but the requesting one.
+            self.vgic.vcpu_mask() & !(1 << vcpu_id)
+        } else if val & 0x00ff_00ff_00ff_0000 == 0 {
+            // Aff3.Aff2.Aff1 is 0.0.0, TargetList holds the Aff0 values 16 * RS
+            // to 16 * RS + 15.
+            let rs = (val >> 44) & 0xf;
+            (val & 0xffff).checked_shl(rs as u32 * 16).unwrap_or(0)
+        } else {
+            0
+        };
+        self.vgic.send_sgi(vcpu_id, sgi, targets);
+        Ok(())
+    }
+}
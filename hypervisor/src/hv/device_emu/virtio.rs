@@ -1,7 +1,10 @@
 use alloc::{collections::BTreeMap, vec, vec::Vec};
 use spin::Mutex;
 
-use crate::{hv::gpm::GuestPhysMemorySet, mm::PAGE_SIZE};
+use crate::{
+    hv::{GuestPhysMemorySet, RvmHalImpl},
+    mm::PAGE_SIZE,
+};
 
 use super::MMIODevice;
 
@@ -81,7 +84,9 @@ impl VirtQueueInfo {
 }
 
 impl Virtio {
-    pub const fn new(base_vaddr: usize) -> Self {
+    pub fn new(base_vaddr: usize) -> Self {
+        // Guest accesses are forwarded to the virtio-mmio device of the host.
+        crate::mm::map_device(base_vaddr, 0x200).unwrap();
         Self {
             base_vaddr,
             virt_queue_info: Mutex::new(VirtQueueInfo::new()),
@@ -167,17 +172,18 @@ impl Virtio {
     }
 }
 
-impl MMIODevice for Virtio {
+impl MMIODevice<RvmHalImpl> for Virtio {
     fn mem_range(&self) -> core::ops::Range<usize> {
         self.base_vaddr..self.base_vaddr + 0x200
     }
 
-    fn read(&self, addr: usize, access_size: u8) -> rvm::RvmResult<u32> {
+    fn read(&self, _: usize, addr: usize, access_size: u8) -> rvm::RvmResult<u32> {
         Ok(unsafe { (addr as *const u32).read_volatile() })
     }
 
     fn write(
         &self,
+        _: usize,
         addr: usize,
         val: u32,
         access_size: u8,
@@ -1,17 +1,14 @@
-use rvm::{GuestPhysAddr, HostPhysAddr};
-use spin::Mutex;
+use core::ops::Range;
 
-use crate::config::CPU_NUM;
-
-use super::gpm::GuestPhysMemorySet;
+use rvm::arch::SysReg;
+use rvm::{GuestPhysAddr, GuestTraps, HostPhysAddr};
 
+/// The kernel images of the VMs are loaded by QEMU to the flash, in the order
+/// of [`GUEST_CONFIGS`].
 pub const GUEST_IMAGE_PADDR: HostPhysAddr = 0x400_1000;
 pub const GUEST_IMAGE_SIZE: usize = 0x300_0000; // 1M
 
-pub const GUEST_PHYS_MEMORY_BASE: GuestPhysAddr = 0x4000_0000;
 pub const BIOS_ENTRY: GuestPhysAddr = 0x8000;
-pub const GUEST_ENTRY: GuestPhysAddr = 0x4008_0000;
-pub const GUEST_PHYS_MEMORY_SIZE: usize = 0x800_0000; // 128M
 
 pub const VIRTIO_HEADER_TOTAL_SIZE: usize = 0x4000;
 pub const VIRTIO_HEADER_EACH_SIZE: usize = 0x200;
@@ -19,18 +16,100 @@ pub const VIRTIO_HEADER_EACH_SIZE: usize = 0x200;
 pub const VIRTIO_BLK_IDX: usize = 0;
 pub const VIRTIO_NET_IDX: usize = 1;
 
-pub const GUEST_INITRAMFS_START: usize = 0x4800_0000;
-pub const GUEST_INITRAMFS_END: usize = 0x4820_0000;
+/// Replace the bits `mask` of the hardware value of an ID register, read by
+/// the guest when its ID registers are trapped (e.g. with
+/// [`GuestTraps::TID3`]), with the ones of `value`.
+pub struct IdRegOverride {
+    pub reg: SysReg,
+    pub mask: u64,
+    pub value: u64,
+}
+
+impl IdRegOverride {
+    /// The value read by the guest, for the hardware value `hw`.
+    pub const fn apply(&self, hw: u64) -> u64 {
+        hw & !self.mask | self.value & self.mask
+    }
+}
+
+/// Configuration of a guest VM.
+pub struct GuestConfig {
+    /// The physical CPUs running the vCPUs, vCPU `i` runs on `cpus[i]`.
+    pub cpus: &'static [usize],
+    /// The guest RAM, the kernel image is loaded to `entry` in it.
+    pub memory_base: GuestPhysAddr,
+    pub memory_size: usize,
+    pub entry: GuestPhysAddr,
+    /// Size of the guest physical address space, in bits.
+    pub ipa_bits: usize,
+    /// Guest operations trapped to the hypervisor.
+    pub traps: GuestTraps,
+    /// The ID register fields exposed to the guest differently than the
+    /// hardware, for the ID registers in `traps`.
+    pub id_reg_overrides: &'static [IdRegOverride],
+    /// Physical interrupts owned by the guest.
+    pub owned_irqs: &'static [Range<usize>],
+    /// The device tree, whose address is passed in x0 to the boot vCPU.
+    pub dtb: &'static [u8],
+    pub dtb_gpa: GuestPhysAddr,
+    pub initramfs: &'static [u8],
+    pub initramfs_gpa: GuestPhysAddr,
+    /// The PL011 UART.
+    pub uart_gpa: GuestPhysAddr,
+    /// The GIC distributor, the CPU interface of a GICv2 and the
+    /// redistributors of a GICv3.
+    pub gicd_gpa: GuestPhysAddr,
+    pub gicc_gpa: GuestPhysAddr,
+    pub gicr_gpa: GuestPhysAddr,
+    /// The virtio-mmio transports hidden from the guest, followed by the one
+    /// forwarded to the device of the host at the same address.
+    pub dummy_virtio: Range<GuestPhysAddr>,
+}
 
-pub const GUEST_DTB_ADDR: usize = 0x4a00_0000;
+/// The guest VMs, created and started by [`super::init`].
+pub static GUEST_CONFIGS: &[GuestConfig] = &[GuestConfig {
+    cpus: &[0],
+    memory_base: 0x4000_0000,
+    memory_size: 0x800_0000, // 128M
+    entry: 0x4008_0000,
+    ipa_bits: 40, // 1T
+    // WFE is not trapped: it is woken up by the events of the guest (SEV,
+    // exclusive monitor clears), which the hypervisor cannot wait for.
+    traps: GuestTraps::TACR
+        .union(GuestTraps::TWI)
+        .union(GuestTraps::TSC)
+        .union(GuestTraps::TID3),
+    // Hide SVE, whose registers are not saved with the FP/SIMD state.
+    id_reg_overrides: &[IdRegOverride {
+        reg: SysReg::ID_AA64PFR0_EL1,
+        mask: 0xf << 32,
+        value: 0,
+    }],
+    // The virtual timer, the UART and the virtio-mmio transports.
+    owned_irqs: &[27..28, 33..34, 48..80],
+    dtb: &GUEST_DTB,
+    dtb_gpa: 0x4a00_0000,
+    initramfs: &GUEST_INITRAMFS,
+    initramfs_gpa: 0x4800_0000,
+    uart_gpa: 0x0900_0000,
+    gicd_gpa: 0x0800_0000,
+    gicc_gpa: 0x0801_0000,
+    gicr_gpa: 0x080a_0000,
+    dummy_virtio: 0x0a00_0000..0x0a00_3e00,
+}];
 
+/// The device tree of the guest, which describes a GICv2 or a GICv3 as the
+/// host one, at `gicd_gpa` and `gicc_gpa` or `gicr_gpa`.
+#[cfg(not(feature = "gicv3"))]
 #[link_section = ".dtb"]
 pub static GUEST_DTB: [u8; include_bytes!("../../../dts/linux_guest.dtb").len()] =
     *include_bytes!("../../../dts/linux_guest.dtb");
 
+#[cfg(feature = "gicv3")]
+#[link_section = ".dtb"]
+pub static GUEST_DTB: [u8; include_bytes!("../../../dts/linux_guest_gicv3.dtb").len()] =
+    *include_bytes!("../../../dts/linux_guest_gicv3.dtb");
+
 #[link_section = ".initramfs"]
 pub static GUEST_INITRAMFS: [u8; include_bytes!("../../../bin/initramfs.cpio.gz").len()] =
     *include_bytes!("../../../bin/initramfs.cpio.gz");
-
-const NONE: Option<GuestPhysMemorySet> = None;
-pub static GUEST_GPM: Mutex<[Option<GuestPhysMemorySet>; CPU_NUM]> = Mutex::new([NONE; CPU_NUM]);
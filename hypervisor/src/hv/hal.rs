@@ -1,9 +1,9 @@
-use rvm::{HostPhysAddr, HostVirtAddr, RvmHal, RvmVcpu};
+use aarch64_cpu::asm;
+use rvm::{HostPhysAddr, HostVirtAddr, RvmHal};
 
+use super::irq;
 use crate::mm::{address, frame};
 
-use super::vmexit;
-
 #[derive(Debug)]
 pub struct RvmHalImpl;
 
@@ -16,6 +16,14 @@ impl RvmHal for RvmHalImpl {
         unsafe { frame::dealloc_page(paddr) }
     }
 
+    fn alloc_contiguous_pages(num_pages: usize, align_log2: usize) -> Option<HostPhysAddr> {
+        unsafe { frame::alloc_pages(num_pages, align_log2) }
+    }
+
+    fn dealloc_contiguous_pages(paddr: HostPhysAddr, num_pages: usize) {
+        unsafe { frame::dealloc_pages(paddr, num_pages) }
+    }
+
     fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
         address::phys_to_virt(paddr)
     }
@@ -24,7 +32,9 @@ impl RvmHal for RvmHalImpl {
         address::virt_to_phys(vaddr)
     }
 
-    fn vmexit_handler(vcpu: &mut RvmVcpu<Self>) {
-        vmexit::vmexit_handler(vcpu);
+    fn kick_cpu(cpu_id: usize) {
+        irq::kick_cpu(cpu_id);
+        // The CPU may wait for its vCPU to be runnable.
+        asm::sev();
     }
 }
@@ -0,0 +1,74 @@
+//! Assignment of the physical interrupts to the hypervisor and the VMs.
+//!
+//! SGIs and PPIs are banked per CPU: the ones used by the hypervisor are
+//! reserved on all CPUs, and the others belong to the VM running on the CPU if
+//! its virtual GIC owns them. Each SPI is owned by exactly one VM, or by the
+//! hypervisor if no VM claims it.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use rvm::{RvmError, RvmResult};
+use spin::Mutex;
+
+use super::device_emu::Vgic;
+use crate::device::intc::{intc, HYP_TIMER_IRQ, MAINTENANCE_IRQ, SPI_BASE};
+
+/// SGI sent to a CPU to make its vCPU exit, e.g. to inject the interrupts
+/// routed to it from other CPUs.
+pub const KICK_SGI: usize = 15;
+
+/// SGIs and PPIs handled by the hypervisor on all CPUs.
+const HYP_PRIVATE_IRQS: [usize; 3] = [KICK_SGI, MAINTENANCE_IRQ, HYP_TIMER_IRQ];
+
+/// The owner of a physical interrupt.
+#[derive(Clone)]
+pub enum IrqOwner {
+    Hypervisor,
+    /// A VM, through its virtual GIC.
+    Vm(Arc<Vgic>),
+}
+
+/// The VM owning each assigned SPI.
+static SPI_OWNERS: Mutex<BTreeMap<usize, Arc<Vgic>>> = Mutex::new(BTreeMap::new());
+
+/// Assign the interrupts owned by the virtual GIC `vgic` to its VM. Fails
+/// without assigning any if one is reserved by the hypervisor, or if an SPI is
+/// already owned by another VM.
+pub fn assign_vm_irqs(vgic: &Arc<Vgic>) -> RvmResult {
+    let mut owners = SPI_OWNERS.lock();
+    for irq in vgic.owned_irqs() {
+        if HYP_PRIVATE_IRQS.contains(&irq) || owners.contains_key(&irq) {
+            warn!("IRQ {} is already owned", irq);
+            return Err(RvmError::AlreadyExists);
+        }
+    }
+    for irq in vgic.owned_irqs().filter(|&irq| irq >= SPI_BASE) {
+        owners.insert(irq, vgic.clone());
+    }
+    Ok(())
+}
+
+/// The owner of the physical interrupt `irq` taken on the current CPU, whose
+/// VM is `current` if it runs one.
+pub fn owner(irq: usize, current: Option<&Arc<Vgic>>) -> IrqOwner {
+    let vgic = if irq < SPI_BASE {
+        current.filter(|vgic| vgic.owns(irq)).cloned()
+    } else {
+        SPI_OWNERS.lock().get(&irq).cloned()
+    };
+    vgic.map_or(IrqOwner::Hypervisor, IrqOwner::Vm)
+}
+
+/// Make the vCPU of the physical CPU `cpu_id` exit, so that it injects the
+/// interrupts queued for it.
+pub fn kick_cpu(cpu_id: usize) {
+    intc().send_sgi(KICK_SGI, cpu_id);
+}
+
+/// Enable the interrupts of the hypervisor on the current CPU.
+pub fn init_percpu() {
+    for irq in HYP_PRIVATE_IRQS {
+        intc().set_enable(irq, true);
+    }
+}
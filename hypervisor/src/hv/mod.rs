@@ -1,43 +1,65 @@
 mod device_emu;
 pub mod gconfig;
-mod gpm;
 mod hal;
+mod irq;
+mod psci;
 mod vmexit;
 
-use rvm::{GuestPhysAddr, HostPhysAddr, HostVirtAddr, MemFlags, RvmPerCpu, RvmResult};
+use alloc::sync::Arc;
 
+use aarch64_cpu::asm;
+use rvm::{
+    GuestMemoryRegion, GuestPhysAddr, HostPhysAddr, MapRegion, MemFlags, RvmError, RvmPerCpu,
+    RvmResult, RvmVm, RvmVmConfig, VcpuState, VmState,
+};
+use spin::Mutex;
+
+use self::device_emu::Vgic;
 use self::gconfig::*;
-use self::gpm::{GuestMemoryRegion, GuestPhysMemorySet};
-use self::hal::RvmHalImpl;
+pub use self::hal::RvmHalImpl;
 use crate::arch::instructions;
-use crate::config::{CPU_NUM, CPU_TO_VM, VM_NUM};
-use crate::mm::address::{align_up, phys_to_virt, virt_to_phys};
+use crate::config::CPU_NUM;
+use crate::device::intc::intc;
+use crate::mm::address::{align_up, phys_to_virt};
+use crate::mm::map_device;
 
-#[repr(align(4096))]
-#[derive(Clone, Copy)]
-struct AlignedMemory<const LEN: usize>([u8; LEN]);
+pub type Vm = RvmVm<RvmHalImpl>;
+pub type Vcpu = rvm::RvmVcpu<RvmHalImpl>;
+pub type GuestPhysMemorySet = rvm::GuestPhysMemorySet<RvmHalImpl>;
 
-static mut GUEST_PHYS_MEMORY: [AlignedMemory<GUEST_PHYS_MEMORY_SIZE>; VM_NUM] =
-    [AlignedMemory([0; GUEST_PHYS_MEMORY_SIZE]); VM_NUM];
+/// A vCPU, with the VM and the virtual GIC it belongs to.
+#[derive(Clone)]
+struct VcpuRef {
+    vm: Arc<Vm>,
+    vgic: Arc<Vgic>,
+    vcpu_id: usize,
+}
 
-// pub static mut VIRTIO_HEADERS: AlignedMemory<VIRTIO_HEADER_TOTAL_SIZE> = AlignedMemory([0; VIRTIO_HEADER_TOTAL_SIZE]);
+/// The vCPU run by each physical CPU.
+static CPU_VCPUS: Mutex<[Option<VcpuRef>; CPU_NUM]> = {
+    const NONE: Option<VcpuRef> = None;
+    Mutex::new([NONE; CPU_NUM])
+};
 
-fn gpa_as_mut_ptr(guest_paddr: GuestPhysAddr, vm_id: usize) -> *mut u8 {
-    let offset = unsafe { &GUEST_PHYS_MEMORY[vm_id] as *const _ as usize };
-    let host_vaddr = guest_paddr - GUEST_PHYS_MEMORY_BASE + offset;
-    // debug!("Host vaddr is {:x}.", host_vaddr);
-    host_vaddr as *mut u8
+/// The vCPU run by the current physical CPU.
+fn current_vcpu() -> Option<VcpuRef> {
+    CPU_VCPUS.lock().get(instructions::cpu_id())?.clone()
 }
 
-fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize, vm_id: usize) {
+fn load_guest_image(
+    gpm: &GuestPhysMemorySet,
+    hpa: HostPhysAddr,
+    load_gpa: GuestPhysAddr,
+    size: usize,
+) {
     debug!("loading guest image");
+    // The image is loaded to the flash by QEMU.
+    map_device(hpa, size).unwrap();
     let image_ptr = phys_to_virt(hpa) as *const u8;
     let image = unsafe { core::slice::from_raw_parts(image_ptr, size) };
-    unsafe {
-        core::slice::from_raw_parts_mut(gpa_as_mut_ptr(load_gpa, vm_id), size)
-            .copy_from_slice(image)
-        // core::slice::from_raw_parts_mut(load_gpa as *mut _, size).copy_from_slice(image)
-    }
+    // The guest RAM is contiguous in host memory.
+    let load_ptr = phys_to_virt(gpm.gpa_to_hpa(load_gpa)) as *mut u8;
+    unsafe { core::slice::from_raw_parts_mut(load_ptr, size).copy_from_slice(image) }
 }
 
 // fn setup_guest_page_table() {
@@ -66,123 +88,168 @@ fn load_guest_image(hpa: HostPhysAddr, load_gpa: GuestPhysAddr, size: usize, vm_
 //     // );
 // }
 
-fn setup_gpm(cpu_id: usize) -> RvmResult<HostPhysAddr> {
-    // setup_guest_page_table();
-    // debug!("Set guest page table.");
-
-    // copy guest code
-    // unsafe {
-    //     core::ptr::copy_nonoverlapping(
-    //         test_guest as usize as *const u8,
-    //         gpa_as_mut_ptr(GUEST_ENTRY),
-    //         0x100,
-    //     );
-    // }
-
-    info!("before setup gpm.");
-    // create nested page table and add mapping
-    let vm_id = CPU_TO_VM[cpu_id];
-    info!("vm id {}.", vm_id);
-    let mut gpms = GUEST_GPM.lock();
-    // let guest_gpm = &mut gpm_guard[vm_id];
-    if let Some(gpm) = &gpms[vm_id] {
-        info!("initialized.");
-        Ok(gpm.nest_page_table_root())
-        // Err(rvm::RvmError::OutOfMemory)
-    } else {
-        load_guest_image(
-            GUEST_IMAGE_PADDR + vm_id * GUEST_IMAGE_SIZE,
-            GUEST_ENTRY,
-            GUEST_IMAGE_SIZE,
-            vm_id,
-        );
-        let mut gpm = GuestPhysMemorySet::new()?;
-        let guest_memory_regions = [
-            GuestMemoryRegion {
-                // RAM
-                gpa: GUEST_PHYS_MEMORY_BASE,
-                hpa: virt_to_phys(gpa_as_mut_ptr(GUEST_PHYS_MEMORY_BASE, vm_id) as HostVirtAddr),
-                size: GUEST_PHYS_MEMORY_SIZE,
-                flags: MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
-            },
-            GuestMemoryRegion {
-                // pl011
-                gpa: 0x0900_0000,
-                hpa: 0x0900_0000,
-                size: 0x1000,
-                flags: MemFlags::READ | MemFlags::WRITE | MemFlags::DEVICE,
-            },
-            GuestMemoryRegion {
-                // GICD
-                gpa: 0x0800_0000,
-                hpa: 0x0800_0000,
-                size: 0x10000,
-                flags: MemFlags::READ | MemFlags::WRITE | MemFlags::DEVICE,
-            },
+fn setup_gpm(
+    gpm: &mut GuestPhysMemorySet,
+    config: &GuestConfig,
+    image_paddr: HostPhysAddr,
+) -> RvmResult {
+    gpm.map_region(MapRegion::new_alloc(
+        config.memory_base,
+        config.memory_size,
+        MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+    )?)?;
+    let guest_memory_regions = [
+        GuestMemoryRegion {
+            // pl011
+            gpa: config.uart_gpa,
+            hpa: config.uart_gpa,
+            size: 0x1000,
+            flags: MemFlags::READ | MemFlags::WRITE | MemFlags::DEVICE,
+        },
+        GuestMemoryRegion {
+            // DTB
+            gpa: config.dtb_gpa,
+            hpa: config.dtb.as_ptr() as usize,
+            size: align_up(config.dtb.len()),
+            flags: MemFlags::READ | MemFlags::WRITE,
+        },
+        GuestMemoryRegion {
+            // INITRAMFS
+            gpa: config.initramfs_gpa,
+            hpa: config.initramfs.as_ptr() as usize,
+            size: align_up(config.initramfs.len()),
+            flags: MemFlags::READ | MemFlags::WRITE,
+        },
+    ];
+    for r in guest_memory_regions.into_iter() {
+        gpm.map_region(r.into())?;
+    }
+    // The guest accesses the GICv2 virtual CPU interface (GICV) in place of the
+    // CPU interface (GICC). The one of a GICv3 is accessed through system
+    // registers.
+    if let Some(gicv_base) = intc().vcpu_interface_base() {
+        gpm.map_region(
             GuestMemoryRegion {
-                // GICC
-                gpa: 0x0801_0000,
-                hpa: 0x0801_0000,
+                gpa: config.gicc_gpa,
+                hpa: gicv_base,
                 size: 0x10000,
                 flags: MemFlags::READ | MemFlags::WRITE | MemFlags::DEVICE,
-            },
-            GuestMemoryRegion {
-                // DTB
-                gpa: GUEST_DTB_ADDR,
-                hpa: GUEST_DTB.as_ptr() as usize,
-                size: align_up(GUEST_DTB.len()),
-                flags: MemFlags::READ | MemFlags::WRITE,
-            },
-            GuestMemoryRegion {
-                // INITRAMFS
-                gpa: GUEST_INITRAMFS_START,
-                hpa: GUEST_INITRAMFS.as_ptr() as usize,
-                size: align_up(GUEST_INITRAMFS.len()),
-                flags: MemFlags::READ | MemFlags::WRITE,
-            },
-        ];
-        for r in guest_memory_regions.into_iter() {
-            info!("mapping");
-            gpm.map_region(r.into())?;
+            }
+            .into(),
+        )?;
+    }
+    load_guest_image(gpm, image_paddr, config.entry, GUEST_IMAGE_SIZE);
+    Ok(())
+}
+
+/// Create the guest VM `vm_id` described by `config`.
+fn create_guest_vm(vm_id: usize, config: &GuestConfig) -> RvmResult<Arc<Vm>> {
+    let mut cpu_vcpus = CPU_VCPUS.lock();
+    for &cpu_id in config.cpus {
+        match cpu_vcpus.get(cpu_id) {
+            Some(None) => {}
+            Some(Some(_)) => {
+                warn!("CPU {} already runs a vCPU", cpu_id);
+                return Err(RvmError::AlreadyExists);
+            }
+            None => {
+                warn!("Invalid CPU {} for VM {}", cpu_id, vm_id);
+                return Err(RvmError::InvalidParam);
+            }
         }
-        let root = gpm.nest_page_table_root();
-        gpms[vm_id] = Some(gpm);
-        Ok(root)
     }
+
+    let mut vm = Vm::new(RvmVmConfig {
+        num_vcpus: config.cpus.len(),
+        ipa_bits: config.ipa_bits,
+        entry: config.entry,
+        boot_arg: config.dtb_gpa as u64,
+        traps: config.traps,
+    })?;
+    let image_paddr = GUEST_IMAGE_PADDR + vm_id * GUEST_IMAGE_SIZE;
+    setup_gpm(&mut vm.gpm(), config, image_paddr)?;
+    debug!("Setup GPM of VM {}: {:#x?}", vm_id, *vm.gpm());
+    let vgic = device_emu::add_virt_devices(&mut vm, config)?;
+    vmexit::register_default_sysreg_handlers(&mut vm, config);
+
+    let vm = Arc::new(vm);
+    for (vcpu_id, &cpu_id) in config.cpus.iter().enumerate() {
+        cpu_vcpus[cpu_id] = Some(VcpuRef {
+            vm: vm.clone(),
+            vgic: vgic.clone(),
+            vcpu_id,
+        });
+    }
+    Ok(vm)
 }
 
-pub fn run(cpu_id: usize, entry: usize, psci_context: usize) -> ! {
+/// Create and start the VMs of [`GUEST_CONFIGS`], before the secondary CPUs
+/// are started.
+pub fn init() {
+    for (vm_id, config) in GUEST_CONFIGS.iter().enumerate() {
+        info!(
+            "VM {}: DTB {:#x} to host {:#x}, initramfs {:#x} to host {:#x}",
+            vm_id,
+            config.dtb_gpa,
+            config.dtb.as_ptr() as usize,
+            config.initramfs_gpa,
+            config.initramfs.as_ptr() as usize
+        );
+        let vm = create_guest_vm(vm_id, config).expect("failed to create a guest VM");
+        vm.start().unwrap();
+    }
+}
+
+/// Run the vCPU assigned to the physical CPU `cpu_id`. It waits until the vCPU
+/// is powered on, either by the start of its VM or by a PSCI CPU_ON, and again
+/// after each power off.
+pub fn run(cpu_id: usize) -> ! {
+    irq::init_percpu();
+    let Some(VcpuRef { vm, vgic, vcpu_id }) = CPU_VCPUS.lock()[cpu_id].clone() else {
+        info!("No vCPU to run on CPU {}", cpu_id);
+        loop {
+            asm::wfe();
+        }
+    };
     println!("Starting virtualization...");
     println!("Hardware support: {:?}", rvm::has_hardware_support());
 
     let mut percpu = RvmPerCpu::<RvmHalImpl>::new(cpu_id);
-    info!("RVM new cpu");
     percpu.hardware_enable().unwrap();
-    info!("RVM new cpu create");
-    debug!("Vcpu Created.");
-
-    if cpu_id == 0 {}
-    let npt_root = setup_gpm(cpu_id).unwrap();
-    // info!("{:#x?}", gpm);
-    info!("Setup GPM.");
-
-    let vcpu_id = cpu_id - CPU_TO_VM[cpu_id];
-    info!("run vcpuid {}", vcpu_id);
-    // TODO: move this to vcpu init
-    unsafe {
-        core::arch::asm!(
-            "msr vmpidr_el2, {}", in(reg) vcpu_id
+    vm.create_vcpu(vcpu_id, &percpu).unwrap();
+    let vcpu = vm.vcpu(vcpu_id).unwrap();
+    instructions::flush_tlb_all();
+    loop {
+        let (entry, arg) = loop {
+            if let Some(power_on) = vm.take_power_on(vcpu_id) {
+                break power_on;
+            }
+            trace!("CPU {} waiting for vCPU {}", cpu_id, vcpu_id);
+            asm::wfe();
+        };
+        info!(
+            "CPU {} runs vCPU {} with entry {:#x}",
+            cpu_id, vcpu_id, entry
         );
+        {
+            let mut vcpu = vcpu.lock();
+            vcpu.reset(entry);
+            vcpu.regs_mut().x[0] = arg;
+        }
+        println!("Running guest...");
+        while vm.vcpu_state(vcpu_id) == Some(VcpuState::On) {
+            // Released between VM exits, so that the VM can be paused.
+            let mut vcpu = vcpu.lock();
+            if vm.state() == VmState::Paused {
+                drop(vcpu);
+                asm::wfe();
+                continue;
+            }
+            vgic.flush_pending_irqs(vcpu_id);
+            let exit = vcpu.run().unwrap();
+            vmexit::handle_vm_exit(&vm, &mut vcpu, exit).unwrap();
+        }
     }
-    let mut vcpu = percpu.create_vcpu(entry, npt_root).unwrap();
-    // vcpu.set_page_table_root(GUEST_PT1);
-    // vcpu.set_stack_pointer(GUEST_STACK_TOP);
-    // info!("{:#x?}", vcpu);
-    // TODO: move this to create_vcpu
-    vcpu.regs_mut().x[0] = psci_context as u64;
-    instructions::flush_tlb_all();
-    println!("Running guest...");
-    vcpu.run();
 }
 
 unsafe extern "C" fn test_guest() -> ! {
@@ -0,0 +1,161 @@
+//! Virtual PSCI 1.1 for guests, called over the HVC or SMC conduit.
+
+use aarch64_cpu::asm;
+use rvm::{RvmResult, VcpuState};
+
+use super::vmexit::wait_for_guest_irq;
+use super::{Vcpu, Vm};
+
+/// Set in the function IDs of the SMC64/HVC64 calling convention.
+const SMC64: u32 = 1 << 30;
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_SUSPEND: u32 = 0x8400_0001;
+const CPU_OFF: u32 = 0x8400_0002;
+const CPU_ON: u32 = 0x8400_0003;
+const AFFINITY_INFO: u32 = 0x8400_0004;
+const MIGRATE_INFO_TYPE: u32 = 0x8400_0006;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+const PSCI_FEATURES: u32 = 0x8400_000a;
+const SYSTEM_RESET2: u32 = 0x8400_0012;
+
+/// PSCI 1.1.
+const VERSION_1_1: u64 = 1 << 16 | 1;
+
+const SUCCESS: i64 = 0;
+const NOT_SUPPORTED: i64 = -1;
+const INVALID_PARAMETERS: i64 = -2;
+const ALREADY_ON: i64 = -4;
+const ON_PENDING: i64 = -5;
+const INTERNAL_FAILURE: i64 = -6;
+
+/// Trusted OS is not present or does not require migration.
+const MIGRATE_INFO_TYPE_NOT_PRESENT: i64 = 2;
+
+/// Power state of a vCPU, as returned by AFFINITY_INFO.
+const STATE_ON: i64 = 0;
+const STATE_OFF: i64 = 1;
+const STATE_ON_PENDING: i64 = 2;
+
+/// Whether `fid` is in the range of the PSCI function IDs.
+pub fn is_psci_call(fid: u64) -> bool {
+    fid >> 32 == 0 && (fid as u32 & !SMC64) & !0x1f == PSCI_VERSION
+}
+
+/// The 32-bit function ID of a supported PSCI function, `None` if it is not
+/// supported with the calling convention of `fid`.
+fn supported_function(fid: u32) -> Option<u32> {
+    let func = fid & !SMC64;
+    let supported = match func {
+        CPU_SUSPEND | CPU_ON | AFFINITY_INFO | SYSTEM_RESET2 => true,
+        PSCI_VERSION | CPU_OFF | MIGRATE_INFO_TYPE | SYSTEM_OFF | SYSTEM_RESET | PSCI_FEATURES => {
+            fid & SMC64 == 0
+        }
+        _ => false,
+    };
+    supported.then_some(func)
+}
+
+/// Handle the PSCI call in the registers of `vcpu`. For an SMC, the PC must
+/// already point to the next instruction.
+pub fn handle_psci(vm: &Vm, vcpu: &mut Vcpu) -> RvmResult {
+    let fid = vcpu.regs().x[0] as u32;
+    // Arguments of SMC32 calls are in W registers.
+    let arg_mask = if fid & SMC64 != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    let [arg0, arg1, arg2] = [1, 2, 3].map(|i| vcpu.regs().x[i] & arg_mask);
+    debug!(
+        "PSCI call {:#x} on CPU {}: {:#x?}",
+        fid,
+        vcpu.cpu_id,
+        [arg0, arg1, arg2]
+    );
+
+    let ret = match supported_function(fid) {
+        Some(PSCI_VERSION) => VERSION_1_1 as i64,
+        Some(PSCI_FEATURES) => match supported_function(arg0 as u32) {
+            // Original format of the power state parameter of CPU_SUSPEND, no
+            // OS-initiated mode.
+            Some(_) => SUCCESS,
+            None => NOT_SUPPORTED,
+        },
+        Some(CPU_SUSPEND) => {
+            // Any power state is entered as standby, which returns on wakeup.
+            wait_for_guest_irq(vm, vcpu);
+            SUCCESS
+        }
+        Some(CPU_OFF) => return cpu_off(vm, vcpu),
+        Some(CPU_ON) => cpu_on(vm, arg0, arg1 as usize, arg2),
+        Some(AFFINITY_INFO) => affinity_info(vm, arg0, arg1),
+        Some(MIGRATE_INFO_TYPE) => MIGRATE_INFO_TYPE_NOT_PRESENT,
+        Some(SYSTEM_OFF) => return system_off(vm),
+        Some(SYSTEM_RESET) => return system_reset(vm),
+        // Only the architectural warm reset, which is done as a cold reset.
+        Some(SYSTEM_RESET2) if arg0 == 0 => return system_reset(vm),
+        Some(SYSTEM_RESET2) => INVALID_PARAMETERS,
+        _ => NOT_SUPPORTED,
+    };
+    vcpu.regs_mut().x[0] = ret as u64;
+    Ok(())
+}
+
+/// The ID of the vCPU with affinity `mpidr` in `vm`.
+fn target_vcpu(vm: &Vm, mpidr: u64) -> Option<usize> {
+    // vCPUs only have Aff0.
+    let vcpu_id = mpidr as usize;
+    (mpidr & !0xff == 0 && vcpu_id < vm.num_vcpus()).then_some(vcpu_id)
+}
+
+fn cpu_on(vm: &Vm, mpidr: u64, entry: usize, context: u64) -> i64 {
+    let Some(target) = target_vcpu(vm, mpidr) else {
+        return INVALID_PARAMETERS;
+    };
+    match vm.power_on_vcpu(target, entry, context) {
+        Ok(VcpuState::Off) => {
+            info!("vCPU {} power on, entry {:#x}", target, entry);
+            // Wake up the CPU waiting for its vCPU to be powered on.
+            asm::sev();
+            SUCCESS
+        }
+        Ok(VcpuState::OnPending) => ON_PENDING,
+        Ok(VcpuState::On) => ALREADY_ON,
+        Err(_) => INTERNAL_FAILURE,
+    }
+}
+
+/// Power off the calling vCPU. Its CPU waits for it to be powered on again,
+/// after the current VM exit.
+fn cpu_off(vm: &Vm, vcpu: &Vcpu) -> RvmResult {
+    let vcpu_id = (vcpu.mpidr() & 0xff) as usize;
+    info!("vCPU {} power off", vcpu_id);
+    vm.power_off_vcpu(vcpu_id)
+}
+
+/// Stop the calling VM, the host and the other VMs keep running.
+fn system_off(vm: &Vm) -> RvmResult {
+    info!("VM system off");
+    vm.stop();
+    Ok(())
+}
+
+/// Restart the calling VM from its entry point.
+fn system_reset(vm: &Vm) -> RvmResult {
+    info!("VM system reset");
+    vm.reset()
+}
+
+fn affinity_info(vm: &Vm, mpidr: u64, lowest_level: u64) -> i64 {
+    if lowest_level != 0 {
+        return INVALID_PARAMETERS;
+    }
+    match target_vcpu(vm, mpidr).and_then(|id| vm.vcpu_state(id)) {
+        Some(VcpuState::On) => STATE_ON,
+        Some(VcpuState::Off) => STATE_OFF,
+        Some(VcpuState::OnPending) => STATE_ON_PENDING,
+        None => INVALID_PARAMETERS,
+    }
+}
@@ -1,159 +1,544 @@
-use core::arch::asm;
+use alloc::{sync::Arc, vec::Vec};
 
-use aarch64_cpu::{
-    asm::barrier,
-    registers::{ESR_EL2, FAR_EL2},
+use rvm::arch::{
+    self, read_id_reg, ArmExitReason, DataAbortInfo, SysReg, SysRegAccess, TimerContext, PTIMER_IRQ,
 };
-use rvm::{RvmResult, RvmVcpu};
-use tock_registers::interfaces::Readable;
-
-use crate::{
-    config::{GUEST_ENTRIES, PSCI_CONTEXT},
-    device::{gicv2::deactivate_irq, inject_irq, pending_irq},
-    hv::device_emu::all_virt_devices,
-    platform::psci::{PSCI_CPU_HVC_ON, PSCI_CPU_OFF, PSCI_CPU_ON},
+use rvm::{
+    GuestPhysAddr, GuestTraps, GuestVirtAddr, MemFlags, MmioDevice, RvmResult, SysRegHandler,
+    VcpuState, VmExit, VmState,
 };
 
-use super::{gconfig::GUEST_GPM, hal::RvmHalImpl};
-use crate::config::CPU_TO_VM;
+use crate::device::intc::{intc, MAINTENANCE_IRQ};
+use crate::mm::address::phys_to_virt;
 
-pub type Vcpu = RvmVcpu<RvmHalImpl>;
+use super::gconfig::{GuestConfig, IdRegOverride};
+use super::irq::{self, IrqOwner};
+use super::{current_vcpu, psci, GuestPhysMemorySet, RvmHalImpl, Vcpu, Vm};
 
-fn handle_hypercall(vcpu: &mut Vcpu) -> RvmResult {
-    let cpu_id = vcpu.cpu_id();
-    let mut regs = vcpu.regs_mut();
-    info!(
+/// Emulates the registers saved in the register file of the vCPU, which are
+/// loaded to hardware on the next VM entry.
+struct VcpuSysReg;
+
+impl VcpuSysReg {
+    fn reg_mut(vcpu: &mut Vcpu, reg: SysReg) -> RvmResult<&mut u64> {
+        let regs = vcpu.sys_regs_mut();
+        Ok(match reg {
+            SysReg::SCTLR_EL1 => &mut regs.sctlr_el1,
+            SysReg::ACTLR_EL1 => &mut regs.actlr_el1,
+            SysReg::TTBR0_EL1 => &mut regs.ttbr0_el1,
+            SysReg::TTBR1_EL1 => &mut regs.ttbr1_el1,
+            SysReg::TCR_EL1 => &mut regs.tcr_el1,
+            SysReg::AFSR0_EL1 => &mut regs.afsr0_el1,
+            SysReg::AFSR1_EL1 => &mut regs.afsr1_el1,
+            SysReg::ESR_EL1 => &mut regs.esr_el1,
+            SysReg::FAR_EL1 => &mut regs.far_el1,
+            SysReg::MAIR_EL1 => &mut regs.mair_el1,
+            SysReg::AMAIR_EL1 => &mut regs.amair_el1,
+            SysReg::CONTEXTIDR_EL1 => &mut regs.contextidr_el1,
+            SysReg::CSSELR_EL1 => &mut regs.csselr_el1,
+            _ => return Err(rvm::RvmError::Unsupported),
+        })
+    }
+}
+
+impl SysRegHandler<RvmHalImpl> for VcpuSysReg {
+    fn read(&self, vcpu: &mut Vcpu, reg: SysReg) -> RvmResult<u64> {
+        Ok(*Self::reg_mut(vcpu, reg)?)
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, reg: SysReg, val: u64) -> RvmResult {
+        *Self::reg_mut(vcpu, reg)? = val;
+        Ok(())
+    }
+}
+
+/// Emulates the EL1 physical timer, which is always trapped.
+struct PhysTimer;
+
+impl SysRegHandler<RvmHalImpl> for PhysTimer {
+    fn read(&self, vcpu: &mut Vcpu, reg: SysReg) -> RvmResult<u64> {
+        vcpu.timer()
+            .read_ptimer(reg)
+            .ok_or(rvm::RvmError::Unsupported)
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, reg: SysReg, val: u64) -> RvmResult {
+        vcpu.timer_mut()
+            .write_ptimer(reg, val)
+            .ok_or(rvm::RvmError::Unsupported)
+    }
+}
+
+/// Emulates reads of the ID registers with their hardware values, with some
+/// fields replaced by the overrides of the VM.
+struct IdReg {
+    overrides: &'static [IdRegOverride],
+}
+
+impl SysRegHandler<RvmHalImpl> for IdReg {
+    fn read(&self, vcpu: &mut Vcpu, reg: SysReg) -> RvmResult<u64> {
+        let hw = read_id_reg(reg, vcpu.sys_regs().csselr_el1).ok_or(rvm::RvmError::Unsupported)?;
+        Ok(match self.overrides.iter().find(|o| o.reg == reg) {
+            Some(o) => o.apply(hw),
+            None => hw,
+        })
+    }
+
+    fn write(&self, _: &mut Vcpu, _: SysReg, _: u64) -> RvmResult {
+        // Read-only.
+        Err(rvm::RvmError::Unsupported)
+    }
+}
+
+/// Ignores data cache maintenance by set/way.
+///
+/// These only act on the caches of the executing CPU, so a guest cannot use
+/// them to make its memory coherent once its vCPUs migrate or span CPUs, and
+/// the guest RAM is normal memory kept coherent by the hardware. Cleaning the
+/// whole RAM of the VM instead would be issued once per set and way.
+struct DcSetWay;
+
+impl SysRegHandler<RvmHalImpl> for DcSetWay {
+    fn read(&self, _: &mut Vcpu, _: SysReg) -> RvmResult<u64> {
+        Err(rvm::RvmError::Unsupported)
+    }
+
+    fn write(&self, _: &mut Vcpu, _: SysReg, _: u64) -> RvmResult {
+        Ok(())
+    }
+}
+
+/// Emulates cache maintenance by guest virtual address.
+///
+/// Data cache lines are cleaned and invalidated through the host mapping of
+/// the guest RAM, which covers any of the data cache operations. Addresses
+/// not mapped to RAM are ignored. Instruction caches may be indexed by the
+/// guest address, so they are invalidated entirely.
+struct CacheByVa;
+
+impl SysRegHandler<RvmHalImpl> for CacheByVa {
+    fn read(&self, _: &mut Vcpu, _: SysReg) -> RvmResult<u64> {
+        Err(rvm::RvmError::Unsupported)
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, reg: SysReg, val: u64) -> RvmResult {
+        if matches!(reg, SysReg::IC_IALLU | SysReg::IC_IALLUIS | SysReg::IC_IVAU) {
+            arch::flush_icache_all_is();
+            return Ok(());
+        }
+        let Some(v) = current_vcpu() else {
+            return Ok(());
+        };
+        let gpm = v.vm.gpm();
+        let Ok(gpa) = gpm.gva_to_gpa(vcpu, val as GuestVirtAddr).map(|t| t.gpa) else {
+            return Ok(());
+        };
+        if let Ok((hpa, flags)) = gpm.query(gpa) {
+            if !flags.contains(MemFlags::DEVICE) {
+                arch::clean_invalidate_dcache_line(phys_to_virt(hpa));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Emulates the TLB maintenance instructions by invalidating all the stage-1
+/// entries of the VM, which covers any of them.
+struct TlbMaintenance;
+
+impl SysRegHandler<RvmHalImpl> for TlbMaintenance {
+    fn read(&self, _: &mut Vcpu, _: SysReg) -> RvmResult<u64> {
+        Err(rvm::RvmError::Unsupported)
+    }
+
+    fn write(&self, _: &mut Vcpu, _: SysReg, _: u64) -> RvmResult {
+        arch::flush_guest_stage1_tlb();
+        Ok(())
+    }
+}
+
+/// Register the handlers of the EL1 physical timer, and of the registers and
+/// instructions trapped by the `traps` of `config`.
+///
+/// [`GuestTraps::TID0`] only traps AArch32 registers, and the registers
+/// trapped by [`GuestTraps::TIDCP`] have no handler, so their accesses are
+/// undefined as for the other unknown registers.
+pub fn register_default_sysreg_handlers(vm: &mut Vm, config: &GuestConfig) {
+    let traps = config.traps;
+    let devices = vm.devices_mut();
+    let mut register = |regs: Vec<SysReg>, handler: Arc<dyn SysRegHandler<RvmHalImpl>>| {
+        for reg in regs {
+            devices.register_sysreg_handler(reg, handler.clone());
+        }
+    };
+    register(
+        Vec::from([
+            TimerContext::CNTP_CTL_EL0,
+            TimerContext::CNTP_CVAL_EL0,
+            TimerContext::CNTP_TVAL_EL0,
+        ]),
+        Arc::new(PhysTimer),
+    );
+
+    let mut regs = Vec::new();
+    if traps.contains(GuestTraps::TACR) {
+        regs.push(SysReg::ACTLR_EL1);
+    }
+    if traps.contains(GuestTraps::TID2) {
+        regs.push(SysReg::CSSELR_EL1);
+    }
+    if traps.intersects(GuestTraps::TVM | GuestTraps::TRVM) {
+        regs.extend([
+            SysReg::SCTLR_EL1,
+            SysReg::TTBR0_EL1,
+            SysReg::TTBR1_EL1,
+            SysReg::TCR_EL1,
+            SysReg::AFSR0_EL1,
+            SysReg::AFSR1_EL1,
+            SysReg::ESR_EL1,
+            SysReg::FAR_EL1,
+            SysReg::MAIR_EL1,
+            SysReg::AMAIR_EL1,
+            SysReg::CONTEXTIDR_EL1,
+        ]);
+    }
+    register(regs, Arc::new(VcpuSysReg));
+
+    let mut id_regs = Vec::new();
+    if traps.contains(GuestTraps::TID1) {
+        id_regs.extend([SysReg::REVIDR_EL1, SysReg::AIDR_EL1]);
+    }
+    if traps.contains(GuestTraps::TID2) {
+        id_regs.extend([SysReg::CTR_EL0, SysReg::CCSIDR_EL1, SysReg::CLIDR_EL1]);
+    }
+    if traps.contains(GuestTraps::TID3) {
+        id_regs.extend(SysReg::feature_id_regs());
+    }
+    let overrides = config.id_reg_overrides;
+    register(id_regs, Arc::new(IdReg { overrides }));
+
+    if traps.contains(GuestTraps::TSW) {
+        let ops = Vec::from([SysReg::DC_ISW, SysReg::DC_CSW, SysReg::DC_CISW]);
+        register(ops, Arc::new(DcSetWay));
+    }
+
+    let mut cache_ops = Vec::new();
+    if traps.contains(GuestTraps::TPCP) {
+        cache_ops.extend([
+            SysReg::DC_IVAC,
+            SysReg::DC_CVAC,
+            SysReg::DC_CVAP,
+            SysReg::DC_CVADP,
+            SysReg::DC_CIVAC,
+        ]);
+    }
+    if traps.contains(GuestTraps::TPU) {
+        cache_ops.extend([
+            SysReg::IC_IALLUIS,
+            SysReg::IC_IALLU,
+            SysReg::IC_IVAU,
+            SysReg::DC_CVAU,
+        ]);
+    }
+    register(cache_ops, Arc::new(CacheByVa));
+
+    if traps.contains(GuestTraps::TTLB) {
+        register(SysReg::tlbi_ops().collect(), Arc::new(TlbMaintenance));
+    }
+}
+
+fn handle_hypercall(vm: &Vm, vcpu: &mut Vcpu) -> RvmResult {
+    let regs = vcpu.regs();
+    debug!(
         "VM exit: VMCALL({:#x}): {:?}",
         regs.x[0],
         [regs.x[1], regs.x[2], regs.x[3], regs.x[4]]
     );
-    match regs.x[0] as usize {
-        PSCI_CPU_ON | PSCI_CPU_HVC_ON => {
-            warn!("guest call psci on");
-            // we assume vcpuid are continous
-            let guest_gpms = GUEST_GPM.lock();
-            if let Some(gpm) = &guest_gpms[CPU_TO_VM[cpu_id as usize]] {
-                // if let Some(gpm) = &guest_gpm {
-                let pcpu_id = regs.x[1] as usize + cpu_id as usize;
-                // let entry = gpm.gpa_to_hpa(regs.x[2] as usize);
-                info!("pcpuid {}, entry gpa {:x}", pcpu_id, regs.x[2]);
-                unsafe {
-                    PSCI_CONTEXT[pcpu_id] = regs.x[3] as usize;
-                    barrier::dsb(barrier::SY); // necessary for WMM
-                    GUEST_ENTRIES[pcpu_id] = regs.x[2] as usize;
-                }
-                // psci_start_cpu(regs.x[1] as usize + cpu_id as usize, entry);
-                regs.x[0] = 0;
-            }
-        }
-        PSCI_CPU_OFF => loop {},
-        _ => {}
+    if psci::is_psci_call(regs.x[0]) {
+        psci::handle_psci(vm, vcpu)
+    } else {
+        warn!("Unknown hypercall {:#x}", regs.x[0]);
+        // SMCCC NOT_SUPPORTED.
+        vcpu.regs_mut().x[0] = -1i64 as u64;
+        Ok(())
+    }
+}
+
+/// Make the virtual interrupt `irq` pending for the vCPU of the current CPU.
+fn inject_virq(irq: usize) {
+    if let Some(v) = current_vcpu() {
+        v.vgic.inject_virq(v.vcpu_id, irq);
+    }
+}
+
+/// Inject the interrupt of the emulated physical timer.
+///
+/// The interrupt of the virtual timer is a physical interrupt owned by the
+/// guest, which fires once the vCPU is loaded again, and is forwarded through
+/// a hardware list register like the other owned interrupts.
+fn inject_timer_irqs(vcpu: &Vcpu) {
+    if vcpu.timer().ptimer_pending() {
+        inject_virq(PTIMER_IRQ);
+    }
+}
+
+/// Whether interrupts are pending for the vCPU of the current CPU, but not
+/// written to the list registers yet.
+fn queued_irq_pending() -> bool {
+    current_vcpu().map_or(false, |v| v.vgic.has_pending_irqs(v.vcpu_id))
+}
+
+/// Block the vCPU until an interrupt is pending for it, or until it must stop
+/// running guest code: powered off, or its VM no longer running.
+pub(super) fn wait_for_guest_irq(vm: &Vm, vcpu: &Vcpu) {
+    let vcpu_id = (vcpu.mpidr() & 0xff) as usize;
+    let timer = vcpu.timer();
+    while vm.state() == VmState::Running
+        && vm.vcpu_state(vcpu_id) == Some(VcpuState::On)
+        && !timer.vtimer_pending()
+        && !timer.ptimer_pending()
+        && !intc().guest_irq_pending()
+        && !queued_irq_pending()
+    {
+        vcpu.wait_for_interrupt();
+    }
+    inject_timer_irqs(vcpu);
+}
+
+/// Block the vCPU on a trapped WFI, until an interrupt is pending for it.
+fn handle_wfi(vm: &Vm, vcpu: &mut Vcpu) -> RvmResult {
+    vcpu.advance_pc()?;
+    wait_for_guest_irq(vm, vcpu);
+    Ok(())
+}
+
+/// The guest fetched an instruction outside of its memory, fault it.
+fn handle_iabt(
+    vm: &Vm,
+    vcpu: &mut Vcpu,
+    ipa: GuestPhysAddr,
+    gva: GuestVirtAddr,
+    fault_status: u8,
+) -> RvmResult {
+    // The mapping was being updated, see `handle_dabt`.
+    if arch::is_translation_fault(fault_status) && vm.gpm().is_mapped(ipa) {
+        return Ok(());
     }
+    warn!("Instruction abort at {:#x} (IPA {:#x})", gva, ipa);
+    vcpu.inject_instr_abort(gva);
     Ok(())
 }
 
-fn handle_iabt(vcpu: &mut Vcpu) -> RvmResult {
-    // todo!();
-    // info!("VTTBR_EL2: {:x}", VTTBR_EL2.get());
-    // Ok(())
-    error!("Instruction abort!!!");
-    Err(rvm::RvmError::ResourceBusy)
+fn handle_sysreg(vm: &Vm, vcpu: &mut Vcpu, access: &SysRegAccess) -> RvmResult {
+    let Some(handler) = vm.devices().sysreg_handler(access.reg) else {
+        warn!("No handler for the system register {:?}", access.reg);
+        vcpu.inject_undef();
+        return Ok(());
+    };
+    if access.is_read {
+        let val = handler.read(vcpu, access.reg)?;
+        if access.rt != 31 {
+            vcpu.regs_mut().x[access.rt] = val;
+        }
+    } else {
+        let val = if access.rt != 31 {
+            vcpu.regs().x[access.rt]
+        } else {
+            0
+        };
+        handler.write(vcpu, access.reg, val)?;
+    }
+    vcpu.advance_pc()
 }
 
-#[no_mangle]
-fn handle_dabt(vcpu: &mut Vcpu) -> RvmResult {
-    let far = FAR_EL2.get() & 0xfff;
-    let mut hpfar: u64 = 0;
-    unsafe {
-        asm!("mrs {}, HPFAR_EL2", out(reg) hpfar);
+/// Emulate a read of `size` bytes at `addr`. A 64-bit access is done as two
+/// 32-bit ones, low half first, as allowed for the 64-bit registers of the
+/// GICv3.
+fn mmio_read(
+    dev: &dyn MmioDevice<RvmHalImpl>,
+    vcpu_id: usize,
+    addr: usize,
+    size: u8,
+) -> RvmResult<u64> {
+    if size == 8 {
+        let low = dev.read(vcpu_id, addr, 4)? as u64;
+        return Ok(low | (dev.read(vcpu_id, addr + 4, 4)? as u64) << 32);
     }
-    let fault_vaddr = (hpfar << 8) | far;
+    Ok(dev.read(vcpu_id, addr, size)? as u64)
+}
+
+/// Emulate a write of `size` bytes at `addr`, as [`mmio_read`].
+fn mmio_write(
+    dev: &dyn MmioDevice<RvmHalImpl>,
+    vcpu_id: usize,
+    addr: usize,
+    val: u64,
+    size: u8,
+    gpm: &GuestPhysMemorySet,
+) -> RvmResult {
+    if size == 8 {
+        dev.write(vcpu_id, addr, val as u32, 4, gpm)?;
+        return dev.write(vcpu_id, addr + 4, (val >> 32) as u32, 4, gpm);
+    }
+    dev.write(vcpu_id, addr, val as u32, size, gpm)
+}
+
+#[no_mangle]
+fn handle_dabt(vm: &Vm, vcpu: &mut Vcpu, dabt: &DataAbortInfo) -> RvmResult {
+    let fault_vaddr = dabt.ipa;
     info!("handling dabt, fault addr 0x{:x}", fault_vaddr);
-    let iss = ESR_EL2.read(ESR_EL2::ISS);
-    let isv = iss >> 24;
-    let sas = iss >> 22 & 0x3;
-    let sse = iss >> 21 & 0x1;
-    let srt = iss >> 16 & 0x1f;
-    let ea = iss >> 9 & 0x1;
-    let cm = iss >> 8 & 0x1;
-    let s1ptw = iss >> 7 & 0x1;
-    let is_write = iss >> 6 & 0x1;
-    let size = 1 << sas;
-    let val = if is_write == 1 && srt != 31 {
-        vcpu.regs().x[srt as usize]
+
+    // Stage-2 permission fault on a write: the page may be write-protected for
+    // dirty logging, in which case the access is retried after the fixup.
+    if dabt.is_write && dabt.is_permission_fault() && vm.gpm().handle_dirty_write(fault_vaddr)? {
+        return Ok(());
+    }
+    // Translation fault in the guest memory: the mapping was being updated,
+    // e.g. a block was split by another CPU, retry the access.
+    if dabt.is_translation_fault() && vm.gpm().is_mapped(fault_vaddr) {
+        return Ok(());
+    }
+
+    let Some(access) = dabt.syndrome else {
+        warn!("No instruction syndrome for the data abort at {:#x}", fault_vaddr);
+        vcpu.inject_data_abort(dabt.gva, dabt.is_write);
+        return Ok(());
+    };
+    let size = access.size as u8;
+    let val = if dabt.is_write && access.reg != 31 {
+        vcpu.regs().x[access.reg]
     } else {
         0
     };
 
-    if let Some(dev) =
-        all_virt_devices(CPU_TO_VM[vcpu.cpu_id as usize]).find_mmio_device(fault_vaddr as usize)
-    {
-        // info!("iss {:x} is write {}", iss, is_write);
-        if is_write == 1 {
-            let cpu_id = vcpu.cpu_id;
-            let guest_id = CPU_TO_VM[cpu_id as usize];
-            let mut gpms = GUEST_GPM.lock();
-            // let guest_gpm = &mut gpm_guard[guest_id];
-            if let Some(gpm) = &gpms[guest_id] {
-                dev.write(fault_vaddr as usize, val as u32, size, gpm)?;
-            } else {
-                error!("Guest Without GPM!");
-            };
+    let vcpu_id = (vcpu.mpidr() & 0xff) as usize;
+    if let Some(dev) = vm.devices().find_mmio_device(fault_vaddr) {
+        if dabt.is_write {
+            mmio_write(dev.as_ref(), vcpu_id, fault_vaddr, val, size, &vm.gpm())?;
         } else {
-            vcpu.regs_mut().x[srt as usize] = dev.read(fault_vaddr as usize, size)? as u64;
-            // info!("elr {:x} srt {:x} read val {:x}", vcpu.elr, srt, vcpu.regs().x[srt as usize]);
+            let mut val = mmio_read(dev.as_ref(), vcpu_id, fault_vaddr, size)?;
+            if access.sign_extend && size < 8 {
+                let shift = 64 - access.size * 8;
+                val = (((val << shift) as i64) >> shift) as u64;
+            }
+            if !access.is_64bit {
+                val &= 0xffff_ffff;
+            }
+            if access.reg != 31 {
+                vcpu.regs_mut().x[access.reg] = val;
+            }
         }
         vcpu.advance_pc()?;
         Ok(())
     } else {
-        Err(rvm::RvmError::OutOfMemory)
+        warn!("Guest access to the unassigned address {:#x}", fault_vaddr);
+        vcpu.inject_data_abort(dabt.gva, dabt.is_write);
+        Ok(())
     }
 }
 
-#[no_mangle]
-pub fn vmexit_handler(vcpu: &mut Vcpu) -> RvmResult {
-    let exit_info = vcpu.exit_info()?;
-    // if vcpu.cpu_id != 0 {
-    // println!("cpu {} exit", vcpu.cpu_id);
-    // }
-    // debug!("VM exit: {:#x?}", exit_info);
-
-    let res = match exit_info.exit_reason {
-        Some(ESR_EL2::EC::Value::HVC64) => handle_hypercall(vcpu),
-        Some(ESR_EL2::EC::Value::InstrAbortLowerEL)
-        | Some(ESR_EL2::EC::Value::InstrAbortCurrentEL) => handle_iabt(vcpu),
-        Some(ESR_EL2::EC::Value::DataAbortLowerEL)
-        | Some(ESR_EL2::EC::Value::DataAbortCurrentEL) => handle_dabt(vcpu),
-        _ => panic!(
-            "Unhandled VM-Exit reason {:?}:\n{:#x?}",
-            exit_info.exit_reason.unwrap() as u64,
-            vcpu
-        ),
+pub fn handle_vm_exit(vm: &Vm, vcpu: &mut Vcpu, exit: VmExit) -> RvmResult {
+    // debug!("VM exit: {:#x?}", exit);
+    // The instruction that caused the exit, HVC returns after it.
+    let fault_pc = match exit {
+        VmExit::Exception(ArmExitReason::Hvc(_)) => vcpu.elr - 4,
+        _ => vcpu.elr,
+    };
+    let res = match exit {
+        VmExit::Exception(ref reason) => match reason {
+            ArmExitReason::Hvc(_) => handle_hypercall(vm, vcpu),
+            ArmExitReason::Smc(_) => {
+                // The trapped SMC is not executed, skip it.
+                vcpu.advance_pc()?;
+                handle_hypercall(vm, vcpu)
+            }
+            ArmExitReason::Wfi => handle_wfi(vm, vcpu),
+            // WFE may complete spuriously, the guest retries its spin loop.
+            ArmExitReason::Wfe => vcpu.advance_pc(),
+            ArmExitReason::SysReg(access) => handle_sysreg(vm, vcpu, access),
+            ArmExitReason::InstrAbort {
+                ipa,
+                gva,
+                fault_status,
+            } => handle_iabt(vm, vcpu, *ipa, *gva, *fault_status),
+            ArmExitReason::DataAbort(dabt) => handle_dabt(vm, vcpu, dabt),
+            _ => Err(rvm::RvmError::Unsupported),
+        },
+        VmExit::Irq => irq_handler(),
+        VmExit::Fiq => {
+            // All interrupts of the GIC are signaled as IRQs, a FIQ is spurious.
+            warn!("Unexpected FIQ from guest");
+            Ok(())
+        }
+        VmExit::SError(esr) => {
+            // A physical SError caused by the guest, reflect it as a virtual one.
+            warn!("SError from guest, ESR_EL2 {:#x}", esr);
+            vcpu.inject_serror();
+            Ok(())
+        }
     };
 
-    if res.is_err() {
-        panic!(
-            "Failed to handle VM-exit {:?}:\n{:#x?}",
-            exit_info.exit_reason.unwrap() as u64,
-            vcpu
+    // Only the guest is affected by the failed emulation: the instruction that
+    // caused the exit faults, as if the access was denied by the hardware.
+    if let Err(e) = res {
+        warn!(
+            "Failed to handle VM-exit {:x?}: {:?}\n{:#x?}",
+            exit, e, vcpu
         );
+        if let VmExit::Exception(reason) = exit {
+            vcpu.elr = fault_pc;
+            match reason {
+                ArmExitReason::DataAbort(dabt) => vcpu.inject_data_abort(dabt.gva, dabt.is_write),
+                _ => vcpu.inject_undef(),
+            }
+        }
     }
-
+    // The emulated physical timer fires with a VM exit, by the EL2 timer.
+    inject_timer_irqs(vcpu);
     Ok(())
 }
 
+/// Handle a physical interrupt taken to EL2. The interrupts of the hypervisor
+/// are completed here, and the ones of a VM are routed to their target vCPU,
+/// which injects them on its next VM entry. The CPU of the target is kicked if
+/// it is not the current one.
+///
+/// The maintenance interrupt signals that list registers are free again, and
+/// refills them with the interrupts pending for the current vCPU.
 #[no_mangle]
 pub fn irq_handler() -> RvmResult {
     debug!("IRQ routed to EL2");
-    if let Some(irq_id) = pending_irq() {
-        // TODO: Judge whether vcpu is running on the vcpu.
-        // if irq_id != 30 {
-        //     info!("IRQ {} routed to EL2", irq_id);
-        // }
-        deactivate_irq(irq_id);
-        inject_irq(irq_id);
+    let Some(irq_id) = intc().pending_irq() else {
+        return Ok(());
+    };
+    // Priority drop, the interrupt stays active until deactivated by its owner.
+    intc().eoi(irq_id);
+    let current = current_vcpu();
+    match irq::owner(irq_id, current.as_ref().map(|v| &v.vgic)) {
+        IrqOwner::Hypervisor => {
+            if irq_id == MAINTENANCE_IRQ {
+                if let Some(v) = &current {
+                    v.vgic.flush_pending_irqs(v.vcpu_id);
+                }
+            }
+            // Kicks and the EL2 timer only make the vCPU exit or wake it up,
+            // the emulated physical timer is injected after the exit.
+            intc().deactivate(irq_id);
+        }
+        IrqOwner::Vm(vgic) => {
+            let current_id = current
+                .filter(|v| Arc::ptr_eq(&v.vgic, &vgic))
+                .map(|v| v.vcpu_id);
+            match vgic.route_irq(irq_id, current_id) {
+                Some(target) if Some(target) == current_id => {}
+                Some(target) => irq::kick_cpu(vgic.vcpu_cpu(target)),
+                None => {
+                    warn!("IRQ {} targets no vCPU", irq_id);
+                    intc().deactivate(irq_id);
+                }
+            }
+        }
     }
-
     Ok(())
 }
 
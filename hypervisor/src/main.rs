@@ -26,11 +26,7 @@ mod lang_items;
 
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::{
-    config::{GUEST_ENTRIES, PSCI_CONTEXT},
-    hv::gconfig::{GUEST_DTB, GUEST_DTB_ADDR, GUEST_ENTRY, GUEST_INITRAMFS, GUEST_INITRAMFS_START},
-    platform::mp::start_secondary_cpus,
-};
+use crate::platform::mp::start_secondary_cpus;
 
 static INIT_OK: AtomicBool = AtomicBool::new(false);
 
@@ -101,22 +97,12 @@ fn rust_main(cpu_id: usize, dtb_addr: usize) {
 
     mm::init();
     device::init();
+    mm::activate_kernel_page_table();
     INIT_OK.store(true, Ordering::SeqCst);
     info!("Initialization completed.\n");
-    info!(
-        "GUEST DTB 0x{:x} to HOST 0x{:x}",
-        GUEST_DTB_ADDR,
-        GUEST_DTB.as_ptr() as usize
-    );
-    info!(
-        "GUEST INITRAMFS 0x{:x} to HOST 0x{:x}",
-        GUEST_INITRAMFS_START,
-        GUEST_INITRAMFS.as_ptr() as usize
-    );
+    hv::init();
     start_secondary_cpus(cpu_id);
-    unsafe {
-        hv::run(cpu_id, GUEST_ENTRY, GUEST_DTB_ADDR);
-    }
+    hv::run(cpu_id);
     // arch::instructions::wait_for_ints();
 }
 
@@ -125,18 +111,9 @@ fn rust_main_secondary(cpu_id: usize) {
     // todo
     arch::init();
     device::init();
+    mm::activate_kernel_page_table();
     info!("Hello World from cpu {}", cpu_id);
-    // Safety: Modify to usize is atomic; there is most one writer at the same time.
-    unsafe {
-        while GUEST_ENTRIES[cpu_id] == 0 {
-            trace!("secondary cpu {} waiting", cpu_id);
-        }
-        info!(
-            "secondary cpu {} will run a vcpu with entry 0x{:x}",
-            cpu_id, GUEST_ENTRIES[cpu_id]
-        );
-        hv::run(cpu_id, GUEST_ENTRIES[cpu_id], PSCI_CONTEXT[cpu_id]);
-    }
+    hv::run(cpu_id);
     // console_putchar('z' as u8);
     // console_putchar('b' as u8);
     // console_putchar('d' as u8);
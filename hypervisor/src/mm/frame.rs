@@ -10,6 +10,10 @@ type FrameAlloc = bitmap_allocator::BitAlloc1M;
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
 
+// Alignment of the allocator base, so that aligned frame indexes are also
+// aligned physical addresses (up to 2M, e.g. guest RAM mapped with blocks).
+const BASE_ALIGN: usize = PAGE_SIZE * 512;
+
 struct FrameAllocator {
     base: PhysAddr,
     inner: FrameAlloc,
@@ -24,8 +28,8 @@ impl FrameAllocator {
     }
 
     fn init(&mut self, base: PhysAddr, size: usize) {
-        self.base = align_up(base);
-        let page_count = align_up(size) / PAGE_SIZE;
+        self.base = (base + BASE_ALIGN - 1) & !(BASE_ALIGN - 1);
+        let page_count = (base + size - self.base) / PAGE_SIZE;
         self.inner.insert(0..page_count);
     }
 
@@ -35,6 +39,15 @@ impl FrameAllocator {
         ret
     }
 
+    unsafe fn alloc_contiguous(&mut self, num_pages: usize, align_log2: usize) -> Option<PhysAddr> {
+        let ret = self
+            .inner
+            .alloc_contiguous(num_pages, align_log2)
+            .map(|idx| idx * PAGE_SIZE + self.base);
+        trace!("Allocate {} contiguous frames: {:x?}", num_pages, ret);
+        ret
+    }
+
     unsafe fn dealloc(&mut self, target: PhysAddr) {
         trace!("Deallocate frame: {:x}", target);
         self.inner.dealloc((target - self.base) / PAGE_SIZE)
@@ -49,6 +62,19 @@ pub unsafe fn dealloc_page(paddr: PhysAddr) {
     FRAME_ALLOCATOR.lock().dealloc(paddr)
 }
 
+pub unsafe fn alloc_pages(num_pages: usize, align_log2: usize) -> Option<PhysAddr> {
+    FRAME_ALLOCATOR
+        .lock()
+        .alloc_contiguous(num_pages, align_log2)
+}
+
+pub unsafe fn dealloc_pages(paddr: PhysAddr, num_pages: usize) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..num_pages {
+        allocator.dealloc(paddr + i * PAGE_SIZE)
+    }
+}
+
 pub(super) fn init() {
     extern "C" {
         fn ekernel();
@@ -2,8 +2,10 @@ pub mod address;
 
 pub mod frame;
 mod heap;
+mod paging;
 
 pub use address::{PhysAddr, VirtAddr};
+pub use paging::{activate as activate_kernel_page_table, map_device};
 
 pub const PAGE_SIZE: usize = 0x1000;
 
@@ -13,4 +15,5 @@ pub fn init_heap_early() {
 
 pub fn init() {
     frame::init();
+    paging::init();
 }
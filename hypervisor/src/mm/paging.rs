@@ -0,0 +1,139 @@
+//! The page table of the hypervisor's own EL2 address space.
+//!
+//! It replaces the boot page table once the frame allocator is ready: kernel
+//! sections are mapped with their own permissions, the boot stacks have guard
+//! pages, and devices are only accessible after being mapped by their drivers.
+
+use alloc::collections::BTreeSet;
+
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::TTBR0_EL2;
+use rvm::{MemFlags, PageSize, PageTable, RvmError, RvmResult};
+use spin::Mutex;
+use tock_registers::interfaces::Writeable;
+
+use super::address::{align_down, align_up, phys_to_virt, virt_to_phys, PhysAddr};
+use super::PAGE_SIZE;
+use crate::arch::instructions;
+use crate::config::{BOOT_KERNEL_STACK_SIZE, CPU_NUM, PHYS_MEMORY_BASE, PHYS_MEMORY_END};
+use crate::hv::RvmHalImpl;
+use crate::platform::BOOT_STACK_STRIDE;
+
+type HostPageTable = PageTable<RvmHalImpl>;
+
+struct KernelPageTable {
+    pt: HostPageTable,
+    /// Pages mapped by `map_device`.
+    device_pages: BTreeSet<PhysAddr>,
+}
+
+static KERNEL_PAGE_TABLE: Mutex<Option<KernelPageTable>> = Mutex::new(None);
+
+// AP[1] is RES1 in the EL2 translation regime, and is set by `USER`.
+const KERNEL_RW: MemFlags = MemFlags::READ.union(MemFlags::WRITE).union(MemFlags::USER);
+const KERNEL_RO: MemFlags = MemFlags::READ.union(MemFlags::USER);
+const KERNEL_RX: MemFlags = KERNEL_RO.union(MemFlags::EXECUTE);
+const DEVICE_RW: MemFlags = KERNEL_RW.union(MemFlags::DEVICE);
+
+extern "C" {
+    fn stext();
+    fn etext();
+    fn erodata();
+    fn sdata();
+    fn edata();
+    fn boot_stack_top();
+    fn ekernel();
+}
+
+/// Identity-map the physical range `[start, end)` with `flags`.
+fn map_range(pt: &mut HostPageTable, start: PhysAddr, end: PhysAddr, flags: MemFlags) -> RvmResult {
+    debug!("mapping [{:#x}, {:#x}) {:?}", start, end, flags);
+    pt.map_region(phys_to_virt(start), start, end - start, flags, true)
+}
+
+fn new_kernel_page_table() -> RvmResult<HostPageTable> {
+    let mut pt = HostPageTable::new()?;
+    let sym = |f: unsafe extern "C" fn()| virt_to_phys(f as usize);
+
+    map_range(&mut pt, PHYS_MEMORY_BASE, sym(stext), KERNEL_RW)?;
+    map_range(&mut pt, sym(stext), sym(etext), KERNEL_RX)?;
+    // The guest DTB and initramfs are between the text and the rodata.
+    map_range(&mut pt, sym(etext), sym(erodata), KERNEL_RO)?;
+    map_range(&mut pt, sym(sdata), sym(edata), KERNEL_RW)?;
+    for cpu_id in 0..CPU_NUM {
+        let stack_top = sym(boot_stack_top) - cpu_id * BOOT_STACK_STRIDE;
+        map_range(
+            &mut pt,
+            stack_top - BOOT_KERNEL_STACK_SIZE,
+            stack_top,
+            KERNEL_RW,
+        )?;
+    }
+    map_range(&mut pt, sym(boot_stack_top), sym(ekernel), KERNEL_RW)?;
+    // Free memory of the frame allocator.
+    map_range(
+        &mut pt,
+        align_up(sym(ekernel)),
+        align_down(PHYS_MEMORY_END),
+        KERNEL_RW,
+    )?;
+    Ok(pt)
+}
+
+/// Build the kernel page table. Devices are mapped later by their drivers.
+pub(super) fn init() {
+    let pt = new_kernel_page_table().expect("failed to build the kernel page table");
+    info!("Kernel page table root: {:#x}", pt.root_paddr());
+    *KERNEL_PAGE_TABLE.lock() = Some(KernelPageTable {
+        pt,
+        device_pages: BTreeSet::new(),
+    });
+}
+
+/// Switch the current CPU from the boot page table to the kernel page table.
+pub fn activate() {
+    let root = KERNEL_PAGE_TABLE
+        .lock()
+        .as_ref()
+        .expect("kernel page table is not initialized")
+        .pt
+        .root_paddr();
+    // Make the table writes visible to the walker before switching to it.
+    barrier::dsb(barrier::ISHST);
+    TTBR0_EL2.set(root as _);
+    instructions::flush_tlb_all();
+}
+
+/// Identity-map the device registers at `[paddr, paddr + size)` as device
+/// memory. Pages already mapped as devices are skipped, so that drivers
+/// initialized on every CPU can call it more than once.
+///
+/// Fails without mapping any page if the range overlaps normal memory.
+pub fn map_device(paddr: PhysAddr, size: usize) -> RvmResult {
+    let mut guard = KERNEL_PAGE_TABLE.lock();
+    let kpt = guard
+        .as_mut()
+        .expect("kernel page table is not initialized");
+    let (start, end) = (align_down(paddr), align_up(paddr + size));
+    let pages = (start..end).step_by(PAGE_SIZE);
+    let normal = pages.clone().find(|&paddr| {
+        !kpt.device_pages.contains(&paddr) && kpt.pt.query(phys_to_virt(paddr)).is_ok()
+    });
+    if let Some(paddr) = normal {
+        warn!("device page {:#x} is mapped as normal memory", paddr);
+        return Err(RvmError::InvalidParam);
+    }
+    for paddr in pages {
+        if !kpt.device_pages.contains(&paddr) {
+            kpt.pt
+                .map(phys_to_virt(paddr), paddr, PageSize::Size4K, DEVICE_RW)?;
+            kpt.device_pages.insert(paddr);
+        }
+    }
+    debug!("mapped device [{:#x}, {:#x})", start, end);
+    // Make the new entries visible to the table walker, invalid entries are
+    // never cached in the TLB.
+    barrier::dsb(barrier::ISHST);
+    barrier::isb(barrier::SY);
+    Ok(())
+}
@@ -6,11 +6,14 @@ use rvm::{GenericPTE, MemFlags, Stage1PTE};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 use crate::arch::instructions;
-use crate::config::{BOOT_KERNEL_STACK_SIZE, CPU_NUM};
+use crate::config::{BOOT_KERNEL_STACK_SIZE, BOOT_STACK_GUARD_SIZE, CPU_NUM};
+
+/// Distance between the boot stack tops of two CPUs. The boot stack of each
+/// CPU is preceded by a guard, which is not mapped in the kernel page table.
+pub const BOOT_STACK_STRIDE: usize = BOOT_KERNEL_STACK_SIZE + BOOT_STACK_GUARD_SIZE;
 
 #[link_section = ".bss.stack"]
-static mut BOOT_STACK: [u8; BOOT_KERNEL_STACK_SIZE * CPU_NUM] =
-    [0; BOOT_KERNEL_STACK_SIZE * CPU_NUM];
+static mut BOOT_STACK: [u8; BOOT_STACK_STRIDE * CPU_NUM] = [0; BOOT_STACK_STRIDE * CPU_NUM];
 
 #[link_section = ".data.boot_page_table"]
 static mut BOOT_PT_L0: [Stage1PTE; 512] = [Stage1PTE::empty(); 512];
@@ -129,7 +132,7 @@ unsafe extern "C" fn _start_secondary() -> ! {
 
         mrs     x0, mpidr_el1
         and     x0, x0, #0xffffff
-        mov     x19, {boot_stack_size}
+        mov     x19, {boot_stack_stride}
         mul     x19, x0, x19        
         sub     x8, x8, x19
         mov     sp, x8
@@ -151,7 +154,7 @@ unsafe extern "C" fn _start_secondary() -> ! {
         init_boot_page_table = sym init_boot_page_table,
         init_mmu = sym init_mmu,
         rust_main_secondary = sym crate::rust_main_secondary,
-        boot_stack_size = const BOOT_KERNEL_STACK_SIZE,
+        boot_stack_stride = const BOOT_STACK_STRIDE,
         options(noreturn),
     )
 }
@@ -1,8 +1,8 @@
 use core::arch::asm;
 
-pub const PSCI_CPU_HVC_ON: usize = 0xc4000003;
 pub const PSCI_CPU_ON: usize = 0x84000003;
-pub const PSCI_CPU_OFF: usize = 0x84000008;
+pub const PSCI_SYSTEM_OFF: usize = 0x84000008;
+pub const PSCI_SYSTEM_RESET: usize = 0x84000009;
 
 fn psci_smc_call(func: usize, args0: usize, args1: usize, args2: usize) -> usize {
     let ret;
@@ -21,3 +21,13 @@ pub fn psci_start_cpu(cpuid: usize, entry: usize) {
     assert_eq!(psci_smc_call(PSCI_CPU_ON, cpuid, entry, 0), 0);
     info!("cpu {} started.", cpuid)
 }
+
+pub fn psci_system_off() -> ! {
+    psci_smc_call(PSCI_SYSTEM_OFF, 0, 0, 0);
+    panic!("system off failed")
+}
+
+pub fn psci_system_reset() -> ! {
+    psci_smc_call(PSCI_SYSTEM_RESET, 0, 0, 0);
+    panic!("system reset failed")
+}
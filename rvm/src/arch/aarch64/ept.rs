@@ -1,6 +1,12 @@
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::mm::{GenericPTE, HostPhysAddr, Level4PageTable, MemFlags, PAGE_SIZE};
+use aarch64_cpu::registers::{ID_AA64MMFR0_EL1, ID_AA64MMFR2_EL1, VTCR_EL2};
+use tock_registers::interfaces::Readable;
+
+use crate::mm::PAGE_SIZE;
+use crate::mm::{GenericPTE, HostPhysAddr, Level4PageTable, MemFlags, MemType, Shareability};
+use crate::{RvmHal, RvmResult};
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -46,30 +52,111 @@ bitflags::bitflags! {
     }
 }
 
-#[repr(u64)]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum MemType {
-    Device = 1,
-    Normal = 15,
+/// Whether stage-2 descriptors use the FEAT_S2FWB encoding of MemAttr.
+static S2FWB_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the CPU implements FEAT_S2FWB.
+pub fn has_s2fwb() -> bool {
+    ID_AA64MMFR2_EL1.read(ID_AA64MMFR2_EL1::FWB) != 0
+}
+
+/// Make the memory types of stage-2 mappings override the ones of the guest
+/// stage-1 translation (FEAT_S2FWB), instead of combining with them.
+///
+/// It changes the encoding of stage-2 descriptors, so it must be called before
+/// creating any stage-2 page table and vCPU.
+pub fn enable_s2fwb() -> RvmResult {
+    if !has_s2fwb() {
+        return rvm_err!(Unsupported, "FEAT_S2FWB is not implemented");
+    }
+    S2FWB_ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+pub(crate) fn s2fwb_enabled() -> bool {
+    S2FWB_ENABLED.load(Ordering::Acquire)
+}
+
+/// Encoding of `mem_type` in the MemAttr[3:0] field of stage-2 descriptors.
+///
+/// With FEAT_S2FWB, write-through cannot be forced, the guest attributes are
+/// used instead.
+const fn mem_attr(mem_type: MemType, fwb: bool) -> u64 {
+    match (mem_type, fwb) {
+        (MemType::DeviceNgnRnE, _) => 0b0000,
+        (MemType::DeviceNgnRE, _) => 0b0001,
+        (MemType::DeviceGre, _) => 0b0011,
+        // Outer and inner attributes in MemAttr[3:2] and MemAttr[1:0].
+        (MemType::NormalNonCacheable, false) => 0b0101,
+        (MemType::NormalWriteThrough, false) => 0b1010,
+        (MemType::NormalWriteBack, false) => 0b1111,
+        (MemType::NormalNonCacheable, true) => 0b0101,
+        (MemType::NormalWriteThrough, true) => 0b0111,
+        (MemType::NormalWriteBack, true) => 0b0110,
+    }
+}
+
+/// Decode the MemAttr[3:0] field of stage-2 descriptors, the cacheability of
+/// Normal memory is the inner one.
+pub(super) const fn mem_type_of(mem_attr: u64, fwb: bool) -> MemType {
+    if fwb {
+        match mem_attr & 0b111 {
+            0b000 => MemType::DeviceNgnRnE,
+            0b001 => MemType::DeviceNgnRE,
+            0b010 | 0b011 => MemType::DeviceGre,
+            0b110 => MemType::NormalWriteBack,
+            0b111 => MemType::NormalWriteThrough,
+            _ => MemType::NormalNonCacheable,
+        }
+    } else if mem_attr & 0b1100 == 0 {
+        match mem_attr {
+            0b0000 => MemType::DeviceNgnRnE,
+            0b0001 => MemType::DeviceNgnRE,
+            _ => MemType::DeviceGre,
+        }
+    } else {
+        match mem_attr & 0b11 {
+            0b10 => MemType::NormalWriteThrough,
+            0b11 => MemType::NormalWriteBack,
+            _ => MemType::NormalNonCacheable,
+        }
+    }
 }
 
 impl DescriptorAttr {
-    const ATTR_INDEX_MASK: u64 = 0b1111_00;
+    const MEM_ATTR_SHIFT: u64 = 2;
 
-    const fn from_mem_type(mem_type: MemType) -> Self {
-        let mut bits = (mem_type as u64) << 2;
-        if matches!(mem_type, MemType::Normal) {
-            bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
+    const fn from_mem_type(mem_type: MemType, shareability: Shareability, fwb: bool) -> Self {
+        let mut bits = mem_attr(mem_type, fwb) << Self::MEM_ATTR_SHIFT;
+        let is_device = matches!(
+            mem_type,
+            MemType::DeviceNgnRnE | MemType::DeviceNgnRE | MemType::DeviceGre
+        );
+        // The shareability of Device memory is ignored.
+        if !is_device {
+            bits |= match shareability {
+                Shareability::NonShareable => 0,
+                Shareability::OuterShareable => Self::SHAREABLE.bits(),
+                Shareability::InnerShareable => Self::INNER.bits() | Self::SHAREABLE.bits(),
+            };
         }
         Self::from_bits_truncate(bits)
     }
 
-    fn mem_type(&self) -> MemType {
-        let idx = (self.bits() & Self::ATTR_INDEX_MASK) >> 2;
-        match idx {
-            1 => MemType::Device,
-            15 => MemType::Normal,
-            _ => panic!("Invalid memory attribute index"),
+    fn mem_type(&self, fwb: bool) -> MemType {
+        mem_type_of(
+            (self.bits() & Self::ATTR.bits()) >> Self::MEM_ATTR_SHIFT,
+            fwb,
+        )
+    }
+
+    fn shareability(&self) -> Shareability {
+        if !self.contains(Self::SHAREABLE) {
+            Shareability::NonShareable
+        } else if self.contains(Self::INNER) {
+            Shareability::InnerShareable
+        } else {
+            Shareability::OuterShareable
         }
     }
 }
@@ -87,20 +174,18 @@ impl From<DescriptorAttr> for MemFlags {
         if !attr.contains(DescriptorAttr::XN_1) {
             flags |= Self::EXECUTE;
         }
-        if attr.mem_type() == MemType::Device {
-            flags |= Self::DEVICE;
+        let flags = flags.with_mem_type(attr.mem_type(s2fwb_enabled()));
+        if flags.contains(Self::DEVICE) {
+            flags
+        } else {
+            flags.with_shareability(attr.shareability())
         }
-        flags
     }
 }
 
 impl From<MemFlags> for DescriptorAttr {
     fn from(flags: MemFlags) -> Self {
-        let mut attr = if flags.contains(MemFlags::DEVICE) {
-            Self::from_mem_type(MemType::Device)
-        } else {
-            Self::from_mem_type(MemType::Normal)
-        };
+        let mut attr = Self::from_mem_type(flags.mem_type(), flags.shareability(), s2fwb_enabled());
         if flags.contains(MemFlags::READ) {
             attr |= Self::VALID;
             attr |= Self::S2AP_R;
@@ -181,3 +266,360 @@ impl fmt::Debug for PageTableEntry {
 }
 
 pub type ExtendedPageTable<H> = Level4PageTable<H, PageTableEntry>;
+
+impl<H: RvmHal> ExtendedPageTable<H> {
+    /// Create a stage-2 page table with the geometry described by `config`.
+    pub fn new_stage2(config: &Stage2Config) -> RvmResult<Self> {
+        Self::new_with_geometry(config.ipa_bits, config.start_level)
+    }
+}
+
+/// The geometry of stage-2 translation: the IPA size, the output PA size and
+/// the level at which the table walk starts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Stage2Config {
+    ipa_bits: usize,
+    pa_bits: usize,
+    start_level: usize,
+    vmid_bits: usize,
+}
+
+impl Stage2Config {
+    /// Smallest supported IPA size.
+    pub const MIN_IPA_BITS: usize = 32;
+    /// Largest supported IPA size, 52-bit addresses need FEAT_LPA.
+    pub const MAX_IPA_BITS: usize = 48;
+
+    /// Create a configuration for `ipa_bits` bits of guest physical address,
+    /// limited by the physical address size reported in ID_AA64MMFR0_EL1.
+    pub fn new(ipa_bits: usize) -> RvmResult<Self> {
+        let pa_bits = match ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange) {
+            0b0000 => 32,
+            0b0001 => 36,
+            0b0010 => 40,
+            0b0011 => 42,
+            0b0100 => 44,
+            _ => 48,
+        };
+        let mut config = Self::with_pa_bits(ipa_bits, pa_bits)?;
+        config.vmid_bits = super::vmid_bits();
+        Ok(config)
+    }
+
+    /// Create a configuration for `ipa_bits` bits of guest physical address
+    /// on a host with `pa_bits` bits of physical address and 8-bit VMIDs.
+    pub fn with_pa_bits(ipa_bits: usize, pa_bits: usize) -> RvmResult<Self> {
+        let pa_bits = pa_bits.min(Self::MAX_IPA_BITS);
+        let ipa_bits = ipa_bits.min(pa_bits);
+        if ipa_bits < Self::MIN_IPA_BITS {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("unsupported stage-2 IPA size: {} bits", ipa_bits)
+            );
+        }
+        // Each level resolves 9 bits, and up to 16 tables (4 bits) can be
+        // concatenated at the starting level.
+        let levels = (ipa_bits - 12 - 4 + 8) / 9;
+        Ok(Self {
+            ipa_bits,
+            pa_bits,
+            start_level: 4 - levels,
+            vmid_bits: 8,
+        })
+    }
+
+    /// Number of guest physical address bits.
+    pub fn ipa_bits(&self) -> usize {
+        self.ipa_bits
+    }
+
+    /// Number of output physical address bits.
+    pub fn pa_bits(&self) -> usize {
+        self.pa_bits
+    }
+
+    /// The level at which the stage-2 table walk starts.
+    pub fn start_level(&self) -> usize {
+        self.start_level
+    }
+
+    /// The VTCR_EL2 value for this configuration.
+    pub fn vtcr(&self) -> u64 {
+        let ps = match self.pa_bits {
+            32 => VTCR_EL2::PS::PA_32B_4GB,
+            36 => VTCR_EL2::PS::PA_36B_64GB,
+            40 => VTCR_EL2::PS::PA_40B_1TB,
+            42 => VTCR_EL2::PS::PA_42B_4TB,
+            44 => VTCR_EL2::PS::PA_44B_16TB,
+            _ => VTCR_EL2::PS::PA_48B_256TB,
+        };
+        // For the 4K granule, SL0 encodes the starting level as 2 - level.
+        let flags = VTCR_EL2::TG0::Granule4KB
+            + VTCR_EL2::SH0::Inner
+            + VTCR_EL2::SL0.val(2 - self.start_level as u64)
+            + VTCR_EL2::ORGN0::NormalWBRAWA
+            + VTCR_EL2::IRGN0::NormalWBRAWA
+            + VTCR_EL2::T0SZ.val(64 - self.ipa_bits as u64);
+        let vs = if self.vmid_bits == 16 {
+            VTCR_EL2::VS::Bits16
+        } else {
+            VTCR_EL2::VS::Bits8
+        };
+        (ps + vs + flags).value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::mm::{HeapHal, PageSize};
+
+    type Npt = ExtendedPageTable<HeapHal>;
+
+    fn rw() -> MemFlags {
+        MemFlags::READ | MemFlags::WRITE
+    }
+
+    fn new_npt() -> Npt {
+        Npt::new_stage2(&Stage2Config::with_pa_bits(40, 40).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_attr_round_trip() {
+        for flags in [
+            MemFlags::READ,
+            rw(),
+            MemFlags::READ | MemFlags::EXECUTE,
+            rw() | MemFlags::EXECUTE,
+            rw() | MemFlags::DEVICE,
+            MemFlags::READ | MemFlags::DEVICE,
+        ] {
+            assert_eq!(MemFlags::from(DescriptorAttr::from(flags)), flags);
+            for is_huge in [false, true] {
+                let pte = PageTableEntry::new_page(0x8020_0000, flags, is_huge);
+                assert_eq!(pte.flags(), flags);
+                assert_eq!(pte.paddr(), 0x8020_0000);
+                assert!(pte.is_present());
+                assert_eq!(pte.is_huge(), is_huge);
+            }
+        }
+        let attr = DescriptorAttr::from(rw());
+        assert!(attr.contains(DescriptorAttr::SHAREABLE | DescriptorAttr::INNER));
+        assert!(attr.contains(DescriptorAttr::XN_1));
+        let attr = DescriptorAttr::from(rw() | MemFlags::DEVICE);
+        assert!(!attr.intersects(DescriptorAttr::SHAREABLE | DescriptorAttr::INNER));
+    }
+
+    #[test]
+    fn test_mem_types() {
+        let types = [
+            (MemType::DeviceNgnRnE, 0b0000),
+            (MemType::DeviceNgnRE, 0b0001),
+            (MemType::DeviceGre, 0b0011),
+            (MemType::NormalNonCacheable, 0b0101),
+            (MemType::NormalWriteThrough, 0b1010),
+            (MemType::NormalWriteBack, 0b1111),
+        ];
+        for (mem_type, mem_attr) in types {
+            let flags = rw().with_mem_type(mem_type);
+            assert_eq!(flags.mem_type(), mem_type);
+            let attr = DescriptorAttr::from(flags);
+            assert_eq!((attr.bits() >> 2) & 0b1111, mem_attr);
+            assert_eq!(MemFlags::from(attr), flags);
+        }
+        assert_eq!(
+            rw().with_mem_type(MemType::DeviceNgnRnE),
+            rw() | MemFlags::DEVICE_NGNRNE
+        );
+        assert_eq!(
+            MemFlags::DEVICE_GRE.with_mem_type(MemType::NormalWriteBack),
+            MemFlags::empty()
+        );
+        // Unknown Normal encodings take the inner cacheability.
+        let attr = DescriptorAttr::from_bits_truncate(0b1101 << 2);
+        assert_eq!(attr.mem_type(false), MemType::NormalNonCacheable);
+
+        // Forced write-back of FEAT_S2FWB.
+        let fwb_types = [
+            (MemType::DeviceNgnRE, 0b0001),
+            (MemType::NormalNonCacheable, 0b0101),
+            (MemType::NormalWriteBack, 0b0110),
+        ];
+        for (mem_type, mem_attr) in fwb_types {
+            let attr = DescriptorAttr::from_mem_type(mem_type, Shareability::InnerShareable, true);
+            assert_eq!((attr.bits() >> 2) & 0b1111, mem_attr);
+            assert_eq!(attr.mem_type(true), mem_type);
+        }
+    }
+
+    #[test]
+    fn test_shareability() {
+        for sh in [
+            Shareability::NonShareable,
+            Shareability::OuterShareable,
+            Shareability::InnerShareable,
+        ] {
+            let flags = rw().with_shareability(sh);
+            assert_eq!(flags.shareability(), sh);
+            assert_eq!(MemFlags::from(DescriptorAttr::from(flags)), flags);
+        }
+        let attr = DescriptorAttr::from(rw() | MemFlags::OUTER_SHAREABLE);
+        assert!(attr.contains(DescriptorAttr::SHAREABLE));
+        assert!(!attr.contains(DescriptorAttr::INNER));
+        // Ignored for Device memory.
+        let flags = rw() | MemFlags::DEVICE | MemFlags::NON_SHAREABLE;
+        assert_eq!(
+            MemFlags::from(DescriptorAttr::from(flags)),
+            rw() | MemFlags::DEVICE
+        );
+    }
+
+    #[test]
+    fn test_map_unmap_query() {
+        let mut npt = new_npt();
+        assert_eq!(npt.start_level(), 1);
+        npt.map(0x4000_1000, 0x8000_5000, PageSize::Size4K, rw())
+            .unwrap();
+        assert_eq!(
+            npt.query(0x4000_1234).unwrap(),
+            (0x8000_5234, rw(), PageSize::Size4K)
+        );
+        assert!(npt.query(0x4000_2000).is_err());
+        npt.update(0x4000_1000, Some(0x8000_6000), Some(MemFlags::READ))
+            .unwrap();
+        assert_eq!(
+            npt.query(0x4000_1000).unwrap(),
+            (0x8000_6000, MemFlags::READ, PageSize::Size4K)
+        );
+        assert_eq!(
+            npt.unmap(0x4000_1000).unwrap(),
+            (0x8000_6000, PageSize::Size4K)
+        );
+        assert!(npt.query(0x4000_1000).is_err());
+        assert!(npt.unmap(0x4000_1000).is_err());
+    }
+
+    #[test]
+    fn test_map_errors() {
+        let mut npt = new_npt();
+        npt.map(0x4000_0000, 0x8000_0000, PageSize::Size4K, rw())
+            .unwrap();
+        // Already mapped.
+        assert!(npt
+            .map(0x4000_0000, 0x9000_0000, PageSize::Size4K, rw())
+            .is_err());
+        // Unaligned addresses.
+        assert!(npt
+            .map(0x4000_1001, 0x8000_1000, PageSize::Size4K, rw())
+            .is_err());
+        assert!(npt
+            .map(0x4010_0000, 0x8020_0000, PageSize::Size2M, rw())
+            .is_err());
+        // Out of the input address range.
+        assert!(npt
+            .map(1 << 40, 0x8000_0000, PageSize::Size4K, rw())
+            .is_err());
+        // A block over an existing table, and a page under an existing block.
+        assert!(npt
+            .map(0x4000_0000, 0x8000_0000, PageSize::Size2M, rw())
+            .is_err());
+        npt.map(0x8000_0000, 0x8000_0000, PageSize::Size1G, rw())
+            .unwrap();
+        assert!(npt
+            .map(0x8000_1000, 0x8000_1000, PageSize::Size4K, rw())
+            .is_err());
+        // 1G blocks need the walk to start at level 1 or below.
+        let mut npt = Npt::new_stage2(&Stage2Config::with_pa_bits(32, 32).unwrap()).unwrap();
+        assert_eq!(npt.start_level(), 2);
+        assert!(npt.map(0, 0, PageSize::Size1G, rw()).is_err());
+    }
+
+    #[test]
+    fn test_map_region_huge() {
+        let mut npt = new_npt();
+        // 4K head, 2M blocks up to the 1G boundary, a 1G block, then a 4K tail.
+        let (gpa, hpa) = (0x3fdf_f000, 0x7fdf_f000);
+        let size = 0x1000 + 0x20_0000 + 0x4000_0000 + 0x1000;
+        npt.map_region(gpa, hpa, size, rw(), true).unwrap();
+        let expected = [
+            (0x3fdf_f000, PageSize::Size4K),
+            (0x3fe0_0000, PageSize::Size2M),
+            (0x4000_0000, PageSize::Size1G),
+            (0x8000_0000, PageSize::Size4K),
+        ];
+        for (gpa, page_size) in expected {
+            let (paddr, flags, size) = npt.query(gpa + 8).unwrap();
+            assert_eq!(paddr, gpa + 0x4000_0000 + 8);
+            assert_eq!(flags, rw());
+            assert_eq!(size, page_size);
+        }
+        // Misaligned physical address: 4K pages only.
+        npt.map_region(0x1_0000_0000, 0x1000, 0x20_0000, rw(), true)
+            .unwrap();
+        assert_eq!(npt.query(0x1_0000_0000).unwrap().2, PageSize::Size4K);
+        assert_eq!(npt.query(0x1_001f_f000).unwrap().2, PageSize::Size4K);
+    }
+
+    #[test]
+    fn test_protect_splits_blocks() {
+        let mut npt = new_npt();
+        npt.map(0x4000_0000, 0x8000_0000, PageSize::Size1G, rw())
+            .unwrap();
+        let mut flushed = Vec::new();
+        npt.protect(0x4020_1000, 0x1000, MemFlags::READ, |ipa| flushed.push(ipa))
+            .unwrap();
+        // The 1G block, then the 2M block, are split.
+        assert_eq!(flushed, [0x4020_1000, 0x4020_1000]);
+        assert_eq!(
+            npt.query(0x4020_1000).unwrap(),
+            (0x8020_1000, MemFlags::READ, PageSize::Size4K)
+        );
+        assert_eq!(
+            npt.query(0x4020_2000).unwrap(),
+            (0x8020_2000, rw(), PageSize::Size4K)
+        );
+        assert_eq!(
+            npt.query(0x4040_0000).unwrap(),
+            (0x8040_0000, rw(), PageSize::Size2M)
+        );
+        assert!(npt.protect(0x8000_0000, 0x1000, rw(), |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_unmap_region_reclaims_tables() {
+        let mut npt = new_npt();
+        npt.map_region(0x4000_0000, 0x8000_0000, 0x40_0000, rw(), false)
+            .unwrap();
+        npt.map(0x4060_0000, 0x8060_0000, PageSize::Size2M, rw())
+            .unwrap();
+        // Partially covered blocks are rejected.
+        assert!(npt.unmap_region(0x4060_0000, 0x1000).is_err());
+        // The two level-3 tables become empty, the level-2 table still holds
+        // the block.
+        let freed = npt.unmap_region(0x4000_0000, 0x40_0000).unwrap();
+        assert_eq!(freed.len(), 2);
+        assert!(npt.query(0x4000_0000).is_err());
+        let freed = npt.unmap_region(0x4060_0000, 0x20_0000).unwrap();
+        assert_eq!(freed.len(), 1);
+    }
+
+    #[test]
+    fn test_unmap_region_errors() {
+        let mut npt = new_npt();
+        npt.map_region(0x401f_d000, 0x801f_d000, 0x3000, rw(), false)
+            .unwrap();
+        npt.map(0x4020_0000, 0x8020_0000, PageSize::Size2M, rw())
+            .unwrap();
+        // A hole, or a partially covered block, after mapped pages: nothing is
+        // unmapped.
+        assert!(npt.unmap_region(0x4020_0000, 0x20_1000).is_err());
+        assert!(npt.unmap_region(0x401f_d000, 0x4000).is_err());
+        for vaddr in [0x401f_d000, 0x401f_f000, 0x4020_0000] {
+            assert!(npt.query(vaddr).is_ok());
+        }
+        assert!(npt.unmap_region(0x401f_d000, 0x20_3000).is_ok());
+        assert!(npt.query(0x4020_0000).is_err());
+    }
+}
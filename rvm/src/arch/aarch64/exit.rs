@@ -0,0 +1,399 @@
+//! Decoding of VM exits from the exception syndrome (ESR_EL2).
+
+use crate::mm::{GuestPhysAddr, GuestVirtAddr};
+
+/// Exception classes (ESR_ELx.EC) that cause VM exits or are injected.
+pub(super) mod ec {
+    pub const UNKNOWN: u64 = 0x00;
+    pub const WFX: u64 = 0x01;
+    pub const FP: u64 = 0x07;
+    pub const HVC64: u64 = 0x16;
+    pub const SMC64: u64 = 0x17;
+    pub const SYS64: u64 = 0x18;
+    pub const IABT_LOWER: u64 = 0x20;
+    pub const IABT_CURRENT: u64 = 0x21;
+    pub const DABT_LOWER: u64 = 0x24;
+    pub const DABT_CURRENT: u64 = 0x25;
+}
+
+/// The syndrome of a load or store instruction, only available for data
+/// aborts caused by a single general-purpose register access (ISV = 1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessSyndrome {
+    /// Access size in bytes.
+    pub size: usize,
+    /// The transfer register, 31 means XZR/WZR.
+    pub reg: usize,
+    /// Whether the loaded value must be sign-extended.
+    pub sign_extend: bool,
+    /// Whether the transfer register is 64-bit (X) rather than 32-bit (W).
+    pub is_64bit: bool,
+}
+
+/// A stage-2 data abort, typically an MMIO access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAbortInfo {
+    /// The faulting guest physical address.
+    pub ipa: GuestPhysAddr,
+    /// The faulting guest virtual address.
+    pub gva: GuestVirtAddr,
+    /// Whether the access is a write.
+    pub is_write: bool,
+    /// Data fault status code.
+    pub fault_status: u8,
+    /// The decoded instruction, if the hardware provides it.
+    pub syndrome: Option<AccessSyndrome>,
+}
+
+impl DataAbortInfo {
+    /// Whether the abort is a permission fault (not a translation fault).
+    pub fn is_permission_fault(&self) -> bool {
+        self.fault_status & 0b11_1100 == 0b00_1100
+    }
+
+    /// Whether the abort is a translation fault, i.e. the address was not
+    /// mapped at stage 2 when accessed.
+    pub fn is_translation_fault(&self) -> bool {
+        is_translation_fault(self.fault_status)
+    }
+}
+
+/// Whether the data or instruction fault status code `fault_status` is a
+/// translation fault.
+pub fn is_translation_fault(fault_status: u8) -> bool {
+    fault_status & 0b11_1100 == 0b00_0100
+}
+
+/// Encoding of a system register, as in the operands of MSR and MRS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SysReg {
+    pub op0: u8,
+    pub op1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub op2: u8,
+}
+
+impl SysReg {
+    pub const SCTLR_EL1: Self = Self::new(3, 0, 1, 0, 0);
+    pub const ACTLR_EL1: Self = Self::new(3, 0, 1, 0, 1);
+    pub const TTBR0_EL1: Self = Self::new(3, 0, 2, 0, 0);
+    pub const TTBR1_EL1: Self = Self::new(3, 0, 2, 0, 1);
+    pub const TCR_EL1: Self = Self::new(3, 0, 2, 0, 2);
+    pub const AFSR0_EL1: Self = Self::new(3, 0, 5, 1, 0);
+    pub const AFSR1_EL1: Self = Self::new(3, 0, 5, 1, 1);
+    pub const ESR_EL1: Self = Self::new(3, 0, 5, 2, 0);
+    pub const FAR_EL1: Self = Self::new(3, 0, 6, 0, 0);
+    pub const MAIR_EL1: Self = Self::new(3, 0, 10, 2, 0);
+    pub const AMAIR_EL1: Self = Self::new(3, 0, 10, 3, 0);
+    pub const CONTEXTIDR_EL1: Self = Self::new(3, 0, 13, 0, 1);
+    /// GICv3 SGI generation, trapped while physical IRQs are routed to EL2.
+    pub const ICC_SGI1R_EL1: Self = Self::new(3, 0, 12, 11, 5);
+
+    pub const REVIDR_EL1: Self = Self::new(3, 0, 0, 0, 6);
+    pub const AIDR_EL1: Self = Self::new(3, 1, 0, 0, 7);
+    pub const CTR_EL0: Self = Self::new(3, 3, 0, 0, 1);
+    pub const CCSIDR_EL1: Self = Self::new(3, 1, 0, 0, 0);
+    pub const CLIDR_EL1: Self = Self::new(3, 1, 0, 0, 1);
+    pub const CSSELR_EL1: Self = Self::new(3, 2, 0, 0, 0);
+    pub const ID_AA64PFR0_EL1: Self = Self::new(3, 0, 0, 4, 0);
+
+    // Cache maintenance instructions, with the address or set/way in Xt.
+    pub const DC_IVAC: Self = Self::new(1, 0, 7, 6, 1);
+    pub const DC_ISW: Self = Self::new(1, 0, 7, 6, 2);
+    pub const DC_CSW: Self = Self::new(1, 0, 7, 10, 2);
+    pub const DC_CISW: Self = Self::new(1, 0, 7, 14, 2);
+    pub const DC_CVAC: Self = Self::new(1, 3, 7, 10, 1);
+    pub const DC_CVAU: Self = Self::new(1, 3, 7, 11, 1);
+    pub const DC_CVAP: Self = Self::new(1, 3, 7, 12, 1);
+    pub const DC_CVADP: Self = Self::new(1, 3, 7, 13, 1);
+    pub const DC_CIVAC: Self = Self::new(1, 3, 7, 14, 1);
+    pub const IC_IALLUIS: Self = Self::new(1, 0, 7, 1, 0);
+    pub const IC_IALLU: Self = Self::new(1, 0, 7, 5, 0);
+    pub const IC_IVAU: Self = Self::new(1, 3, 7, 5, 1);
+
+    pub const fn new(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        Self {
+            op0,
+            op1,
+            crn,
+            crm,
+            op2,
+        }
+    }
+
+    /// The encodings of the feature ID registers (ID_*_EL1 and ID_AA64*_EL1),
+    /// trapped by HCR_EL2.TID3. The unallocated ones read as zero.
+    pub fn feature_id_regs() -> impl Iterator<Item = Self> {
+        (1..8).flat_map(|crm| (0..8).map(move |op2| Self::new(3, 0, 0, crm, op2)))
+    }
+
+    /// The encodings of the TLB maintenance instructions executed at EL1,
+    /// trapped by HCR_EL2.TTLB, including the nXS variants.
+    pub fn tlbi_ops() -> impl Iterator<Item = Self> {
+        [8, 9].into_iter().flat_map(|crn| {
+            (0..16).flat_map(move |crm| (0..8).map(move |op2| Self::new(1, 0, crn, crm, op2)))
+        })
+    }
+
+    /// Whether this is one of [`Self::feature_id_regs`].
+    pub fn is_feature_id_reg(&self) -> bool {
+        self.op0 == 3 && self.op1 == 0 && self.crn == 0 && (1..8).contains(&self.crm)
+    }
+}
+
+/// A trapped MSR, MRS or system instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysRegAccess {
+    /// The accessed register.
+    pub reg: SysReg,
+    /// The transfer register, 31 means XZR.
+    pub rt: usize,
+    /// Whether the access is a read (MRS).
+    pub is_read: bool,
+}
+
+/// The reason of a VM exit, with the decoded syndrome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArmExitReason {
+    /// Trapped WFI instruction.
+    Wfi,
+    /// Trapped WFE instruction.
+    Wfe,
+    /// Trapped FP/SIMD access.
+    FpAccess,
+    /// HVC instruction with its immediate.
+    Hvc(u16),
+    /// Trapped SMC instruction with its immediate.
+    Smc(u16),
+    /// Trapped system register access.
+    SysReg(SysRegAccess),
+    /// Stage-2 instruction abort.
+    InstrAbort {
+        /// The faulting guest physical address.
+        ipa: GuestPhysAddr,
+        /// The faulting guest virtual address.
+        gva: GuestVirtAddr,
+        /// Instruction fault status code.
+        fault_status: u8,
+    },
+    /// Stage-2 data abort.
+    DataAbort(DataAbortInfo),
+    /// Any other exception class, with the raw ESR_EL2 value.
+    Unknown(u64),
+}
+
+impl ArmExitReason {
+    /// Decode the exit reason from the values of ESR_EL2, FAR_EL2 and HPFAR_EL2.
+    pub fn decode(esr: u64, far: u64, hpfar: u64) -> Self {
+        let iss = esr & 0x1ff_ffff;
+        let bits = |shift: u64, width: u64| (iss >> shift) & ((1 << width) - 1);
+        // HPFAR_EL2.FIPA holds bits [55:12] of the IPA, the page offset comes from FAR_EL2.
+        let ipa = (((hpfar & 0x0fff_ffff_ffff_fff0) << 8) | (far & 0xfff)) as GuestPhysAddr;
+        match esr >> 26 {
+            ec::WFX if bits(0, 2) == 0 => Self::Wfi,
+            ec::WFX => Self::Wfe,
+            ec::FP => Self::FpAccess,
+            ec::HVC64 => Self::Hvc(bits(0, 16) as u16),
+            ec::SMC64 => Self::Smc(bits(0, 16) as u16),
+            ec::SYS64 => Self::SysReg(SysRegAccess {
+                reg: SysReg {
+                    op0: bits(20, 2) as u8,
+                    op2: bits(17, 3) as u8,
+                    op1: bits(14, 3) as u8,
+                    crn: bits(10, 4) as u8,
+                    crm: bits(1, 4) as u8,
+                },
+                rt: bits(5, 5) as usize,
+                is_read: bits(0, 1) == 1,
+            }),
+            ec::IABT_LOWER | ec::IABT_CURRENT => Self::InstrAbort {
+                ipa,
+                gva: far as GuestVirtAddr,
+                fault_status: bits(0, 6) as u8,
+            },
+            ec::DABT_LOWER | ec::DABT_CURRENT => Self::DataAbort(DataAbortInfo {
+                ipa,
+                gva: far as GuestVirtAddr,
+                is_write: bits(6, 1) == 1,
+                fault_status: bits(0, 6) as u8,
+                syndrome: (bits(24, 1) == 1).then(|| AccessSyndrome {
+                    size: 1 << bits(22, 2),
+                    sign_extend: bits(21, 1) == 1,
+                    reg: bits(16, 5) as usize,
+                    is_64bit: bits(15, 1) == 1,
+                }),
+            }),
+            _ => Self::Unknown(esr),
+        }
+    }
+}
+
+pub struct ArmExitInfo {
+    pub exit_reason: ArmExitReason,
+    pub guest_pc: u64,
+}
+
+/// The result of running a vCPU until it exits to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// A synchronous exception taken from the guest.
+    Exception(ArmExitReason),
+    /// A physical IRQ routed to EL2 interrupted the guest. The interrupt is
+    /// still pending and must be handled by the host.
+    Irq,
+    /// A physical FIQ routed to EL2 interrupted the guest.
+    Fiq,
+    /// An SError taken from the guest, with the raw ESR_EL2 value.
+    SError(u64),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_wfx() {
+        assert_eq!(ArmExitReason::decode(0x0600_0000, 0, 0), ArmExitReason::Wfi);
+        assert_eq!(ArmExitReason::decode(0x0600_0001, 0, 0), ArmExitReason::Wfe);
+    }
+
+    #[test]
+    fn test_hvc_smc() {
+        assert_eq!(
+            ArmExitReason::decode(0x5a00_0000, 0, 0),
+            ArmExitReason::Hvc(0)
+        );
+        assert_eq!(
+            ArmExitReason::decode(0x5a00_1234, 0, 0),
+            ArmExitReason::Hvc(0x1234)
+        );
+        assert_eq!(
+            ArmExitReason::decode(0x5e00_0000, 0, 0),
+            ArmExitReason::Smc(0)
+        );
+    }
+
+    #[test]
+    fn test_fp() {
+        assert_eq!(
+            ArmExitReason::decode(0x1fe0_0000, 0, 0),
+            ArmExitReason::FpAccess
+        );
+    }
+
+    #[test]
+    fn test_sysreg() {
+        // mrs x2, ctr_el0
+        assert_eq!(
+            ArmExitReason::decode(0x6232_c041, 0, 0),
+            ArmExitReason::SysReg(SysRegAccess {
+                reg: SysReg::new(3, 3, 0, 0, 1),
+                rt: 2,
+                is_read: true,
+            })
+        );
+        // msr cntp_ctl_el0, x0
+        assert_eq!(
+            ArmExitReason::decode(0x6232_f804, 0, 0),
+            ArmExitReason::SysReg(SysRegAccess {
+                reg: SysReg::new(3, 3, 14, 2, 1),
+                rt: 0,
+                is_read: false,
+            })
+        );
+        // dc cisw, x3
+        assert_eq!(
+            ArmExitReason::decode(0x6214_1c7c, 0, 0),
+            ArmExitReason::SysReg(SysRegAccess {
+                reg: SysReg::DC_CISW,
+                rt: 3,
+                is_read: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sysreg_groups() {
+        let ids: Vec<_> = SysReg::feature_id_regs().collect();
+        assert_eq!(ids.len(), 56);
+        assert!(ids.contains(&SysReg::ID_AA64PFR0_EL1));
+        assert!(ids.iter().all(SysReg::is_feature_id_reg));
+        assert!(!SysReg::CTR_EL0.is_feature_id_reg());
+        assert!(!SysReg::new(3, 0, 0, 0, 5).is_feature_id_reg());
+
+        let tlbis: Vec<_> = SysReg::tlbi_ops().collect();
+        // tlbi vmalle1is, tlbi vae1, tlbi vae1isnxs
+        assert!(tlbis.contains(&SysReg::new(1, 0, 8, 3, 0)));
+        assert!(tlbis.contains(&SysReg::new(1, 0, 8, 7, 1)));
+        assert!(tlbis.contains(&SysReg::new(1, 0, 9, 3, 1)));
+        assert!(!tlbis.contains(&SysReg::DC_CISW));
+    }
+
+    #[test]
+    fn test_data_abort() {
+        // str w1, [x0] to 0x0900_0018, translation fault at level 3
+        assert_eq!(
+            ArmExitReason::decode(0x9381_0047, 0xffff_0000_0900_0018, 0x0009_0000),
+            ArmExitReason::DataAbort(DataAbortInfo {
+                ipa: 0x0900_0018,
+                gva: 0xffff_0000_0900_0018,
+                is_write: true,
+                fault_status: 0x07,
+                syndrome: Some(AccessSyndrome {
+                    size: 4,
+                    reg: 1,
+                    sign_extend: false,
+                    is_64bit: false,
+                }),
+            })
+        );
+        // ldrsh x3, [x2], permission fault at level 2
+        let reason = ArmExitReason::decode(0x9363_800e, 0x4020_1002, 0x0040_2010);
+        let ArmExitReason::DataAbort(info) = reason else {
+            panic!("unexpected exit reason {:?}", reason);
+        };
+        assert_eq!(info.ipa, 0x4020_1002);
+        assert!(!info.is_write);
+        assert!(info.is_permission_fault());
+        assert!(!info.is_translation_fault());
+        assert_eq!(
+            info.syndrome,
+            Some(AccessSyndrome {
+                size: 2,
+                reg: 3,
+                sign_extend: true,
+                is_64bit: true,
+            })
+        );
+        // No valid instruction syndrome (e.g. ldp)
+        let reason = ArmExitReason::decode(0x9200_0007, 0x1000, 0x10);
+        let ArmExitReason::DataAbort(info) = reason else {
+            panic!("unexpected exit reason {:?}", reason);
+        };
+        assert_eq!(info.ipa, 0x1000);
+        assert!(info.is_translation_fault());
+        assert_eq!(info.syndrome, None);
+    }
+
+    #[test]
+    fn test_instr_abort() {
+        assert_eq!(
+            ArmExitReason::decode(0x8200_0007, 0x8000_0004, 0x0080_0000),
+            ArmExitReason::InstrAbort {
+                ipa: 0x8000_0004,
+                gva: 0x8000_0004,
+                fault_status: 0x07,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(
+            ArmExitReason::decode(0x5600_0000, 0, 0),
+            ArmExitReason::Unknown(0x5600_0000)
+        );
+    }
+}
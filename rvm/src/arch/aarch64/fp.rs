@@ -0,0 +1,138 @@
+//! Lazy FP/SIMD register switching between vCPUs.
+//!
+//! The hypervisor itself never uses the FP/SIMD registers, so they keep the
+//! state of the last vCPU that used them on each physical CPU (its owner).
+//! Other vCPUs run with FP/SIMD accesses trapped (CPTR_EL2.TFP), and the
+//! registers are only restored when a vCPU traps on its first access.
+//!
+//! The state of the owner stays in the registers when its vCPU returns to the
+//! host, and is only saved to the vCPU when it is unloaded, before the host
+//! runs another vCPU on the CPU or moves it to another physical CPU. Owners
+//! are recorded by ID, so vCPUs can be moved in memory freely.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aarch64_cpu::registers::MPIDR_EL1;
+use tock_registers::interfaces::Readable;
+
+/// Maximum number of physical CPUs whose FP/SIMD owner is tracked. The
+/// registers are restored on every entry on other CPUs.
+const MAX_CPUS: usize = 64;
+
+/// No vCPU has ID 0.
+const NO_OWNER: u64 = 0;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: AtomicU64 = AtomicU64::new(NO_OWNER);
+/// The ID of the vCPU whose state is in the FP/SIMD registers of each physical
+/// CPU.
+static FP_OWNER: [AtomicU64; MAX_CPUS] = [FREE; MAX_CPUS];
+static NEXT_ID: AtomicU64 = AtomicU64::new(NO_OWNER + 1);
+
+/// The FP/SIMD registers of a vCPU.
+#[repr(C, align(16))]
+#[derive(Debug, Default, Clone)]
+pub struct FpState {
+    pub v: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
+
+impl FpState {
+    /// Save the registers from hardware, FP/SIMD accesses must not be trapped.
+    #[inline]
+    pub(crate) unsafe fn save(&mut self) {
+        core::arch::asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "stp q0, q1, [{0}, #0 * 32]",
+            "stp q2, q3, [{0}, #1 * 32]",
+            "stp q4, q5, [{0}, #2 * 32]",
+            "stp q6, q7, [{0}, #3 * 32]",
+            "stp q8, q9, [{0}, #4 * 32]",
+            "stp q10, q11, [{0}, #5 * 32]",
+            "stp q12, q13, [{0}, #6 * 32]",
+            "stp q14, q15, [{0}, #7 * 32]",
+            "stp q16, q17, [{0}, #8 * 32]",
+            "stp q18, q19, [{0}, #9 * 32]",
+            "stp q20, q21, [{0}, #10 * 32]",
+            "stp q22, q23, [{0}, #11 * 32]",
+            "stp q24, q25, [{0}, #12 * 32]",
+            "stp q26, q27, [{0}, #13 * 32]",
+            "stp q28, q29, [{0}, #14 * 32]",
+            "stp q30, q31, [{0}, #15 * 32]",
+            "mrs {1}, fpcr",
+            "mrs {2}, fpsr",
+            in(reg) self.v.as_mut_ptr(),
+            out(reg) self.fpcr,
+            out(reg) self.fpsr,
+        );
+    }
+
+    /// Restore the registers to hardware, FP/SIMD accesses must not be trapped.
+    #[inline]
+    unsafe fn restore(&self) {
+        core::arch::asm!(
+            ".arch_extension fp",
+            ".arch_extension simd",
+            "ldp q0, q1, [{0}, #0 * 32]",
+            "ldp q2, q3, [{0}, #1 * 32]",
+            "ldp q4, q5, [{0}, #2 * 32]",
+            "ldp q6, q7, [{0}, #3 * 32]",
+            "ldp q8, q9, [{0}, #4 * 32]",
+            "ldp q10, q11, [{0}, #5 * 32]",
+            "ldp q12, q13, [{0}, #6 * 32]",
+            "ldp q14, q15, [{0}, #7 * 32]",
+            "ldp q16, q17, [{0}, #8 * 32]",
+            "ldp q18, q19, [{0}, #9 * 32]",
+            "ldp q20, q21, [{0}, #10 * 32]",
+            "ldp q22, q23, [{0}, #11 * 32]",
+            "ldp q24, q25, [{0}, #12 * 32]",
+            "ldp q26, q27, [{0}, #13 * 32]",
+            "ldp q28, q29, [{0}, #14 * 32]",
+            "ldp q30, q31, [{0}, #15 * 32]",
+            "msr fpcr, {1}",
+            "msr fpsr, {2}",
+            in(reg) self.v.as_ptr(),
+            in(reg) self.fpcr,
+            in(reg) self.fpsr,
+        );
+    }
+}
+
+/// Allocate a new owner ID for an FP/SIMD state, which is never loaded yet.
+pub fn alloc_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The index of the current physical CPU, from the Aff0 field of MPIDR_EL1.
+pub fn current_cpu() -> usize {
+    (MPIDR_EL1.get() & 0xff) as usize
+}
+
+/// Whether the FP/SIMD registers of the current CPU hold the state of the
+/// owner `id`, which was last loaded on the CPU `loaded_on`.
+pub fn is_loaded(id: u64, loaded_on: usize) -> bool {
+    let cpu = current_cpu();
+    cpu == loaded_on
+        && FP_OWNER
+            .get(cpu)
+            .map_or(false, |o| o.load(Ordering::Acquire) == id)
+}
+
+/// Make `state` of the owner `id` live in the FP/SIMD registers of the current
+/// CPU, and return the CPU. The previous owner must have been unloaded, its
+/// state is overwritten.
+///
+/// # Safety
+///
+/// FP/SIMD accesses must not be trapped.
+#[inline]
+pub unsafe fn load(id: u64, state: &FpState) -> usize {
+    let cpu = current_cpu();
+    if let Some(owner) = FP_OWNER.get(cpu) {
+        owner.store(id, Ordering::Release);
+    }
+    state.restore();
+    cpu
+}
@@ -3,6 +3,9 @@ use aarch64_cpu::registers::*;
 use core::arch::asm;
 use tock_registers::interfaces::*;
 
+use super::SysReg;
+use crate::mm::PAGE_SIZE;
+
 #[inline]
 pub fn enable_irqs() {
     unsafe { asm!("msr daifclr, #2") };
@@ -52,11 +55,142 @@ pub fn flush_tlb_all() {
     unsafe { asm!("tlbi vmalls12e1is; dsb sy; isb") };
 }
 
+/// Above this number of pages, a ranged invalidation flushes the whole VMID.
+const MAX_TLBI_PAGES: usize = 512;
+
+/// Run `f` with VTTBR_EL2.VMID set to `vmid`, since stage-2 TLB maintenance
+/// applies to the current VMID.
+#[inline]
+fn with_vmid(vmid: u16, f: impl FnOnce()) {
+    let old = VTTBR_EL2.get();
+    let switch = VTTBR_EL2.read(VTTBR_EL2::VMID) != vmid as u64;
+    if switch {
+        VTTBR_EL2.modify(VTTBR_EL2::VMID.val(vmid as u64));
+        unsafe { asm!("isb") };
+    }
+    f();
+    if switch {
+        VTTBR_EL2.set(old);
+        unsafe { asm!("isb") };
+    }
+}
+
+/// Make page table updates visible to the table walker, then invalidate all
+/// stage-1 and stage-2 entries of `vmid` in the inner shareable domain.
+#[inline]
+pub fn flush_guest_tlb_all(vmid: u16) {
+    with_vmid(vmid, || unsafe {
+        asm!("dsb ishst; tlbi vmalls12e1is; dsb ish; isb")
+    });
+}
+
+/// Make page table updates visible to the table walker, then invalidate the
+/// stage-2 entries of `vmid` for the IPA range `[ipa, ipa + size)`.
+///
+/// Stage-1 entries are invalidated for the whole VMID as they are tagged by
+/// guest VA, not by IPA.
+#[inline]
+pub fn flush_guest_tlb_range(vmid: u16, ipa: usize, size: usize) {
+    let start = ipa & !(PAGE_SIZE - 1);
+    let end = (ipa + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    if (end - start) / PAGE_SIZE > MAX_TLBI_PAGES {
+        return flush_guest_tlb_all(vmid);
+    }
+    with_vmid(vmid, || unsafe {
+        asm!("dsb ishst");
+        for page in (start..end).step_by(PAGE_SIZE) {
+            asm!("tlbi ipas2e1is, {}", in(reg) page >> 12);
+        }
+        asm!("dsb ish; tlbi vmalle1is; dsb ish; isb");
+    });
+}
+
+/// Trap (or stop trapping) FP/SIMD accesses at EL0/1/2 with CPTR_EL2.TFP.
+#[inline]
+pub fn set_fp_trap(trap: bool) {
+    const CPTR_EL2_TFP: u64 = 1 << 10;
+    unsafe {
+        let mut cptr: u64;
+        asm!("mrs {}, cptr_el2", out(reg) cptr);
+        if trap {
+            cptr |= CPTR_EL2_TFP;
+        } else {
+            cptr &= !CPTR_EL2_TFP;
+        }
+        asm!("msr cptr_el2, {}; isb", in(reg) cptr);
+    }
+}
+
 #[inline]
 pub fn flush_icache_all() {
     unsafe { asm!("ic iallu; dsb sy; isb") };
 }
 
+/// Invalidate all instruction caches of the inner shareable domain to the
+/// point of unification.
+#[inline]
+pub fn flush_icache_all_is() {
+    unsafe { asm!("ic ialluis; dsb ish; isb") };
+}
+
+/// Clean and invalidate the data cache line of `vaddr` to the point of
+/// coherency.
+#[inline]
+pub fn clean_invalidate_dcache_line(vaddr: usize) {
+    unsafe { asm!("dc civac, {}; dsb ish", in(reg) vaddr) };
+}
+
+/// Invalidate the stage-1 TLB entries of the current VMID, i.e. of the vCPU
+/// last run on this CPU, in the inner shareable domain.
+#[inline]
+pub fn flush_guest_stage1_tlb() {
+    unsafe { asm!("dsb ishst; tlbi vmalle1is; dsb ish; isb") };
+}
+
+/// Read the hardware value of an ID register: one of
+/// [`SysReg::feature_id_regs`], REVIDR_EL1, AIDR_EL1, CTR_EL0, CLIDR_EL1 or
+/// CCSIDR_EL1, which is read for the cache selected by `csselr`.
+#[inline]
+pub fn read_id_reg(reg: SysReg, csselr: u64) -> Option<u64> {
+    macro_rules! mrs {
+        ($name:expr) => {{
+            let val: u64;
+            unsafe { asm!(concat!("mrs {}, ", $name), out(reg) val) };
+            Some(val)
+        }};
+    }
+    macro_rules! feature_id_regs {
+        ($($crm:literal: [$($op2:literal),*]),*) => {
+            match (reg.crm, reg.op2) {
+                $($(($crm, $op2) => mrs!(concat!("s3_0_c0_c", $crm, "_", $op2)),)*)*
+                _ => None,
+            }
+        };
+    }
+    if reg.is_feature_id_reg() {
+        return feature_id_regs!(
+            1: [0, 1, 2, 3, 4, 5, 6, 7],
+            2: [0, 1, 2, 3, 4, 5, 6, 7],
+            3: [0, 1, 2, 3, 4, 5, 6, 7],
+            4: [0, 1, 2, 3, 4, 5, 6, 7],
+            5: [0, 1, 2, 3, 4, 5, 6, 7],
+            6: [0, 1, 2, 3, 4, 5, 6, 7],
+            7: [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+    }
+    match reg {
+        SysReg::REVIDR_EL1 => mrs!("revidr_el1"),
+        SysReg::AIDR_EL1 => mrs!("aidr_el1"),
+        SysReg::CTR_EL0 => mrs!("ctr_el0"),
+        SysReg::CLIDR_EL1 => mrs!("clidr_el1"),
+        SysReg::CCSIDR_EL1 => {
+            unsafe { asm!("msr csselr_el1, {}; isb", in(reg) csselr) };
+            mrs!("ccsidr_el1")
+        }
+        _ => None,
+    }
+}
+
 #[inline]
 pub fn wait_for_ints() {
     aarch64_cpu::asm::wfi();
@@ -2,24 +2,81 @@
 pub mod regs;
 
 mod ept;
+mod exit;
+mod fp;
 mod instructions;
 mod s1pt;
+mod s1walk;
+mod s2walk;
+mod timer;
 mod vcpu;
+mod vgic;
+mod vmid;
 
 use core::marker::PhantomData;
 
-pub use self::ept::ExtendedPageTable as NestedPageTable;
+pub use self::ept::{enable_s2fwb, has_s2fwb, ExtendedPageTable as NestedPageTable, Stage2Config};
 pub use self::s1pt::{PageTable, Stage1PTE};
+pub use self::s1walk::{translate_gva, GuestMemoryAccess, GuestStage1Context, GuestTranslation};
+pub use self::s2walk::{translate_ipa, Stage2Translation};
 pub use self::ArmPerCpuState as ArchPerCpuState;
 use crate::{RvmHal, RvmResult};
 use aarch64_cpu::registers::HCR_EL2;
 use tock_registers::interfaces::{Writeable, ReadWriteable, Readable};
+pub use fp::FpState;
+pub use timer::{TimerContext, VmCounter, PTIMER_IRQ, VTIMER_IRQ};
+pub use regs::{GeneralRegisters, SystemRegisters};
 pub use vcpu::ArmVcpu as RvmVcpu;
-pub use vcpu::{ArmExitInfo, ArmExitReason};
+pub use vgic::{choose_list_register, ListRegister, LrChoice};
+pub use exit::{
+    is_translation_fault, AccessSyndrome, ArmExitInfo, ArmExitReason, DataAbortInfo, SysReg,
+    SysRegAccess, VmExit,
+};
+pub use vmid::{vmid_bits, Vmid};
+pub use self::instructions::{
+    clean_invalidate_dcache_line, flush_guest_stage1_tlb, flush_guest_tlb_all,
+    flush_guest_tlb_range, flush_icache_all_is, read_id_reg,
+};
 
 pub fn has_hardware_support() -> bool {
     true
 }
+
+bitflags::bitflags! {
+    /// Guest operations trapped to EL2, as their HCR_EL2 trap controls.
+    pub struct GuestTraps: u64 {
+        /// WFI instructions.
+        const TWI   = 1 << 13;
+        /// WFE instructions.
+        const TWE   = 1 << 14;
+        /// Reads of the group 0 ID registers (AArch32 only).
+        const TID0  = 1 << 15;
+        /// Reads of REVIDR_EL1, AIDR_EL1 and SMIDR_EL1.
+        const TID1  = 1 << 16;
+        /// Accesses to CTR_EL0, CCSIDR_EL1, CLIDR_EL1 and CSSELR_EL1.
+        const TID2  = 1 << 17;
+        /// Reads of the group 3 ID registers, e.g. ID_AA64*_EL1.
+        const TID3  = 1 << 18;
+        /// SMC instructions.
+        const TSC   = 1 << 19;
+        /// Accesses to the implementation defined system registers.
+        const TIDCP = 1 << 20;
+        /// Accesses to ACTLR_EL1.
+        const TACR  = 1 << 21;
+        /// Data cache maintenance by set/way.
+        const TSW   = 1 << 22;
+        /// Data cache maintenance to the point of coherency.
+        const TPCP  = 1 << 23;
+        /// Cache maintenance to the point of unification.
+        const TPU   = 1 << 24;
+        /// TLB maintenance instructions.
+        const TTLB  = 1 << 25;
+        /// Writes of the virtual memory control registers, e.g. SCTLR_EL1.
+        const TVM   = 1 << 26;
+        /// Reads of the virtual memory control registers.
+        const TRVM  = 1 << 30;
+    }
+}
 pub struct ArmPerCpuState<H: RvmHal> {
     _phantom_data: PhantomData<H>,
 }
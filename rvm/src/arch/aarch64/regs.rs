@@ -58,3 +58,84 @@ macro_rules! restore_regs_from_stack {
         add     sp, sp, 34 * 8"
     };
 }
+
+macro_rules! define_sys_regs {
+    ($($(#[$doc:meta])* $reg:ident,)*) => {
+        /// The EL0/EL1 system registers of a guest, and the EL2 registers that
+        /// virtualize its identity. They are live in hardware only while the
+        /// vCPU is loaded on a physical CPU.
+        #[repr(C)]
+        #[derive(Debug, Default, Clone)]
+        pub struct SystemRegisters {
+            $($(#[$doc])* pub $reg: u64,)*
+        }
+
+        impl SystemRegisters {
+            /// Save the registers from hardware.
+            #[inline]
+            pub fn save(&mut self) {
+                unsafe {
+                    $(core::arch::asm!(
+                        concat!("mrs {}, ", stringify!($reg)),
+                        out(reg) self.$reg,
+                    );)*
+                }
+            }
+
+            /// Restore the registers to hardware.
+            #[inline]
+            pub fn restore(&self) {
+                unsafe {
+                    $(core::arch::asm!(
+                        concat!("msr ", stringify!($reg), ", {}"),
+                        in(reg) self.$reg,
+                    );)*
+                    core::arch::asm!("isb");
+                }
+            }
+        }
+    };
+}
+
+define_sys_regs! {
+    sp_el0,
+    elr_el1,
+    spsr_el1,
+    sctlr_el1,
+    actlr_el1,
+    cpacr_el1,
+    ttbr0_el1,
+    ttbr1_el1,
+    tcr_el1,
+    mair_el1,
+    amair_el1,
+    vbar_el1,
+    esr_el1,
+    far_el1,
+    par_el1,
+    afsr0_el1,
+    afsr1_el1,
+    contextidr_el1,
+    tpidr_el0,
+    tpidrro_el0,
+    tpidr_el1,
+    cntkctl_el1,
+    csselr_el1,
+    mdscr_el1,
+    /// The MPIDR_EL1 value read by the guest.
+    vmpidr_el2,
+}
+
+impl SystemRegisters {
+    /// Architectural reset value of SCTLR_EL1 (RES1 bits set, MMU and caches off).
+    const SCTLR_EL1_RESET: u64 = 0x30d0_0800;
+
+    /// Registers of a vCPU just out of reset, with the affinity `mpidr`.
+    pub fn reset(mpidr: u64) -> Self {
+        Self {
+            sctlr_el1: Self::SCTLR_EL1_RESET,
+            vmpidr_el2: mpidr | 1 << 31,
+            ..Default::default()
+        }
+    }
+}
@@ -60,6 +60,7 @@ enum MemType {
 }
 
 impl DescriptorAttr {
+    #[allow(clippy::unusual_byte_groupings)]
     const ATTR_INDEX_MASK: u64 = 0b111_00;
 
     const fn from_mem_type(mem_type: MemType) -> Self {
@@ -94,7 +95,7 @@ impl From<DescriptorAttr> for MemFlags {
             if !attr.contains(DescriptorAttr::UXN) {
                 flags |= Self::EXECUTE;
             }
-        } else if !attr.intersects(DescriptorAttr::PXN) {
+        } else if !attr.intersects(DescriptorAttr::PXN | DescriptorAttr::UXN) {
             flags |= Self::EXECUTE;
         }
         if attr.mem_type() == MemType::Device {
@@ -119,15 +120,10 @@ impl From<MemFlags> for DescriptorAttr {
         }
         if flags.contains(MemFlags::USER) {
             attr |= Self::AP_EL0;
-            //  | Self::PXN;
-            // if !flags.contains(MemFlags::EXECUTE) {
-            //     attr |= Self::UXN;
-            // }
-        } else {
-            // attr |= Self::UXN;
-            // if !flags.contains(MemFlags::EXECUTE) {
-            //     attr |= Self::PXN;
-            // }
+        }
+        // Bit 54 is UXN in the EL1&0 translation regime, and XN in the EL2 one.
+        if !flags.contains(MemFlags::EXECUTE) {
+            attr |= Self::UXN;
         }
         attr
     }
@@ -0,0 +1,359 @@
+//! Software walker of the guest stage-1 (EL1&0) translation tables.
+
+use super::regs::SystemRegisters;
+use crate::mm::{GuestPhysAddr, GuestVirtAddr, MemFlags};
+use crate::RvmResult;
+
+/// Read access to the guest physical memory, used to fetch the guest page
+/// table descriptors.
+pub trait GuestMemoryAccess {
+    /// Read the 64-bit little-endian value at the 8-byte aligned `gpa`.
+    fn read_u64(&self, gpa: GuestPhysAddr) -> RvmResult<u64>;
+}
+
+/// Guest system registers controlling stage-1 translation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestStage1Context {
+    pub sctlr: u64,
+    pub tcr: u64,
+    pub ttbr0: u64,
+    pub ttbr1: u64,
+}
+
+impl GuestStage1Context {
+    /// The registers saved in the system registers `regs` of a vCPU.
+    pub fn from_sys_regs(regs: &SystemRegisters) -> Self {
+        Self {
+            sctlr: regs.sctlr_el1,
+            tcr: regs.tcr_el1,
+            ttbr0: regs.ttbr0_el1,
+            ttbr1: regs.ttbr1_el1,
+        }
+    }
+
+    fn mmu_enabled(&self) -> bool {
+        self.sctlr & 1 != 0
+    }
+}
+
+/// The result of a guest stage-1 translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestTranslation {
+    /// The guest physical address that the virtual address translates to.
+    pub gpa: GuestPhysAddr,
+    /// Permissions of the mapping. `EXECUTE` means executable at EL1,
+    /// `USER` means accessible at EL0.
+    pub flags: MemFlags,
+    /// Size of the page or block that contains the address.
+    pub size: usize,
+}
+
+// Descriptor fields.
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1;
+const DESC_AP_EL0: u64 = 1 << 6;
+const DESC_AP_RO: u64 = 1 << 7;
+const DESC_PXN: u64 = 1 << 53;
+const DESC_PXN_TABLE: u64 = 1 << 59;
+const DESC_AP_NO_EL0_TABLE: u64 = 1 << 61;
+const DESC_AP_NO_WRITE_TABLE: u64 = 1 << 62;
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+// TCR_EL1 fields.
+const TCR_T0SZ_SHIFT: u64 = 0;
+const TCR_EPD0: u64 = 1 << 7;
+const TCR_TG0_SHIFT: u64 = 14;
+const TCR_T1SZ_SHIFT: u64 = 16;
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_TG1_SHIFT: u64 = 30;
+const TCR_TBI0: u64 = 1 << 37;
+const TCR_TBI1: u64 = 1 << 38;
+
+const TTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+
+/// Translate the guest virtual address `gva` with the guest stage-1 tables
+/// described by `ctx`, reading descriptors from `mem`.
+///
+/// Supports the 4K, 16K and 64K granules and block entries at any level the
+/// granule permits. Without the stage-1 MMU enabled, `gva` is returned as is.
+pub fn translate_gva<M: GuestMemoryAccess + ?Sized>(
+    mem: &M,
+    ctx: &GuestStage1Context,
+    gva: GuestVirtAddr,
+) -> RvmResult<GuestTranslation> {
+    let va = gva as u64;
+    if !ctx.mmu_enabled() {
+        return Ok(GuestTranslation {
+            gpa: gva,
+            flags: MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+            size: 1 << 12,
+        });
+    }
+
+    // Select the upper or lower VA range by bit 55, the top byte is only
+    // checked if it is not ignored.
+    let upper = va & (1 << 55) != 0;
+    let (tsz, tg, epd, tbi, ttbr) = if upper {
+        let tg = match (ctx.tcr >> TCR_TG1_SHIFT) & 0b11 {
+            0b01 => 14,
+            0b11 => 16,
+            _ => 12,
+        };
+        let tsz = (ctx.tcr >> TCR_T1SZ_SHIFT) & 0x3f;
+        (tsz, tg, TCR_EPD1, TCR_TBI1, ctx.ttbr1)
+    } else {
+        let tg = match (ctx.tcr >> TCR_TG0_SHIFT) & 0b11 {
+            0b01 => 16,
+            0b10 => 14,
+            _ => 12,
+        };
+        let tsz = (ctx.tcr >> TCR_T0SZ_SHIFT) & 0x3f;
+        (tsz, tg, TCR_EPD0, TCR_TBI0, ctx.ttbr0)
+    };
+    let ia_bits = 64 - tsz.clamp(16, 39);
+    let top_bits = if ctx.tcr & tbi != 0 { 56 } else { 64 };
+    let top = (va & (u64::MAX >> (64 - top_bits))) >> ia_bits;
+    let expected = if upper {
+        u64::MAX >> (64 - top_bits + ia_bits)
+    } else {
+        0
+    };
+    if ctx.tcr & epd != 0 || top != expected {
+        return rvm_err!(
+            InvalidParam,
+            format_args!("guest VA {:#x} is out of the translation range", gva)
+        );
+    }
+
+    // Each level resolves `tg - 3` bits, the starting level resolves the rest.
+    let stride = tg - 3;
+    let levels = (ia_bits - tg + stride - 1) / stride;
+    let mut level = 4 - levels;
+    let mut table = ttbr & TTBR_BADDR_MASK;
+    let mut flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::USER;
+    loop {
+        let shift = tg + stride * (3 - level);
+        let index_bits = (ia_bits - shift).min(stride);
+        let index = (va >> shift) & ((1 << index_bits) - 1);
+        let desc = mem.read_u64((table + index * 8) as GuestPhysAddr)?;
+        if desc & DESC_VALID == 0 {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("guest translation fault at level {} for {:#x}", level, gva)
+            );
+        }
+        let is_table = desc & DESC_TABLE != 0;
+        if level < 3 && is_table {
+            if desc & DESC_AP_NO_WRITE_TABLE != 0 {
+                flags -= MemFlags::WRITE;
+            }
+            if desc & DESC_AP_NO_EL0_TABLE != 0 {
+                flags -= MemFlags::USER;
+            }
+            if desc & DESC_PXN_TABLE != 0 {
+                flags -= MemFlags::EXECUTE;
+            }
+            table = desc & DESC_ADDR_MASK & !((1 << tg) - 1);
+            level += 1;
+            continue;
+        }
+        // A block is not allowed at level 0, nor at level 1 with a granule
+        // larger than 4K.
+        if (level == 3 && !is_table) || level == 0 || (level == 1 && tg != 12) {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("invalid guest descriptor {:#x} at level {}", desc, level)
+            );
+        }
+        if desc & DESC_AP_RO != 0 {
+            flags -= MemFlags::WRITE;
+        }
+        if desc & DESC_AP_EL0 == 0 {
+            flags -= MemFlags::USER;
+        }
+        if desc & DESC_PXN != 0 {
+            flags -= MemFlags::EXECUTE;
+        }
+        let size = 1u64 << shift;
+        let gpa = (desc & DESC_ADDR_MASK & !(size - 1)) | (va & (size - 1));
+        return Ok(GuestTranslation {
+            gpa: gpa as GuestPhysAddr,
+            flags,
+            size: size as usize,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    // TCR_EL1 granule encodings.
+    const TG0_16K: u64 = 0b10 << TCR_TG0_SHIFT;
+    const TG0_64K: u64 = 0b01 << TCR_TG0_SHIFT;
+    const TG1_64K: u64 = 0b11 << TCR_TG1_SHIFT;
+
+    const TABLE: u64 = DESC_VALID | DESC_TABLE;
+    const PAGE: u64 = DESC_VALID | DESC_TABLE;
+    const BLOCK: u64 = DESC_VALID;
+
+    /// Guest memory holding only the descriptors, other entries are invalid.
+    #[derive(Default)]
+    struct Memory(BTreeMap<GuestPhysAddr, u64>);
+
+    impl Memory {
+        fn set(&mut self, table: u64, index: u64, desc: u64) {
+            self.0.insert((table + index * 8) as GuestPhysAddr, desc);
+        }
+    }
+
+    impl GuestMemoryAccess for Memory {
+        fn read_u64(&self, gpa: GuestPhysAddr) -> RvmResult<u64> {
+            Ok(self.0.get(&gpa).copied().unwrap_or(0))
+        }
+    }
+
+    fn ctx(tcr: u64, ttbr0: u64, ttbr1: u64) -> GuestStage1Context {
+        GuestStage1Context {
+            sctlr: 1,
+            tcr,
+            ttbr0,
+            ttbr1,
+        }
+    }
+
+    fn rwx() -> MemFlags {
+        MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE
+    }
+
+    /// 4K granule and 48-bit VAs, from level 0: a page, a 2M and a 1G block.
+    fn mem_4k() -> Memory {
+        let mut mem = Memory::default();
+        mem.set(0x1000, 1, 0x2000 | TABLE);
+        mem.set(0x2000, 2, 0x3000 | TABLE);
+        mem.set(0x2000, 3, 0xc000_0000 | BLOCK | DESC_AP_RO);
+        mem.set(0x3000, 3, 0x4000 | TABLE);
+        mem.set(0x3000, 5, 0x4020_0000 | BLOCK);
+        mem.set(0x4000, 4, 0x8000_0000 | PAGE | DESC_AP_EL0);
+        mem
+    }
+
+    #[test]
+    fn test_granule_4k() {
+        let mem = mem_4k();
+        let ctx = ctx(16 << TCR_T0SZ_SHIFT, 0x1000, 0);
+        let va = 1 << 39 | 2 << 30;
+        let t = translate_gva(&mem, &ctx, va | 3 << 21 | 4 << 12 | 0x123).unwrap();
+        assert_eq!(t.gpa, 0x8000_0123);
+        assert_eq!(t.flags, rwx() | MemFlags::USER);
+        assert_eq!(t.size, 0x1000);
+
+        let t = translate_gva(&mem, &ctx, va | 5 << 21 | 0x1_2345).unwrap();
+        assert_eq!(t.gpa, 0x4021_2345);
+        assert_eq!(t.flags, rwx());
+        assert_eq!(t.size, 0x20_0000);
+
+        let t = translate_gva(&mem, &ctx, 1 << 39 | 3 << 30 | 0x1234_5678).unwrap();
+        assert_eq!(t.gpa, 0xd234_5678);
+        assert_eq!(t.flags, MemFlags::READ | MemFlags::EXECUTE);
+        assert_eq!(t.size, 0x4000_0000);
+
+        // Missing entries at levels 0 and 3, and VAs over 48 bits.
+        assert!(translate_gva(&mem, &ctx, 0x1000).is_err());
+        assert!(translate_gva(&mem, &ctx, va | 3 << 21 | 5 << 12).is_err());
+        assert!(translate_gva(&mem, &ctx, 1 << 48 | va).is_err());
+    }
+
+    #[test]
+    fn test_granule_16k() {
+        // 39-bit VAs, from level 1 which resolves 3 bits.
+        let mut mem = Memory::default();
+        mem.set(0x4000, 1, 0x8000 | TABLE);
+        mem.set(0x4000, 2, 0x1000_0000 | BLOCK);
+        mem.set(0x8000, 2, 0xc000 | TABLE);
+        mem.set(0x8000, 4, 0x2_0000_0000 | BLOCK);
+        mem.set(0xc000, 3, 0x1_0000_0000 | PAGE);
+        let ctx = ctx(25 << TCR_T0SZ_SHIFT | TG0_16K, 0x4000, 0);
+
+        let t = translate_gva(&mem, &ctx, 1 << 36 | 2 << 25 | 3 << 14 | 0x1234).unwrap();
+        assert_eq!(t.gpa, 0x1_0000_1234);
+        assert_eq!(t.size, 0x4000);
+        let t = translate_gva(&mem, &ctx, 1 << 36 | 4 << 25 | 0x1ab_cdef).unwrap();
+        assert_eq!(t.gpa, 0x2_01ab_cdef);
+        assert_eq!(t.size, 0x200_0000);
+        // No level 1 block with a 16K granule.
+        assert!(translate_gva(&mem, &ctx, 2 << 36).is_err());
+    }
+
+    #[test]
+    fn test_granule_64k() {
+        // 42-bit VAs of the upper range, from level 2.
+        let mut mem = Memory::default();
+        mem.set(0x1_0000, 5, 0x2_0000 | TABLE);
+        mem.set(0x1_0000, 6, 0x4000_0000 | BLOCK);
+        mem.set(0x2_0000, 7, 0x3_0000_0000 | PAGE);
+        let tcr = 22 << TCR_T1SZ_SHIFT | TG1_64K | TG0_64K;
+        let ctx = ctx(tcr, 0, 0x1_0000);
+        let base = 0xffff_fc00_0000_0000;
+
+        let t = translate_gva(&mem, &ctx, base | 5 << 29 | 7 << 16 | 0xabc).unwrap();
+        assert_eq!(t.gpa, 0x3_0000_0abc);
+        assert_eq!(t.size, 0x1_0000);
+        let t = translate_gva(&mem, &ctx, base | 6 << 29 | 0x1234_5678).unwrap();
+        assert_eq!(t.gpa, 0x5234_5678);
+        assert_eq!(t.size, 0x2000_0000);
+
+        // Outside of the upper range, or with its walks disabled.
+        assert!(translate_gva(&mem, &ctx, 0xffff_f800_0000_0000).is_err());
+        let mut disabled = ctx;
+        disabled.tcr |= TCR_EPD1;
+        assert!(translate_gva(&mem, &disabled, base | 6 << 29).is_err());
+    }
+
+    #[test]
+    fn test_top_byte_ignore() {
+        let mem = mem_4k();
+        let va = 1 << 39 | 2 << 30 | 3 << 21 | 4 << 12 | 0x123;
+        let tagged = 0x5a << 56 | va;
+        let ctx = ctx(16 << TCR_T0SZ_SHIFT, 0x1000, 0);
+        assert!(translate_gva(&mem, &ctx, tagged).is_err());
+        let mut tbi = ctx;
+        tbi.tcr |= TCR_TBI0;
+        assert_eq!(translate_gva(&mem, &tbi, tagged).unwrap().gpa, 0x8000_0123);
+        // Bit 55 still selects the range.
+        assert!(translate_gva(&mem, &tbi, tagged | 1 << 55).is_err());
+    }
+
+    #[test]
+    fn test_table_attributes() {
+        // Pages with all permissions, under tables restricting them.
+        let mut mem = Memory::default();
+        let page = 0x8000_0000 | PAGE | DESC_AP_EL0;
+        mem.set(0x1000, 0, 0x2000 | TABLE | DESC_AP_NO_WRITE_TABLE);
+        mem.set(0x1000, 1, 0x2000 | TABLE | DESC_AP_NO_EL0_TABLE);
+        mem.set(0x1000, 2, 0x2000 | TABLE | DESC_PXN_TABLE);
+        mem.set(0x2000, 0, 0x3000 | TABLE);
+        mem.set(0x3000, 0, 0x4000 | TABLE);
+        mem.set(0x4000, 0, page);
+        mem.set(0x4000, 1, page | DESC_PXN);
+        let ctx = ctx(16 << TCR_T0SZ_SHIFT, 0x1000, 0);
+
+        let flags = |va| translate_gva(&mem, &ctx, va).unwrap().flags;
+        let user = MemFlags::READ | MemFlags::USER;
+        assert_eq!(flags(0), user | MemFlags::EXECUTE);
+        assert_eq!(flags(1 << 39), rwx());
+        assert_eq!(flags(2 << 39), user | MemFlags::WRITE);
+        assert_eq!(flags(1 << 12), user);
+    }
+
+    #[test]
+    fn test_mmu_disabled() {
+        let mem = Memory::default();
+        let ctx = GuestStage1Context::default();
+        let t = translate_gva(&mem, &ctx, 0x4008_1234).unwrap();
+        assert_eq!(t.gpa, 0x4008_1234);
+        assert_eq!(t.flags, rwx());
+    }
+}
@@ -0,0 +1,183 @@
+//! Software walker of the stage-2 translation tables, as the hardware walks
+//! them.
+
+use super::ept::{mem_type_of, s2fwb_enabled, Stage2Config};
+use crate::mm::{GuestPhysAddr, HostPhysAddr, MemFlags, PageSize, Shareability};
+use crate::{RvmHal, RvmResult};
+
+/// The result of a stage-2 translation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage2Translation {
+    /// The host physical address that the IPA translates to.
+    pub hpa: HostPhysAddr,
+    /// Permissions and type of the mapping.
+    pub flags: MemFlags,
+    /// Size of the page or block that contains the address.
+    pub page_size: PageSize,
+}
+
+// Descriptor fields.
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1;
+const DESC_MEMATTR_SHIFT: u64 = 2;
+const DESC_S2AP_R: u64 = 1 << 6;
+const DESC_S2AP_W: u64 = 1 << 7;
+const DESC_SH_SHIFT: u64 = 8;
+const DESC_XN_1: u64 = 1 << 54;
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Translate `ipa` with the stage-2 tables rooted at `root`, whose geometry is
+/// described by `config`. The descriptors are read through
+/// [`RvmHal::phys_to_virt`].
+pub fn translate_ipa<H: RvmHal>(
+    config: &Stage2Config,
+    root: HostPhysAddr,
+    ipa: GuestPhysAddr,
+) -> RvmResult<Stage2Translation> {
+    let ipa_bits = config.ipa_bits();
+    if ipa >> ipa_bits != 0 {
+        return rvm_err!(
+            InvalidParam,
+            format_args!("IPA {:#x} exceeds {} bits", ipa, ipa_bits)
+        );
+    }
+    let mut level = config.start_level();
+    let mut table = root as u64;
+    loop {
+        let shift = 12 + 9 * (3 - level);
+        // The starting level may index concatenated tables.
+        let index_bits = if level == config.start_level() {
+            ipa_bits - shift
+        } else {
+            9
+        };
+        let index = (ipa >> shift) & ((1 << index_bits) - 1);
+        let desc_paddr = table as HostPhysAddr + index * 8;
+        let desc = unsafe { (H::phys_to_virt(desc_paddr) as *const u64).read_volatile() };
+        if desc & DESC_VALID == 0 {
+            return rvm_err!(
+                InvalidParam,
+                format_args!(
+                    "stage-2 translation fault at level {} for {:#x}",
+                    level, ipa
+                )
+            );
+        }
+        let is_table = desc & DESC_TABLE != 0;
+        if level < 3 && is_table {
+            table = desc & DESC_ADDR_MASK;
+            level += 1;
+            continue;
+        }
+        let page_size = match (level, is_table) {
+            (3, true) => PageSize::Size4K,
+            (2, false) => PageSize::Size2M,
+            (1, false) => PageSize::Size1G,
+            _ => {
+                return rvm_err!(
+                    InvalidParam,
+                    format_args!("invalid stage-2 descriptor {:#x} at level {}", desc, level)
+                )
+            }
+        };
+        let mut flags = MemFlags::empty();
+        if desc & DESC_S2AP_R != 0 {
+            flags |= MemFlags::READ;
+        }
+        if desc & DESC_S2AP_W != 0 {
+            flags |= MemFlags::WRITE;
+        }
+        if desc & DESC_XN_1 == 0 {
+            flags |= MemFlags::EXECUTE;
+        }
+        let mem_type = mem_type_of((desc >> DESC_MEMATTR_SHIFT) & 0b1111, s2fwb_enabled());
+        flags = flags.with_mem_type(mem_type);
+        if !flags.contains(MemFlags::DEVICE) {
+            flags = flags.with_shareability(match (desc >> DESC_SH_SHIFT) & 0b11 {
+                0b10 => Shareability::OuterShareable,
+                0b11 => Shareability::InnerShareable,
+                _ => Shareability::NonShareable,
+            });
+        }
+        let offset = page_size.page_offset(ipa);
+        return Ok(Stage2Translation {
+            hpa: (desc & DESC_ADDR_MASK) as HostPhysAddr & !(page_size as usize - 1) | offset,
+            flags,
+            page_size,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::NestedPageTable;
+    use crate::mm::HeapHal;
+
+    type Npt = NestedPageTable<HeapHal>;
+
+    fn rw() -> MemFlags {
+        MemFlags::READ | MemFlags::WRITE
+    }
+
+    #[test]
+    fn test_translate_pages_and_blocks() {
+        for (ipa_bits, start_level) in [(36, 1), (40, 1), (44, 0), (48, 0)] {
+            let config = Stage2Config::with_pa_bits(ipa_bits, 48).unwrap();
+            assert_eq!(config.start_level(), start_level);
+            let mut npt = Npt::new_stage2(&config).unwrap();
+            let top = 1usize << (ipa_bits - 1);
+            npt.map(0x1000, 0x8000_3000, PageSize::Size4K, rw())
+                .unwrap();
+            npt.map(
+                0x4020_0000,
+                0x8020_0000,
+                PageSize::Size2M,
+                rw() | MemFlags::EXECUTE,
+            )
+            .unwrap();
+            npt.map(
+                top,
+                0x1_4000_0000,
+                PageSize::Size1G,
+                rw() | MemFlags::DEVICE,
+            )
+            .unwrap();
+
+            for ipa in [
+                0x1000,
+                0x1ff8,
+                0x4020_0000,
+                0x402f_f123,
+                top,
+                top + 0x3fff_fff8,
+            ] {
+                let t = translate_ipa::<HeapHal>(&config, npt.root_paddr(), ipa).unwrap();
+                let (hpa, flags, page_size) = npt.query(ipa).unwrap();
+                assert_eq!(t.hpa, hpa);
+                assert_eq!(t.flags, flags);
+                assert_eq!(t.page_size, page_size);
+            }
+            let t = translate_ipa::<HeapHal>(&config, npt.root_paddr(), 0x402f_f123).unwrap();
+            assert_eq!(t.hpa, 0x802f_f123);
+            assert_eq!(t.flags, rw() | MemFlags::EXECUTE);
+            let t = translate_ipa::<HeapHal>(&config, npt.root_paddr(), top + 0x10).unwrap();
+            assert_eq!(t.hpa, 0x1_4000_0010);
+            assert_eq!(t.flags, rw() | MemFlags::DEVICE);
+        }
+    }
+
+    #[test]
+    fn test_translation_fault() {
+        let config = Stage2Config::with_pa_bits(40, 40).unwrap();
+        let mut npt = Npt::new_stage2(&config).unwrap();
+        npt.map(0x1000, 0x2000, PageSize::Size4K, rw()).unwrap();
+        let root = npt.root_paddr();
+        // Unmapped entry in an existing table, and missing tables.
+        assert!(translate_ipa::<HeapHal>(&config, root, 0x2000).is_err());
+        assert!(translate_ipa::<HeapHal>(&config, root, 0x8000_0000).is_err());
+        assert!(translate_ipa::<HeapHal>(&config, root, 1 << 40).is_err());
+        npt.unmap(0x1000).unwrap();
+        assert!(translate_ipa::<HeapHal>(&config, root, 0x1000).is_err());
+    }
+}
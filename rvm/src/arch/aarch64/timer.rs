@@ -0,0 +1,195 @@
+//! Generic timer virtualization.
+//!
+//! The EL1 virtual timer of a vCPU runs in hardware while the vCPU is loaded,
+//! and is saved and disabled when it exits, so that its interrupt only fires
+//! for the loaded vCPU. The EL1 physical timer is trapped and emulated in
+//! software.
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{CNTPCT_EL0, CNTVOFF_EL2, CNTV_CTL_EL0, CNTV_CVAL_EL0};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use super::exit::SysReg;
+
+/// PPI of the EL1 virtual timer.
+pub const VTIMER_IRQ: usize = 27;
+/// PPI of the EL1 physical timer.
+pub const PTIMER_IRQ: usize = 30;
+
+// CNTx_CTL_EL0 fields.
+const CTL_ENABLE: u64 = 1 << 0;
+const CTL_IMASK: u64 = 1 << 1;
+const CTL_ISTATUS: u64 = 1 << 2;
+
+/// The virtual counter of a VM, which starts at zero and stops while the VM is
+/// paused. It is shared by all vCPUs of the VM through their counter offset.
+#[derive(Debug, Clone)]
+pub struct VmCounter {
+    offset: u64,
+    paused_at: Option<u64>,
+}
+
+impl VmCounter {
+    /// Start the counter at zero.
+    pub fn new() -> Self {
+        Self {
+            offset: CNTPCT_EL0.get(),
+            paused_at: None,
+        }
+    }
+
+    /// The value of CNTVOFF_EL2 for the vCPUs of the VM.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Current value of the counter.
+    pub fn count(&self) -> u64 {
+        self.paused_at.unwrap_or_else(|| CNTPCT_EL0.get()) - self.offset
+    }
+
+    /// Stop the counter, all vCPUs of the VM must not run until resumed.
+    pub fn pause(&mut self) {
+        self.paused_at.get_or_insert_with(|| CNTPCT_EL0.get());
+    }
+
+    /// Restart the counter. The new offset must be set to the vCPUs before
+    /// they run again.
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.offset += CNTPCT_EL0.get() - paused_at;
+        }
+    }
+}
+
+impl Default for VmCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Generic timer context of a vCPU.
+#[derive(Debug, Default, Clone)]
+pub struct TimerContext {
+    cntvoff: u64,
+    cntv_ctl: u64,
+    cntv_cval: u64,
+    cntp_ctl: u64,
+    cntp_cval: u64,
+}
+
+impl TimerContext {
+    pub const CNTP_TVAL_EL0: SysReg = SysReg::new(3, 3, 14, 2, 0);
+    pub const CNTP_CTL_EL0: SysReg = SysReg::new(3, 3, 14, 2, 1);
+    pub const CNTP_CVAL_EL0: SysReg = SysReg::new(3, 3, 14, 2, 2);
+
+    /// Save the virtual timer from hardware and disable it, so that it does
+    /// not fire while the vCPU is not loaded.
+    #[inline]
+    pub(crate) fn save(&mut self) {
+        self.cntv_ctl = CNTV_CTL_EL0.get();
+        self.cntv_cval = CNTV_CVAL_EL0.get();
+        CNTV_CTL_EL0.set(0);
+    }
+
+    /// Load the virtual timer and the counter offset to hardware.
+    #[inline]
+    pub(crate) fn restore(&self) {
+        CNTVOFF_EL2.set(self.cntvoff);
+        CNTV_CVAL_EL0.set(self.cntv_cval);
+        CNTV_CTL_EL0.set(self.cntv_ctl);
+    }
+
+    pub(crate) fn set_counter_offset(&mut self, offset: u64) {
+        self.cntvoff = offset;
+    }
+
+    pub(crate) fn counter_offset(&self) -> u64 {
+        self.cntvoff
+    }
+
+    fn fires(ctl: u64, cval: u64, count: u64) -> bool {
+        ctl & (CTL_ENABLE | CTL_IMASK) == CTL_ENABLE && count >= cval
+    }
+
+    /// Whether the saved virtual timer is asserting its interrupt.
+    pub fn vtimer_pending(&self) -> bool {
+        let count = CNTPCT_EL0.get().wrapping_sub(self.cntvoff);
+        Self::fires(self.cntv_ctl, self.cntv_cval, count)
+    }
+
+    /// Whether the emulated physical timer is asserting its interrupt.
+    pub fn ptimer_pending(&self) -> bool {
+        Self::fires(self.cntp_ctl, self.cntp_cval, CNTPCT_EL0.get())
+    }
+
+    /// The physical count at which the earliest enabled timer fires.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let vtimer = (self.cntv_ctl & (CTL_ENABLE | CTL_IMASK) == CTL_ENABLE)
+            .then(|| self.cntv_cval.saturating_add(self.cntvoff));
+        let ptimer =
+            (self.cntp_ctl & (CTL_ENABLE | CTL_IMASK) == CTL_ENABLE).then_some(self.cntp_cval);
+        match (vtimer, ptimer) {
+            (Some(v), Some(p)) => Some(v.min(p)),
+            (v, p) => v.or(p),
+        }
+    }
+
+    /// The physical count at which the emulated physical timer fires, if it is
+    /// enabled and not asserting its interrupt already.
+    pub fn ptimer_deadline(&self) -> Option<u64> {
+        let enabled = self.cntp_ctl & (CTL_ENABLE | CTL_IMASK) == CTL_ENABLE;
+        (enabled && CNTPCT_EL0.get() < self.cntp_cval).then_some(self.cntp_cval)
+    }
+
+    /// Emulate a trapped read of an EL1 physical timer register.
+    pub fn read_ptimer(&self, reg: SysReg) -> Option<u64> {
+        let count = CNTPCT_EL0.get();
+        Some(match reg {
+            Self::CNTP_CTL_EL0 => {
+                let istatus = if self.cntp_ctl & CTL_ENABLE != 0 && count >= self.cntp_cval {
+                    CTL_ISTATUS
+                } else {
+                    0
+                };
+                self.cntp_ctl | istatus
+            }
+            Self::CNTP_CVAL_EL0 => self.cntp_cval,
+            // TVAL is a signed 32-bit down-counter.
+            Self::CNTP_TVAL_EL0 => self.cntp_cval.wrapping_sub(count) as u32 as u64,
+            _ => return None,
+        })
+    }
+
+    /// Emulate a trapped write of an EL1 physical timer register.
+    pub fn write_ptimer(&mut self, reg: SysReg, val: u64) -> Option<()> {
+        match reg {
+            Self::CNTP_CTL_EL0 => self.cntp_ctl = val & (CTL_ENABLE | CTL_IMASK),
+            Self::CNTP_CVAL_EL0 => self.cntp_cval = val,
+            Self::CNTP_TVAL_EL0 => {
+                self.cntp_cval = CNTPCT_EL0.get().wrapping_add(val as u32 as i32 as u64)
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Arm the EL2 physical timer to fire at the physical count `deadline`, or
+/// disarm it with `None`.
+#[inline]
+pub(crate) fn set_hyp_timer(deadline: Option<u64>) {
+    unsafe {
+        match deadline {
+            Some(cval) => asm!(
+                "msr cnthp_cval_el2, {}",
+                "msr cnthp_ctl_el2, {}",
+                "isb",
+                in(reg) cval,
+                in(reg) CTL_ENABLE,
+            ),
+            None => asm!("msr cnthp_ctl_el2, xzr", "isb"),
+        }
+    }
+}
@@ -6,13 +6,81 @@ use core::{
 };
 
 use aarch64_cpu::asm::barrier;
-use aarch64_cpu::registers::ESR_EL2::EC::Value;
 use aarch64_cpu::registers::*;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
-use crate::{arch::aarch64::instructions, GuestPhysAddr, HostPhysAddr, RvmHal, RvmResult};
+use crate::{
+    arch::aarch64::instructions, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, RvmHal, RvmResult,
+};
+
+use super::{
+    ept,
+    exit::{ec, ArmExitInfo, ArmExitReason, VmExit},
+    fp::{self, FpState},
+    regs::{GeneralRegisters, SystemRegisters},
+    timer::{self, TimerContext},
+    ArchPerCpuState, GuestTraps, Stage2Config,
+};
+
+/// Exception kinds returned by `vm_launch`, in the order of the vector table.
+const EXIT_SYNC: u64 = 0;
+const EXIT_IRQ: u64 = 1;
+const EXIT_FIQ: u64 = 2;
+const EXIT_SERROR: u64 = 3;
+
+// Fields of ESR_ELx.
+const ESR_EC_SHIFT: u64 = 26;
+const ESR_IL: u64 = 1 << 25;
+const ESR_WNR: u64 = 1 << 6;
+/// Synchronous external abort, not on translation table walk.
+const FSC_EXTERNAL_ABORT: u64 = 0x10;
+
+// Virtual exception controls of HCR_EL2.
+const HCR_VF: u64 = 1 << 6;
+const HCR_VSE: u64 = 1 << 8;
+
+/// Size of the host context pushed by `vm_launch`: x18-x30 and VBAR_EL2.
+const HOST_CONTEXT_SIZE: usize = 14 * size_of::<u64>();
 
-use super::{regs::GeneralRegisters, ArchPerCpuState};
+/// Offset of `ArmVcpu::host_stack_top`.
+const HOST_STACK_TOP: usize = size_of::<GeneralRegisters>() + 3 * size_of::<u64>();
+
+/// Offset from VBAR_EL1 of the vector of a synchronous exception taken to
+/// EL1 from the guest PSTATE `spsr`.
+fn sync_vector_offset(spsr: u64) -> u64 {
+    match spsr & 0b1_1111 {
+        // Current EL with SP_EL0, current EL with SP_ELx.
+        0b0_0100 => 0x000,
+        0b0_0101 => 0x200,
+        // Lower EL using AArch64 (M[4] = 0), or using AArch32.
+        m if m & 0b1_0000 == 0 => 0x400,
+        _ => 0x600,
+    }
+}
+
+/// An entry of the guest vector table, for exceptions taken from the guest:
+/// save the guest registers to the vCPU and return to the host.
+macro_rules! guest_vector_entry {
+    ($kind:literal) => {
+        concat!(
+            ".p2align 7\n",
+            save_regs_to_stack!(),
+            "
+        mov     x0, ",
+            $kind,
+            "
+        b       {exit}\n"
+        )
+    };
+}
+
+/// An entry of the guest vector table that can not be taken.
+macro_rules! invalid_vector_entry {
+    () => {
+        ".p2align 7
+        b       {failed}\n"
+    };
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -23,55 +91,301 @@ pub struct ArmVcpu<H: RvmHal> {
     spsr: u64,
     host_stack_top: u64,
     pub cpu_id: u64,
+    hcr: u64,
+    vtcr: u64,
+    vttbr: u64,
+    sys_regs: SystemRegisters,
+    timer: TimerContext,
+    fp_state: FpState,
+    /// Owner ID of `fp_state` in the FP/SIMD registers.
+    fp_id: u64,
+    /// The physical CPU on which `fp_state` was last loaded.
+    fp_cpu: usize,
     _phantom_data: PhantomData<H>,
 }
 
 impl<H: RvmHal> ArmVcpu<H> {
+    /// PSTATE on reset: EL1h with all exceptions masked.
+    const RESET_SPSR: u64 = 0x3c5;
+
     pub(crate) fn new(
         _percpu: &ArchPerCpuState<H>,
         entry: GuestPhysAddr,
         npt_root: HostPhysAddr,
+        s2_config: &Stage2Config,
+        vmid: u16,
         cpu_id: u64,
     ) -> RvmResult<Self> {
-        let vcpu = Self {
+        let mut vcpu = Self {
             guest_regs: GeneralRegisters::default(),
             guest_sp: 0,
             elr: entry as u64,
-            spsr: (SPSR_EL2::M::EL1h
-                + SPSR_EL2::D::Masked
-                + SPSR_EL2::A::Masked
-                + SPSR_EL2::I::Masked
-                + SPSR_EL2::F::Masked)
-                .into(),
+            spsr: Self::RESET_SPSR,
             cpu_id,
             host_stack_top: 0,
+            hcr: 0,
+            vtcr: s2_config.vtcr(),
+            // Stale entries of the VMID were invalidated when it was allocated.
+            vttbr: (VTTBR_EL2::VMID.val(vmid as u64)
+                + VTTBR_EL2::BADDR.val(npt_root as u64 >> 1))
+            .into(),
+            sys_regs: SystemRegisters::reset(0),
+            timer: TimerContext::default(),
+            fp_state: FpState::default(),
+            fp_id: fp::alloc_id(),
+            fp_cpu: usize::MAX,
             _phantom_data: PhantomData,
         };
         info!("npt root is {:x}.", npt_root);
-        vcpu.setup(npt_root)?;
+        vcpu.setup()?;
+        // Physical IRQs are always taken to EL2, and forwarded to the guests
+        // owning them.
+        vcpu.hcr = HCR_EL2.get() & !GuestTraps::all().bits() | HCR_EL2::IMO::SET.value;
+        if ept::s2fwb_enabled() {
+            vcpu.hcr |= HCR_EL2::FWB::SET.value;
+        }
         info!("[RVM] created ArmVcpu");
         Ok(vcpu)
     }
 
-    // #[repr(align(128))]
-    pub fn run(&mut self) -> ! {
-        unsafe { self.vm_launch() }
+    /// Run the guest until the next exit that must be handled by the caller.
+    ///
+    /// Trapped FP/SIMD accesses are handled internally, all other exceptions
+    /// and physical interrupts taken from the guest are returned as a
+    /// [`VmExit`]. The guest state is saved in the vCPU on return, except the
+    /// FP/SIMD registers, which are saved by [`ArmVcpu::unload_fp`].
+    pub fn run(&mut self) -> RvmResult<VmExit> {
+        loop {
+            self.restore_context();
+            let kind = unsafe { self.vm_launch() };
+            self.save_context();
+            let exit = match kind {
+                EXIT_SYNC => VmExit::Exception(self.exit_info()?.exit_reason),
+                EXIT_IRQ => VmExit::Irq,
+                EXIT_FIQ => VmExit::Fiq,
+                EXIT_SERROR => VmExit::SError(ESR_EL2.get()),
+                _ => return rvm_err!(BadState, format_args!("invalid exit kind {}", kind)),
+            };
+            match exit {
+                VmExit::Exception(ArmExitReason::FpAccess) => self.handle_fp_trap()?,
+                _ => return Ok(exit),
+            }
+        }
+    }
+
+    /// Reset the vCPU to its power-on state with the entry point `entry`, e.g.
+    /// on a PSCI CPU_ON. The traps, the stage-2 translation, MPIDR and the
+    /// counter offset are kept.
+    pub fn reset(&mut self, entry: GuestPhysAddr) {
+        self.guest_regs = GeneralRegisters::default();
+        self.guest_sp = 0;
+        self.elr = entry as u64;
+        self.spsr = Self::RESET_SPSR;
+        self.sys_regs = SystemRegisters::reset(self.sys_regs.vmpidr_el2);
+        let cntvoff = self.timer.counter_offset();
+        self.timer = TimerContext::default();
+        self.timer.set_counter_offset(cntvoff);
+        self.fp_state = FpState::default();
+        // The registers still holding the previous state are not loaded.
+        self.fp_id = fp::alloc_id();
     }
 
     pub fn exit_info(&self) -> RvmResult<ArmExitInfo> {
+        let hpfar: u64;
+        unsafe { asm!("mrs {}, hpfar_el2", out(reg) hpfar) };
         Ok(ArmExitInfo {
-            exit_reason: ESR_EL2.read_as_enum(ESR_EL2::EC),
-            guest_pc: ELR_EL2.get(),
+            exit_reason: ArmExitReason::decode(ESR_EL2.get(), FAR_EL2.get(), hpfar),
+            guest_pc: self.elr,
         })
     }
 
     pub fn advance_pc(&mut self) -> RvmResult {
-        let mut elr_el2 = ELR_EL2.get();
-        elr_el2 += 4;
-        ELR_EL2.set(elr_el2);
+        self.elr += 4;
         Ok(())
     }
 
+    /// Inject an undefined instruction exception, for the current instruction.
+    pub fn inject_undef(&mut self) {
+        self.inject_sync(ec::UNKNOWN << ESR_EC_SHIFT | ESR_IL, None);
+    }
+
+    /// Inject a synchronous external data abort at the guest virtual address
+    /// `gva`, for the current instruction.
+    pub fn inject_data_abort(&mut self, gva: GuestVirtAddr, is_write: bool) {
+        let ec = if self.in_el0() {
+            ec::DABT_LOWER
+        } else {
+            ec::DABT_CURRENT
+        };
+        let wnr = if is_write { ESR_WNR } else { 0 };
+        self.inject_sync(
+            ec << ESR_EC_SHIFT | ESR_IL | wnr | FSC_EXTERNAL_ABORT,
+            Some(gva),
+        );
+    }
+
+    /// Inject a synchronous external instruction abort at the guest virtual
+    /// address `gva`.
+    pub fn inject_instr_abort(&mut self, gva: GuestVirtAddr) {
+        let ec = if self.in_el0() {
+            ec::IABT_LOWER
+        } else {
+            ec::IABT_CURRENT
+        };
+        self.inject_sync(ec << ESR_EC_SHIFT | ESR_IL | FSC_EXTERNAL_ABORT, Some(gva));
+    }
+
+    /// Make a virtual SError pending, taken when the guest unmasks it. The
+    /// hardware clears it once taken.
+    pub fn inject_serror(&mut self) {
+        self.hcr |= HCR_VSE;
+    }
+
+    /// Assert or deassert the virtual FIQ line of the guest.
+    pub fn set_virtual_fiq(&mut self, asserted: bool) {
+        if asserted {
+            self.hcr |= HCR_VF;
+        } else {
+            self.hcr &= !HCR_VF;
+        }
+    }
+
+    /// Whether the guest was running at EL0 when it exited.
+    fn in_el0(&self) -> bool {
+        self.spsr & 0b1111 == 0
+    }
+
+    /// Take a synchronous exception to the guest EL1 with the syndrome `esr`,
+    /// as the hardware would: save PSTATE and the return address, and jump to
+    /// the vector of VBAR_EL1 with all exceptions masked.
+    fn inject_sync(&mut self, esr: u64, far: Option<GuestVirtAddr>) {
+        let vector = sync_vector_offset(self.spsr);
+        self.sys_regs.spsr_el1 = self.spsr;
+        self.sys_regs.elr_el1 = self.elr;
+        self.sys_regs.esr_el1 = esr;
+        if let Some(far) = far {
+            self.sys_regs.far_el1 = far as u64;
+        }
+        self.elr = self.sys_regs.vbar_el1 + vector;
+        self.spsr = Self::RESET_SPSR;
+    }
+
+    /// Save the guest EL1 system registers and the virtual timer from hardware,
+    /// and disarm the EL2 timer, on a VM exit.
+    ///
+    /// General registers, SP_EL1, ELR_EL2 and SPSR_EL2 are saved by the trap
+    /// entry code.
+    pub fn save_context(&mut self) {
+        // A pending virtual SError is cleared when taken.
+        self.hcr = HCR_EL2.get();
+        self.sys_regs.save();
+        self.timer.save();
+        timer::set_hyp_timer(None);
+    }
+
+    /// Load the trap controls, the stage-2 translation, the virtual timer and
+    /// the guest EL1 system registers to hardware, before entering the guest.
+    ///
+    /// FP/SIMD accesses are trapped unless the FP/SIMD registers of this CPU
+    /// already hold the state of this vCPU. The EL2 timer is armed to make the
+    /// vCPU exit when the emulated physical timer fires, to inject its
+    /// interrupt. The virtual timer fires by itself while loaded.
+    pub fn restore_context(&self) {
+        HCR_EL2.set(self.hcr);
+        VTCR_EL2.set(self.vtcr);
+        VTTBR_EL2.set(self.vttbr);
+        self.timer.restore();
+        self.sys_regs.restore();
+        instructions::set_fp_trap(!fp::is_loaded(self.fp_id, self.fp_cpu));
+        // Not armed once fired, the interrupt was injected on the last exit.
+        timer::set_hyp_timer(self.timer.ptimer_deadline());
+    }
+
+    /// Handle a trapped FP/SIMD access, by switching the FP/SIMD registers of
+    /// this CPU to the state of this vCPU. The access is then retried.
+    pub fn handle_fp_trap(&mut self) -> RvmResult {
+        instructions::set_fp_trap(false);
+        // The state of the previous owner was saved when it was unloaded.
+        self.fp_cpu = unsafe { fp::load(self.fp_id, &self.fp_state) };
+        Ok(())
+    }
+
+    /// Save the FP/SIMD registers to the vCPU if they hold its state, which
+    /// the guest may have changed since it was loaded.
+    ///
+    /// The registers are left to the vCPU when [`ArmVcpu::run`] returns, so
+    /// this must be called on the CPU that last ran the vCPU before another
+    /// vCPU runs on that CPU, or this vCPU runs on another CPU.
+    pub fn unload_fp(&mut self) {
+        if fp::is_loaded(self.fp_id, self.fp_cpu) {
+            // Still trapped if another vCPU ran on this CPU since.
+            instructions::set_fp_trap(false);
+            unsafe { self.fp_state.save() };
+        }
+    }
+
+    /// The generic timer context of the vCPU, valid while it is not loaded.
+    pub fn timer(&self) -> &TimerContext {
+        &self.timer
+    }
+
+    pub fn timer_mut(&mut self) -> &mut TimerContext {
+        &mut self.timer
+    }
+
+    /// Set CNTVOFF_EL2 of the vCPU, the offset of the virtual counter of its
+    /// VM from the physical counter.
+    pub fn set_counter_offset(&mut self, offset: u64) {
+        self.timer.set_counter_offset(offset);
+    }
+
+    /// Put the physical CPU in a low-power state until an interrupt arrives,
+    /// while the vCPU is blocked on a trapped WFI.
+    ///
+    /// Physical IRQs are routed to EL2, so that the ones belonging to the guest
+    /// also wake up the CPU. They remain pending, and are taken when the guest
+    /// is resumed. The EL2 physical timer is armed to the next deadline of the
+    /// guest timers, whose interrupt must be enabled.
+    pub fn wait_for_interrupt(&self) {
+        timer::set_hyp_timer(self.timer.next_deadline());
+        aarch64_cpu::asm::wfi();
+        timer::set_hyp_timer(None);
+    }
+
+    /// Set the guest operations trapped to EL2, replacing the previous ones.
+    /// Trapped operations exit as [`ArmExitReason`]s to be emulated.
+    pub fn set_traps(&mut self, traps: GuestTraps) {
+        self.hcr = self.hcr & !GuestTraps::all().bits() | traps.bits();
+    }
+
+    /// The guest operations trapped to EL2.
+    pub fn traps(&self) -> GuestTraps {
+        GuestTraps::from_bits_truncate(self.hcr)
+    }
+
+    /// The FP/SIMD state of the vCPU, saved by [`ArmVcpu::unload_fp`].
+    pub fn fp_state(&self) -> &FpState {
+        &self.fp_state
+    }
+
+    /// Set the MPIDR_EL1 value read by the guest.
+    pub fn set_mpidr(&mut self, mpidr: u64) {
+        self.sys_regs.vmpidr_el2 = mpidr | 1 << 31;
+    }
+
+    /// The MPIDR_EL1 value read by the guest.
+    pub fn mpidr(&self) -> u64 {
+        self.sys_regs.vmpidr_el2
+    }
+
+    pub fn sys_regs(&self) -> &SystemRegisters {
+        &self.sys_regs
+    }
+
+    pub fn sys_regs_mut(&mut self) -> &mut SystemRegisters {
+        &mut self.sys_regs
+    }
+
     pub fn cpu_id(&self) -> u64 {
         self.cpu_id
     }
@@ -109,63 +423,91 @@ impl<H: RvmHal> ArmVcpu<H> {
         SCTLR_EL1.write(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
     }
 
-    fn setup(&self, npt_root: HostPhysAddr) -> RvmResult {
-        // Disable EL1 timer traps and the timer offset.
-        CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
-        CNTVOFF_EL2.set(0);
-
-        if !cfg!(feature = "linux") && cfg!(feature = "intr_emulate") {
-            HCR_EL2.modify(HCR_EL2::IMO::SET);
-        }
-
-        let vtcr_flags = VTCR_EL2::TG0::Granule4KB
-            + VTCR_EL2::SH0::Inner
-            + VTCR_EL2::SL0.val(2)
-            + VTCR_EL2::ORGN0::NormalWBRAWA
-            + VTCR_EL2::IRGN0::NormalWBRAWA
-            + VTCR_EL2::T0SZ.val(20);
-        VTCR_EL2.write(VTCR_EL2::PS::PA_44B_16TB + vtcr_flags);
-        barrier::isb(barrier::SY);
-
-        VTTBR_EL2.set_baddr(npt_root as _);
-        instructions::flush_tlb_all();
-
+    fn setup(&self) -> RvmResult {
+        // Trap the EL1 physical timer, the physical counter stays accessible.
+        CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::CLEAR + CNTHCTL_EL2::EL1PCTEN::SET);
         Ok(())
     }
 
+    /// Enter the guest, and return the kind of the exception that exits it.
+    ///
+    /// The callee-saved registers and VBAR_EL2 of the host are pushed to the
+    /// host stack, whose top is saved in `host_stack_top`. The guest runs with
+    /// the vector table below, and SP_EL2 pointing to the end of the saved
+    /// guest registers so that an exception saves them back to the vCPU.
     #[naked]
-    unsafe extern "C" fn vm_launch(&mut self) -> ! {
+    unsafe extern "C" fn vm_launch(&mut self) -> u64 {
         asm!(
-            "mov    x28, sp",
-            "str    x28, [x0, {host_stack_top}]",   // save current SP to Vcpu::host_stack_top
-            "mov    sp, x0",     // set SP to guest regs area
+            "sub    sp, sp, {host_context_size}",
+            "stp    x18, x19, [sp]",
+            "stp    x20, x21, [sp, 2 * 8]",
+            "stp    x22, x23, [sp, 4 * 8]",
+            "stp    x24, x25, [sp, 6 * 8]",
+            "stp    x26, x27, [sp, 8 * 8]",
+            "stp    x28, x29, [sp, 10 * 8]",
+            "mrs    x10, vbar_el2",
+            "stp    x30, x10, [sp, 12 * 8]",
+            "mov    x9, sp",
+            "str    x9, [x0, {host_stack_top}]",    // save current SP to Vcpu::host_stack_top
+            "adr    x10, 2f",
+            "msr    vbar_el2, x10",                 // take guest exceptions with the table below
+            "mov    sp, x0",                        // set SP to guest regs area
             restore_regs_from_stack!(),
             "eret",
-            "bl    {failed}",
-            host_stack_top = const size_of::<GeneralRegisters>() + 3 * size_of::<u64>(),
+            ".p2align 11",
+            "2:",
+            // current EL, with SP_EL0
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            // current EL, with SP_ELx
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            // lower EL, aarch64
+            guest_vector_entry!("{sync}"),
+            guest_vector_entry!("{irq}"),
+            guest_vector_entry!("{fiq}"),
+            guest_vector_entry!("{serror}"),
+            // lower EL, aarch32
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            invalid_vector_entry!(),
+            host_context_size = const HOST_CONTEXT_SIZE,
+            host_stack_top = const HOST_STACK_TOP,
+            sync = const EXIT_SYNC,
+            irq = const EXIT_IRQ,
+            fiq = const EXIT_FIQ,
+            serror = const EXIT_SERROR,
+            exit = sym Self::vm_exit,
             failed = sym Self::vmentry_failed,
             options(noreturn),
         )
     }
 
-    // This function is unused currently.
-    // See HANDLE_SYNC in hypervisor/src/arch/aarch64/trap.S.
+    /// Return from `vm_launch` to the host, with the exception kind in x0 and
+    /// SP pointing to the saved guest registers.
     #[naked]
-    unsafe extern "C" fn vm_exit(&mut self) -> ! {
+    unsafe extern "C" fn vm_exit() -> ! {
         asm!(
-            save_regs_to_stack!(),
-            "mov    x28, sp",                      // save temporary RSP to r15
-            "mov    x0, sp",                      // set the first arg to &Vcpu
-            "ldr    x29, [sp, {host_stack_top}]", // set RSP to Vcpu::host_stack_top
-            "mov    sp, x29",
-            "bl     {vmexit_handler}",              // call vmexit_handler
-            "mov    sp, x28",                      // load temporary RSP from r15
-            restore_regs_from_stack!(),
-            "eret",
-            "bl    {failed}",
-            host_stack_top = const size_of::<GeneralRegisters>() + 3 * size_of::<u64>(),
-            vmexit_handler = sym Self::vmexit_handler,
-            failed = sym Self::vmentry_failed,
+            "ldr    x9, [sp, {host_stack_top}]",    // set SP to Vcpu::host_stack_top
+            "mov    sp, x9",
+            "ldp    x30, x10, [sp, 12 * 8]",
+            "msr    vbar_el2, x10",
+            "isb",
+            "ldp    x28, x29, [sp, 10 * 8]",
+            "ldp    x26, x27, [sp, 8 * 8]",
+            "ldp    x24, x25, [sp, 6 * 8]",
+            "ldp    x22, x23, [sp, 4 * 8]",
+            "ldp    x20, x21, [sp, 2 * 8]",
+            "ldp    x18, x19, [sp]",
+            "add    sp, sp, {host_context_size}",
+            "ret",
+            host_context_size = const HOST_CONTEXT_SIZE,
+            host_stack_top = const HOST_STACK_TOP,
             options(noreturn),
         );
     }
@@ -173,15 +515,83 @@ impl<H: RvmHal> ArmVcpu<H> {
     fn vmentry_failed() -> ! {
         panic!("vm entry failed")
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::HeapHal;
+
+    const VBAR: u64 = 0xffff_8000_1000_0800;
+    const PC: u64 = 0xffff_8000_1234_5678;
+
+    fn new_vcpu(spsr: u64) -> ArmVcpu<HeapHal> {
+        let mut sys_regs = SystemRegisters::reset(0);
+        sys_regs.vbar_el1 = VBAR;
+        ArmVcpu {
+            guest_regs: GeneralRegisters::default(),
+            guest_sp: 0,
+            elr: PC,
+            spsr,
+            host_stack_top: 0,
+            cpu_id: 0,
+            hcr: 0,
+            vtcr: 0,
+            vttbr: 0,
+            sys_regs,
+            timer: TimerContext::default(),
+            fp_state: FpState::default(),
+            fp_id: 0,
+            fp_cpu: usize::MAX,
+            _phantom_data: PhantomData,
+        }
+    }
 
-    fn vmexit_handler(&mut self) {
-        H::vmexit_handler(self);
+    #[test]
+    fn test_vector_offset() {
+        // EL1t, EL1h, EL0 (AArch64), User and System (AArch32).
+        for (spsr, offset) in [
+            (0x3c4, 0x000),
+            (0x3c5, 0x200),
+            (0x000, 0x400),
+            (0x010, 0x600),
+            (0x01f, 0x600),
+        ] {
+            let mut vcpu = new_vcpu(spsr);
+            vcpu.inject_undef();
+            assert_eq!(vcpu.elr, VBAR + offset);
+            assert_eq!(vcpu.spsr, ArmVcpu::<HeapHal>::RESET_SPSR);
+            assert_eq!(vcpu.sys_regs.spsr_el1, spsr);
+            assert_eq!(vcpu.sys_regs.elr_el1, PC);
+        }
     }
-}
 
-pub type ArmExitReason = Option<Value>;
+    #[test]
+    fn test_esr() {
+        let mut vcpu = new_vcpu(0x3c5);
+        vcpu.sys_regs.far_el1 = 0xdead;
+        vcpu.inject_undef();
+        assert_eq!(vcpu.sys_regs.esr_el1, 0x0200_0000);
+        assert_eq!(vcpu.sys_regs.far_el1, 0xdead);
 
-pub struct ArmExitInfo {
-    pub exit_reason: ArmExitReason,
-    guest_pc: u64,
+        // EC 0x25, IL, WnR, FSC 0x10.
+        let mut vcpu = new_vcpu(0x3c5);
+        vcpu.inject_data_abort(0x8000_1000, true);
+        assert_eq!(vcpu.sys_regs.esr_el1, 0x9600_0050);
+        assert_eq!(vcpu.sys_regs.far_el1, 0x8000_1000);
+
+        // From EL0: EC 0x24, read.
+        let mut vcpu = new_vcpu(0x000);
+        vcpu.inject_data_abort(0x1000, false);
+        assert_eq!(vcpu.sys_regs.esr_el1, 0x9200_0010);
+
+        // EC 0x21 and 0x20.
+        let mut vcpu = new_vcpu(0x3c5);
+        vcpu.inject_instr_abort(0x4000);
+        assert_eq!(vcpu.sys_regs.esr_el1, 0x8600_0010);
+        assert_eq!(vcpu.sys_regs.far_el1, 0x4000);
+        let mut vcpu = new_vcpu(0x010);
+        vcpu.inject_instr_abort(0x4000);
+        assert_eq!(vcpu.sys_regs.esr_el1, 0x8200_0010);
+    }
 }
@@ -0,0 +1,127 @@
+//! Allocation of the list registers of the GIC virtual CPU interface, shared
+//! by the GICv2 and GICv3 drivers of the host.
+
+/// An in-use list register, decoded from its GIC-specific encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListRegister {
+    /// The virtual interrupt ID.
+    pub irq: usize,
+    /// Whether it is linked to the physical interrupt with the same ID.
+    pub hw: bool,
+    /// The requesting CPU of a GICv2 virtual SGI, 0 otherwise.
+    pub source: usize,
+}
+
+/// Where to write a virtual interrupt, as chosen by [`choose_list_register`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LrChoice {
+    /// Write the interrupt to this free list register.
+    Free(usize),
+    /// The interrupt is already in this list register. It is set pending
+    /// again if purely virtual, and a physical interrupt merged into it must
+    /// be deactivated by the hypervisor, as no guest EOI will do it.
+    Merge(usize),
+    /// All list registers are in use.
+    Full,
+}
+
+/// Choose the list register to inject the virtual interrupt `irq` into, given
+/// the list registers in order, `None` for the free ones.
+///
+/// `hw` and `source` are as in [`ListRegister`]. A list register holding the
+/// same interrupt is preferred to a free one, so that no interrupt is in
+/// two list registers.
+pub fn choose_list_register<I>(lrs: I, irq: usize, hw: bool, source: usize) -> LrChoice
+where
+    I: IntoIterator<Item = Option<ListRegister>>,
+{
+    let mut free_lr = None;
+    for (i, lr) in lrs.into_iter().enumerate() {
+        match lr {
+            None => {
+                free_lr.get_or_insert(i);
+            }
+            // The source of a physical interrupt is not relevant.
+            Some(lr) if lr.irq == irq && (hw || lr.hw || lr.source == source) => {
+                return LrChoice::Merge(i);
+            }
+            Some(_) => {}
+        }
+    }
+    free_lr.map_or(LrChoice::Full, LrChoice::Free)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn virt(irq: usize, source: usize) -> Option<ListRegister> {
+        Some(ListRegister {
+            irq,
+            hw: false,
+            source,
+        })
+    }
+
+    fn hw(irq: usize) -> Option<ListRegister> {
+        Some(ListRegister {
+            irq,
+            hw: true,
+            source: 0,
+        })
+    }
+
+    #[test]
+    fn test_free() {
+        assert_eq!(
+            choose_list_register([None, None], 27, true, 0),
+            LrChoice::Free(0)
+        );
+        assert_eq!(
+            choose_list_register([hw(33), None, None], 27, true, 0),
+            LrChoice::Free(1)
+        );
+        assert_eq!(choose_list_register([], 27, true, 0), LrChoice::Full);
+    }
+
+    #[test]
+    fn test_duplicate() {
+        // Physical interrupts merge with the same virtual interrupt, whatever
+        // its kind, even after a free list register.
+        assert_eq!(
+            choose_list_register([None, hw(27)], 27, true, 0),
+            LrChoice::Merge(1)
+        );
+        assert_eq!(
+            choose_list_register([None, virt(27, 0)], 27, true, 0),
+            LrChoice::Merge(1)
+        );
+        assert_eq!(
+            choose_list_register([virt(27, 0), None], 27, false, 0),
+            LrChoice::Merge(0)
+        );
+        assert_eq!(
+            choose_list_register([hw(27), None], 27, false, 0),
+            LrChoice::Merge(0)
+        );
+        // SGIs from different sources are distinct.
+        assert_eq!(
+            choose_list_register([virt(1, 0), None], 1, false, 2),
+            LrChoice::Free(1)
+        );
+        assert_eq!(
+            choose_list_register([virt(1, 0), virt(1, 2)], 1, false, 2),
+            LrChoice::Merge(1)
+        );
+    }
+
+    #[test]
+    fn test_overflow() {
+        let lrs = [hw(27), virt(1, 0), hw(33), virt(48, 0)];
+        assert_eq!(choose_list_register(lrs, 30, false, 0), LrChoice::Full);
+        assert_eq!(choose_list_register(lrs, 34, true, 0), LrChoice::Full);
+        // A duplicate is merged even with all list registers in use.
+        assert_eq!(choose_list_register(lrs, 33, true, 0), LrChoice::Merge(2));
+        assert_eq!(choose_list_register(lrs, 48, false, 0), LrChoice::Merge(3));
+    }
+}
@@ -0,0 +1,131 @@
+//! VMID allocation, VMIDs tag the stage-2 translations of each VM in the TLB.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aarch64_cpu::registers::ID_AA64MMFR1_EL1;
+use tock_registers::interfaces::Readable;
+
+use super::instructions;
+use crate::RvmResult;
+
+const MAX_VMIDS: usize = 1 << 16;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: AtomicU64 = AtomicU64::new(0);
+static VMID_BITMAP: [AtomicU64; MAX_VMIDS / 64] = [FREE; MAX_VMIDS / 64];
+
+/// Number of VMID bits supported by the hardware, 8 or 16.
+pub fn vmid_bits() -> usize {
+    if ID_AA64MMFR1_EL1.read(ID_AA64MMFR1_EL1::VMIDBits) == 0b0010 {
+        16
+    } else {
+        8
+    }
+}
+
+/// Take the lowest free ID below `1 << bits` in `bitmap`, 0 excepted.
+fn take_free_id(bitmap: &[AtomicU64], bits: usize) -> Option<u16> {
+    let num_words = (1 << bits) / 64;
+    for (i, word) in bitmap[..num_words].iter().enumerate() {
+        let mut cur = word.load(Ordering::Acquire);
+        loop {
+            let free = !cur & if i == 0 { !1 } else { !0 };
+            if free == 0 {
+                break;
+            }
+            let bit = free.trailing_zeros() as usize;
+            match word.compare_exchange(cur, cur | 1 << bit, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some((i * 64 + bit) as u16),
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+    None
+}
+
+/// Return the ID `id` taken by [`take_free_id`] to `bitmap`.
+fn release_id(bitmap: &[AtomicU64], id: u16) {
+    let id = id as usize;
+    bitmap[id / 64].fetch_and(!(1 << (id % 64)), Ordering::AcqRel);
+}
+
+/// A VMID owned by one VM, it will be released automatically on drop.
+#[derive(Debug)]
+pub struct Vmid(u16);
+
+impl Vmid {
+    /// Allocate a free VMID. VMID 0 is never allocated.
+    ///
+    /// TLB entries left by the previous owner of the VMID are invalidated.
+    #[inline]
+    pub fn alloc() -> RvmResult<Self> {
+        let Some(id) = take_free_id(&VMID_BITMAP, vmid_bits()) else {
+            return rvm_err!(ResourceBusy, "no free VMID");
+        };
+        instructions::flush_guest_tlb_all(id);
+        debug!("[RVM] allocated VMID {}", id);
+        Ok(Self(id))
+    }
+
+    /// The VMID value programmed into VTTBR_EL2.
+    pub fn id(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Drop for Vmid {
+    fn drop(&mut self) {
+        release_id(&VMID_BITMAP, self.0);
+        debug!("[RVM] released VMID {}", self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_bitmap() -> [AtomicU64; MAX_VMIDS / 64] {
+        [FREE; MAX_VMIDS / 64]
+    }
+
+    #[test]
+    fn test_skip_zero() {
+        let bitmap = new_bitmap();
+        assert_eq!(take_free_id(&bitmap, 8), Some(1));
+        assert_eq!(take_free_id(&bitmap, 8), Some(2));
+        // Even when released, 0 is not a valid VMID.
+        release_id(&bitmap, 0);
+        assert_eq!(take_free_id(&bitmap, 8), Some(3));
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        for bits in [8, 16] {
+            let bitmap = new_bitmap();
+            for id in 1..1 << bits {
+                assert_eq!(take_free_id(&bitmap, bits), Some(id as u16));
+            }
+            assert_eq!(take_free_id(&bitmap, bits), None);
+        }
+        // IDs over the supported width are not used.
+        let bitmap = new_bitmap();
+        bitmap[..4]
+            .iter()
+            .for_each(|word| word.store(!0, Ordering::Relaxed));
+        assert_eq!(take_free_id(&bitmap, 8), None);
+        assert_eq!(take_free_id(&bitmap, 16), Some(256));
+    }
+
+    #[test]
+    fn test_reuse() {
+        let bitmap = new_bitmap();
+        for id in 1..200 {
+            assert_eq!(take_free_id(&bitmap, 8), Some(id));
+        }
+        release_id(&bitmap, 130);
+        release_id(&bitmap, 64);
+        assert_eq!(take_free_id(&bitmap, 8), Some(64));
+        assert_eq!(take_free_id(&bitmap, 8), Some(130));
+        assert_eq!(take_free_id(&bitmap, 8), Some(200));
+    }
+}
@@ -5,10 +5,26 @@ pub trait RvmHal: Sized {
     fn alloc_page() -> Option<HostPhysAddr>;
     /// Deallocates the given physical page.
     fn dealloc_page(paddr: HostPhysAddr);
+    /// Allocates `num_pages` contiguous physical pages, with the start address
+    /// aligned to `1 << align_log2` pages.
+    fn alloc_contiguous_pages(num_pages: usize, align_log2: usize) -> Option<HostPhysAddr> {
+        if num_pages == 1 && align_log2 == 0 {
+            Self::alloc_page()
+        } else {
+            None
+        }
+    }
+    /// Deallocates `num_pages` contiguous physical pages.
+    fn dealloc_contiguous_pages(paddr: HostPhysAddr, num_pages: usize) {
+        for i in 0..num_pages {
+            Self::dealloc_page(paddr + i * crate::mm::PAGE_SIZE);
+        }
+    }
     /// Converts a physical address to a virtual address which can access.
     fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr;
     /// Converts a virtual address to the corresponding physical address.
     fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr;
-    /// VM-Exit handler.
-    fn vmexit_handler(vcpu: &mut crate::RvmVcpu<Self>);
+    /// Makes the physical CPU `cpu_id` exit from the guest it runs, or wakes it
+    /// up if it waits for its vCPU to be runnable.
+    fn kick_cpu(_cpu_id: usize) {}
 }
@@ -11,16 +11,26 @@ extern crate log;
 mod error;
 mod hal;
 mod mm;
+mod vm;
 
 pub mod arch;
 
 use arch::ArchPerCpuState;
 
-pub use arch::{NestedPageTable, PageTable, RvmVcpu, Stage1PTE};
+pub use arch::{GuestTraps, NestedPageTable, PageTable, RvmVcpu, Stage1PTE, Stage2Config};
+pub use arch::{VmExit, Vmid};
+pub use arch::{enable_s2fwb, has_s2fwb};
+pub use arch::{flush_guest_tlb_all, flush_guest_tlb_range, vmid_bits};
+pub use arch::{translate_gva, GuestMemoryAccess, GuestStage1Context, GuestTranslation};
+pub use arch::{translate_ipa, Stage2Translation};
 pub use error::{RvmError, RvmResult};
 pub use hal::RvmHal;
-pub use mm::{GenericPTE, Level4PageTable, MemFlags, NestedPageFaultInfo};
+pub use mm::{GenericPTE, Level4PageTable, MemFlags, NestedPageFaultInfo, PageSize, PhysFrame};
+pub use mm::{GuestMemoryRegion, GuestPhysMemorySet, MapRegion};
+pub use mm::{HeapHal, MemType, Shareability};
 pub use mm::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr};
+pub use vm::{DeviceBus, MmioDevice, SysRegHandler};
+pub use vm::{RvmVm, RvmVmConfig, VcpuState, VmState};
 
 /// Whether the hardware has virtualization support.
 pub fn has_hardware_support() -> bool {
@@ -58,16 +68,26 @@ impl<H: RvmHal> RvmPerCpu<H> {
     }
 
     /// Create a [`RvmVcpu`], set the entry point to `entry`, set the nested
-    /// page table root to `npt_root`.
+    /// page table root to `npt_root`, whose geometry is described by `s2_config`,
+    /// and tag its translations with `vmid`.
     pub fn create_vcpu(
         &self,
         entry: GuestPhysAddr,
         npt_root: HostPhysAddr,
+        s2_config: &Stage2Config,
+        vmid: u16,
     ) -> RvmResult<RvmVcpu<H>> {
         if !self.is_enabled() {
             rvm_err!(BadState, "virtualization is not enabled")
         } else {
-            RvmVcpu::new(&self.arch, entry, npt_root, self._cpu_id as u64)
+            RvmVcpu::new(
+                &self.arch,
+                entry,
+                npt_root,
+                s2_config,
+                vmid,
+                self._cpu_id as u64,
+            )
         }
     }
 }
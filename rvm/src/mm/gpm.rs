@@ -0,0 +1,599 @@
+//! Guest physical memory of a VM, as regions mapped by its stage-2 page table.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt::{Debug, Formatter, Result};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, MemFlags, PageSize, PhysFrame, PAGE_SIZE};
+use crate::arch::{
+    translate_gva, GuestMemoryAccess, GuestStage1Context, GuestTranslation, NestedPageTable,
+    RvmVcpu, Stage2Config, Vmid,
+};
+use crate::{flush_guest_tlb_range, RvmError, RvmHal, RvmResult};
+
+const fn is_aligned(addr: usize) -> bool {
+    addr & (PAGE_SIZE - 1) == 0
+}
+
+enum Mapper<H: RvmHal> {
+    /// Maps to the host physical memory at a fixed offset.
+    Offset(usize),
+    /// Maps to frames allocated for the region, freed with it.
+    Alloc(PhysFrame<H>),
+}
+
+impl<H: RvmHal> Debug for Mapper<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Self::Offset(off) => f.debug_tuple("Offset").field(off).finish(),
+            Self::Alloc(frame) => f.debug_tuple("Alloc").field(&frame.start_paddr()).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GuestMemoryRegion {
+    pub gpa: GuestPhysAddr,
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+    pub flags: MemFlags,
+}
+
+/// One bit per 4K page of a region, set when the guest writes to the page.
+pub struct DirtyBitmap {
+    bits: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    fn new(num_pages: usize) -> Self {
+        Self {
+            bits: (0..(num_pages + 63) / 64)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    fn set(&self, page_idx: usize) {
+        self.bits[page_idx / 64].fetch_or(1 << (page_idx % 64), Ordering::AcqRel);
+    }
+
+    /// Returns a copy of the bitmap.
+    pub fn snapshot(&self) -> Vec<u64> {
+        self.bits
+            .iter()
+            .map(|w| w.load(Ordering::Acquire))
+            .collect()
+    }
+
+    /// Returns the bitmap and clears it, each word is fetched and cleared
+    /// atomically so no concurrent update is lost.
+    pub fn fetch_and_clear(&self) -> Vec<u64> {
+        self.bits
+            .iter()
+            .map(|w| w.swap(0, Ordering::AcqRel))
+            .collect()
+    }
+}
+
+pub struct MapRegion<H: RvmHal> {
+    pub start: GuestPhysAddr,
+    pub size: usize,
+    /// Permissions of the pages outside of the `protected` ranges.
+    pub flags: MemFlags,
+    /// Sub-ranges whose permissions were changed by
+    /// [`GuestPhysMemorySet::protect_region`], as their start, end and flags.
+    protected: BTreeMap<GuestPhysAddr, (GuestPhysAddr, MemFlags)>,
+    mapper: Mapper<H>,
+    dirty_log: Option<DirtyBitmap>,
+}
+
+impl<H: RvmHal> MapRegion<H> {
+    pub fn new_offset(
+        start_gpa: GuestPhysAddr,
+        start_hpa: HostPhysAddr,
+        size: usize,
+        flags: MemFlags,
+    ) -> Self {
+        assert!(is_aligned(start_gpa));
+        assert!(is_aligned(start_hpa));
+        assert!(is_aligned(size));
+        let offset = start_gpa.wrapping_sub(start_hpa);
+        Self {
+            start: start_gpa,
+            size,
+            flags,
+            protected: BTreeMap::new(),
+            mapper: Mapper::Offset(offset),
+            dirty_log: None,
+        }
+    }
+
+    /// A region of `size` bytes at `start_gpa`, mapped to newly allocated
+    /// zeroed host memory. The memory is aligned to 2M if the size allows, so
+    /// that it can be mapped with blocks.
+    pub fn new_alloc(start_gpa: GuestPhysAddr, size: usize, flags: MemFlags) -> RvmResult<Self> {
+        assert!(is_aligned(start_gpa));
+        assert!(is_aligned(size));
+        let align_log2 = if PageSize::Size2M.is_aligned(size) {
+            9
+        } else {
+            0
+        };
+        let frame = PhysFrame::alloc_contiguous_zero(size / PAGE_SIZE, align_log2)?;
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags,
+            protected: BTreeMap::new(),
+            mapper: Mapper::Alloc(frame),
+            dirty_log: None,
+        })
+    }
+
+    fn is_overlap_with(&self, other: &Self) -> bool {
+        let s0 = self.start;
+        let e0 = s0 + self.size;
+        let s1 = other.start;
+        let e1 = s1 + other.size;
+        !(e0 <= s1 || e1 <= s0)
+    }
+
+    /// The permissions of the page at `gpa`.
+    fn flags_at(&self, gpa: GuestPhysAddr) -> MemFlags {
+        match self.protected.range(..=gpa).next_back() {
+            Some((_, &(end, flags))) if gpa < end => flags,
+            _ => self.flags,
+        }
+    }
+
+    /// Set the permissions of `[start, end)`, within the region.
+    fn set_flags(&mut self, start: GuestPhysAddr, end: GuestPhysAddr, flags: MemFlags) {
+        if start == self.start && end == self.start + self.size {
+            self.flags = flags;
+            self.protected.clear();
+            return;
+        }
+        // Trim the ranges overlapping with the new one.
+        let overlapping: Vec<_> = self
+            .protected
+            .range(..end)
+            .filter(|(_, &(e, _))| e > start)
+            .map(|(&s, &range)| (s, range))
+            .collect();
+        for (s, (e, f)) in overlapping {
+            self.protected.remove(&s);
+            if s < start {
+                self.protected.insert(s, (start, f));
+            }
+            if e > end {
+                self.protected.insert(end, (e, f));
+            }
+        }
+        if flags != self.flags {
+            self.protected.insert(start, (end, flags));
+        }
+    }
+
+    /// The consecutive sub-ranges covering the region, as their start, size
+    /// and permissions.
+    fn flag_ranges(&self) -> Vec<(GuestPhysAddr, usize, MemFlags)> {
+        let mut ranges = Vec::new();
+        let mut cur = self.start;
+        for (&start, &(end, flags)) in &self.protected {
+            if cur < start {
+                ranges.push((cur, start - cur, self.flags));
+            }
+            ranges.push((start, end - start, flags));
+            cur = end;
+        }
+        let end = self.start + self.size;
+        if cur < end {
+            ranges.push((cur, end - cur, self.flags));
+        }
+        ranges
+    }
+
+    fn target(&self, gpa: GuestPhysAddr) -> HostPhysAddr {
+        match self.mapper {
+            Mapper::Offset(off) => gpa.wrapping_sub(off),
+            Mapper::Alloc(ref frame) => gpa - self.start + frame.start_paddr(),
+        }
+    }
+
+    fn map_to(&self, npt: &mut NestedPageTable<H>) -> RvmResult {
+        debug!("end {:x}", self.start + self.size);
+        npt.map_region(
+            self.start,
+            self.target(self.start),
+            self.size,
+            self.flags,
+            true,
+        )
+    }
+
+    fn unmap_to(&self, npt: &mut NestedPageTable<H>, vmid: u16) -> RvmResult {
+        let freed_tables = npt.unmap_region(self.start, self.size)?;
+        // The emptied tables can be reused only after the stale walks are gone.
+        flush_guest_tlb_range(vmid, self.start, self.size);
+        drop(freed_tables);
+        Ok(())
+    }
+}
+
+impl<H: RvmHal> Debug for MapRegion<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("MapRegion")
+            .field("range", &(self.start..self.start + self.size))
+            .field("size", &self.size)
+            .field("flags", &self.flags)
+            .field("protected", &self.protected)
+            .field("mapper", &self.mapper)
+            .field("dirty_log", &self.dirty_log.is_some())
+            .finish()
+    }
+}
+
+impl<H: RvmHal> From<GuestMemoryRegion> for MapRegion<H> {
+    fn from(r: GuestMemoryRegion) -> Self {
+        Self::new_offset(r.gpa, r.hpa, r.size, r.flags)
+    }
+}
+
+/// The region of `regions`, keyed by their start, that contains `gpa`.
+fn find_region<H: RvmHal>(
+    regions: &BTreeMap<GuestPhysAddr, MapRegion<H>>,
+    gpa: GuestPhysAddr,
+) -> Option<&MapRegion<H>> {
+    match regions.range(..=gpa).last() {
+        Some((_, region)) if gpa < region.start + region.size => Some(region),
+        _ => None,
+    }
+}
+
+pub struct GuestPhysMemorySet<H: RvmHal> {
+    regions: BTreeMap<GuestPhysAddr, MapRegion<H>>,
+    npt: NestedPageTable<H>,
+    s2_config: Stage2Config,
+    vmid: Vmid,
+}
+
+impl<H: RvmHal> GuestPhysMemorySet<H> {
+    pub fn new(ipa_bits: usize) -> RvmResult<Self> {
+        let s2_config = Stage2Config::new(ipa_bits)?;
+        info!("stage-2 config: {:?}", s2_config);
+        Ok(Self {
+            npt: NestedPageTable::new_stage2(&s2_config)?,
+            regions: BTreeMap::new(),
+            s2_config,
+            vmid: Vmid::alloc()?,
+        })
+    }
+
+    pub fn nest_page_table_root(&self) -> HostPhysAddr {
+        self.npt.root_paddr()
+    }
+
+    pub fn stage2_config(&self) -> Stage2Config {
+        self.s2_config
+    }
+
+    pub fn vmid(&self) -> u16 {
+        self.vmid.id()
+    }
+
+    fn test_free_area(&self, other: &MapRegion<H>) -> bool {
+        if let Some((_, before)) = self.regions.range(..other.start).last() {
+            if before.is_overlap_with(other) {
+                return false;
+            }
+        }
+        if let Some((_, after)) = self.regions.range(other.start..).next() {
+            if after.is_overlap_with(other) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn gpa_to_hpa(&self, gpa: GuestPhysAddr) -> HostPhysAddr {
+        self.npt.query(gpa).unwrap().0
+    }
+
+    /// The host physical address that `gpa` maps to, with the permissions.
+    pub fn query(&self, gpa: GuestPhysAddr) -> RvmResult<(HostPhysAddr, MemFlags)> {
+        let (hpa, flags, _) = self.npt.query(gpa)?;
+        Ok((hpa, flags))
+    }
+
+    pub fn map_region(&mut self, region: MapRegion<H>) -> RvmResult {
+        if region.size == 0 {
+            return Ok(());
+        }
+        if !self.test_free_area(&region) {
+            warn!(
+                "MapRegion({:#x}..{:#x}) overlapped in:\n{:#x?}",
+                region.start,
+                region.start + region.size,
+                self
+            );
+            return Err(RvmError::InvalidParam);
+        }
+        debug!("before region map to");
+        // New mappings replace only invalid entries, which are never cached
+        // in the TLB, so no invalidation is needed here.
+        region.map_to(&mut self.npt)?;
+        self.regions.insert(region.start, region);
+        Ok(())
+    }
+
+    fn find_region_mut(&mut self, gpa: GuestPhysAddr) -> Option<&mut MapRegion<H>> {
+        match self.regions.range_mut(..=gpa).last() {
+            Some((_, region)) if gpa < region.start + region.size => Some(region),
+            _ => None,
+        }
+    }
+
+    /// Whether `gpa` is in a mapped region.
+    ///
+    /// A stage-2 translation fault in a region comes from a concurrent update
+    /// of its mappings, e.g. a block split with break-before-make. That update
+    /// is done under the lock of the set, so once this is called the faulting
+    /// access can be retried.
+    pub fn is_mapped(&self, gpa: GuestPhysAddr) -> bool {
+        find_region(&self.regions, gpa).is_some()
+    }
+
+    /// Change the permissions of the guest physical memory `[start, start + size)`,
+    /// which must lie within one mapped region. They are kept by the region,
+    /// and still write-protected while its writes are logged.
+    pub fn protect_region(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MemFlags,
+    ) -> RvmResult {
+        let region = match self.regions.range_mut(..=start).last() {
+            Some((_, region)) if start + size <= region.start + region.size => region,
+            _ => {
+                warn!(
+                    "protect_region({:#x}..{:#x}) is not within a mapped region",
+                    start,
+                    start + size
+                );
+                return Err(RvmError::InvalidParam);
+            }
+        };
+        let vmid = self.vmid.id();
+        let hw_flags = if region.dirty_log.is_some() {
+            flags - MemFlags::WRITE
+        } else {
+            flags
+        };
+        self.npt.protect(start, size, hw_flags, |ipa| {
+            flush_guest_tlb_range(vmid, ipa, PAGE_SIZE)
+        })?;
+        region.set_flags(start, start + size, flags);
+        flush_guest_tlb_range(vmid, start, size);
+        Ok(())
+    }
+
+    /// Start logging guest writes to the writable RAM region at `start`. The
+    /// whole region is write-protected, and each page is made writable again
+    /// on its first write fault.
+    pub fn enable_dirty_log(&mut self, start: GuestPhysAddr) -> RvmResult {
+        let region = match self.regions.get_mut(&start) {
+            Some(region)
+                if region.flags.contains(MemFlags::WRITE)
+                    && !region.flags.contains(MemFlags::DEVICE) =>
+            {
+                region
+            }
+            _ => return Err(RvmError::InvalidParam),
+        };
+        if region.dirty_log.is_none() {
+            region.dirty_log = Some(DirtyBitmap::new(region.size / PAGE_SIZE));
+            let vmid = self.vmid.id();
+            for (start, size, flags) in region.flag_ranges() {
+                self.npt
+                    .protect(start, size, flags - MemFlags::WRITE, |ipa| {
+                        flush_guest_tlb_range(vmid, ipa, PAGE_SIZE)
+                    })?;
+            }
+            flush_guest_tlb_range(vmid, region.start, region.size);
+        }
+        Ok(())
+    }
+
+    /// Stop logging guest writes to the region at `start`, and restore its
+    /// permissions.
+    pub fn disable_dirty_log(&mut self, start: GuestPhysAddr) -> RvmResult {
+        let region = self.regions.get_mut(&start).ok_or(RvmError::InvalidParam)?;
+        if region.dirty_log.take().is_some() {
+            let vmid = self.vmid.id();
+            for (start, size, flags) in region.flag_ranges() {
+                self.npt.protect(start, size, flags, |ipa| {
+                    flush_guest_tlb_range(vmid, ipa, PAGE_SIZE)
+                })?;
+            }
+            flush_guest_tlb_range(vmid, region.start, region.size);
+        }
+        Ok(())
+    }
+
+    /// Returns the dirty bitmap of the region at `start`, one bit per page.
+    ///
+    /// If `clear` is true, the bitmap is cleared and the reported pages are
+    /// write-protected again, so that the next write to them is logged.
+    pub fn fetch_dirty_log(&mut self, start: GuestPhysAddr, clear: bool) -> RvmResult<Vec<u64>> {
+        let region = self.regions.get(&start).ok_or(RvmError::InvalidParam)?;
+        let dirty_log = region.dirty_log.as_ref().ok_or(RvmError::BadState)?;
+        if !clear {
+            return Ok(dirty_log.snapshot());
+        }
+        let bitmap = dirty_log.fetch_and_clear();
+        let vmid = self.vmid.id();
+        for (i, &word) in bitmap.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let page = region.start + (i * 64 + word.trailing_zeros() as usize) * PAGE_SIZE;
+                let flags = region.flags_at(page) - MemFlags::WRITE;
+                self.npt.protect(page, PAGE_SIZE, flags, |ipa| {
+                    flush_guest_tlb_range(vmid, ipa, PAGE_SIZE)
+                })?;
+                word &= word - 1;
+            }
+        }
+        flush_guest_tlb_range(vmid, region.start, region.size);
+        Ok(bitmap)
+    }
+
+    /// Handles a stage-2 write permission fault at `gpa`. If the page is in a
+    /// dirty-logged region, records it and makes it writable again, then
+    /// returns true so that the faulting access can be retried.
+    pub fn handle_dirty_write(&mut self, gpa: GuestPhysAddr) -> RvmResult<bool> {
+        let region = match self.find_region_mut(gpa) {
+            // Not a fault caused by the logging otherwise.
+            Some(region)
+                if region.dirty_log.is_some() && region.flags_at(gpa).contains(MemFlags::WRITE) =>
+            {
+                region
+            }
+            _ => return Ok(false),
+        };
+        let page_idx = (gpa - region.start) / PAGE_SIZE;
+        region.dirty_log.as_ref().unwrap().set(page_idx);
+        let page_start = region.start + page_idx * PAGE_SIZE;
+        let flags = region.flags_at(page_start);
+        let vmid = self.vmid.id();
+        self.npt.protect(page_start, PAGE_SIZE, flags, |ipa| {
+            flush_guest_tlb_range(vmid, ipa, PAGE_SIZE)
+        })?;
+        flush_guest_tlb_range(vmid, page_start, PAGE_SIZE);
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) {
+        for region in self.regions.values() {
+            region.unmap_to(&mut self.npt, self.vmid.id()).unwrap();
+        }
+        self.regions.clear();
+    }
+}
+
+impl<H: RvmHal> GuestPhysMemorySet<H> {
+    /// Translate the guest virtual address `gva` with the stage-1 registers
+    /// of `vcpu`, as saved on its last VM exit.
+    pub fn gva_to_gpa(&self, vcpu: &RvmVcpu<H>, gva: GuestVirtAddr) -> RvmResult<GuestTranslation> {
+        let ctx = GuestStage1Context::from_sys_regs(vcpu.sys_regs());
+        translate_gva(self, &ctx, gva)
+    }
+}
+
+impl<H: RvmHal> GuestMemoryAccess for GuestPhysMemorySet<H> {
+    fn read_u64(&self, gpa: GuestPhysAddr) -> RvmResult<u64> {
+        if gpa % 8 != 0 {
+            return Err(RvmError::InvalidParam);
+        }
+        let (hpa, flags, _) = self.npt.query(gpa)?;
+        if flags.contains(MemFlags::DEVICE) || !flags.contains(MemFlags::READ) {
+            return Err(RvmError::InvalidParam);
+        }
+        Ok(unsafe { core::ptr::read_volatile(H::phys_to_virt(hpa) as *const u64) })
+    }
+}
+
+impl<H: RvmHal> Drop for GuestPhysMemorySet<H> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<H: RvmHal> Debug for GuestPhysMemorySet<H> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("GuestPhysMemorySet")
+            .field("page_table_root", &self.nest_page_table_root())
+            .field("regions", &self.regions)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::HeapHal;
+
+    #[test]
+    fn test_offset_target() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let region = MapRegion::<HeapHal>::new_offset(0x4000_0000, 0x8000_0000, 0x10000, rw);
+        assert_eq!(region.target(0x4000_1234), 0x8000_1234);
+        // Mapped below the host address, e.g. a GICV frame as the GICC.
+        let region = MapRegion::<HeapHal>::new_offset(0x0801_0000, 0x0804_0000, 0x10000, rw);
+        assert_eq!(region.target(0x0801_0010), 0x0804_0010);
+    }
+
+    #[test]
+    fn test_region_flag_ranges() {
+        let (rw, ro) = (MemFlags::READ | MemFlags::WRITE, MemFlags::READ);
+        let mut region = MapRegion::<HeapHal>::new_offset(0x4000_0000, 0x2000_0000, 0x10000, rw);
+        region.set_flags(0x4000_2000, 0x4000_6000, ro);
+        region.set_flags(0x4000_4000, 0x4000_8000, MemFlags::empty());
+        assert_eq!(region.flags_at(0x4000_1000), rw);
+        assert_eq!(region.flags_at(0x4000_3000), ro);
+        assert_eq!(region.flags_at(0x4000_7000), MemFlags::empty());
+        assert_eq!(
+            region.flag_ranges(),
+            [
+                (0x4000_0000, 0x2000, rw),
+                (0x4000_2000, 0x2000, ro),
+                (0x4000_4000, 0x4000, MemFlags::empty()),
+                (0x4000_8000, 0x8000, rw),
+            ]
+        );
+        // Restoring the region permissions removes the range.
+        region.set_flags(0x4000_3000, 0x4000_5000, rw);
+        assert_eq!(
+            region.flag_ranges(),
+            [
+                (0x4000_0000, 0x2000, rw),
+                (0x4000_2000, 0x1000, ro),
+                (0x4000_3000, 0x2000, rw),
+                (0x4000_5000, 0x3000, MemFlags::empty()),
+                (0x4000_8000, 0x8000, rw),
+            ]
+        );
+        region.set_flags(0x4000_0000, 0x4001_0000, ro);
+        assert_eq!(region.flag_ranges(), [(0x4000_0000, 0x10000, ro)]);
+    }
+
+    #[test]
+    fn test_fault_during_split() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut npt =
+            NestedPageTable::<HeapHal>::new_stage2(&Stage2Config::with_pa_bits(40, 40).unwrap())
+                .unwrap();
+        let region = MapRegion::<HeapHal>::new_offset(0x4000_0000, 0x8000_0000, 0x40_0000, rw);
+        region.map_to(&mut npt).unwrap();
+        let mut regions = BTreeMap::new();
+        regions.insert(region.start, region);
+        assert_eq!(npt.query(0x4020_1000).unwrap().2, PageSize::Size2M);
+
+        // Write-protect a page, as for dirty logging. While the 2M block is
+        // invalid, a fault in it is in a mapped region and must be retried.
+        let mut split = Vec::new();
+        npt.protect(0x4020_1000, PAGE_SIZE, MemFlags::READ, |ipa| {
+            for gpa in [0x4020_0000, ipa, 0x403f_f000] {
+                assert!(find_region(&regions, gpa).is_some());
+            }
+            split.push(ipa);
+        })
+        .unwrap();
+        assert_eq!(split, [0x4020_1000]);
+        assert_eq!(
+            npt.query(0x4020_1000).unwrap(),
+            (0x8020_1000, MemFlags::READ, PageSize::Size4K)
+        );
+        assert!(find_region(&regions, 0x3fff_f000).is_none());
+        assert!(find_region(&regions, 0x4040_0000).is_none());
+    }
+}
@@ -0,0 +1,64 @@
+//! An [`RvmHal`] backed by the global heap allocator.
+//!
+//! Physical addresses are the addresses of the heap allocations, so page
+//! tables built with it can be inspected in software, e.g. in host unit tests.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+use super::{HostPhysAddr, HostVirtAddr, PAGE_SIZE};
+use crate::RvmHal;
+
+/// Largest alignment of contiguous allocations, in log2 of pages. Enough for
+/// guest memory mapped with 2M blocks.
+const MAX_ALIGN_LOG2: usize = 9;
+
+/// A [`RvmHal`] that allocates pages from the heap, with identical physical
+/// and virtual addresses.
+pub struct HeapHal;
+
+impl HeapHal {
+    fn page_layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    fn contiguous_layout(num_pages: usize) -> Option<Layout> {
+        Layout::from_size_align(num_pages * PAGE_SIZE, PAGE_SIZE << MAX_ALIGN_LOG2).ok()
+    }
+}
+
+impl RvmHal for HeapHal {
+    fn alloc_page() -> Option<HostPhysAddr> {
+        let ptr = unsafe { alloc_zeroed(Self::page_layout()) };
+        (!ptr.is_null()).then_some(ptr as HostPhysAddr)
+    }
+
+    fn dealloc_page(paddr: HostPhysAddr) {
+        unsafe { dealloc(paddr as *mut u8, Self::page_layout()) }
+    }
+
+    fn alloc_contiguous_pages(num_pages: usize, align_log2: usize) -> Option<HostPhysAddr> {
+        // A single page is freed with `dealloc_page`, so it must have the same
+        // layout.
+        if num_pages == 1 && align_log2 == 0 {
+            return Self::alloc_page();
+        }
+        if num_pages <= 1 || align_log2 > MAX_ALIGN_LOG2 {
+            return None;
+        }
+        let ptr = unsafe { alloc_zeroed(Self::contiguous_layout(num_pages)?) };
+        (!ptr.is_null()).then_some(ptr as HostPhysAddr)
+    }
+
+    fn dealloc_contiguous_pages(paddr: HostPhysAddr, num_pages: usize) {
+        let layout = Self::contiguous_layout(num_pages).unwrap();
+        unsafe { dealloc(paddr as *mut u8, layout) }
+    }
+
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        paddr
+    }
+
+    fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
+        vaddr
+    }
+}
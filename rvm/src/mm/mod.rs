@@ -1,13 +1,52 @@
+mod gpm;
+mod heap_hal;
 mod page_table;
 
 use core::marker::PhantomData;
 
 use crate::{RvmHal, RvmResult};
 
+pub use gpm::{DirtyBitmap, GuestMemoryRegion, GuestPhysMemorySet, MapRegion};
+pub use heap_hal::HeapHal;
 pub use page_table::{GenericPTE, Level4PageTable};
 
 pub const PAGE_SIZE: usize = 0x1000;
 
+/// The page sizes that a leaf (page or block) entry can map with the 4K granule.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum PageSize {
+    /// Size of a 4K page, mapped by a level-3 page descriptor.
+    Size4K = 0x1000,
+    /// Size of a 2M block, mapped by a level-2 block descriptor.
+    Size2M = 0x20_0000,
+    /// Size of a 1G block, mapped by a level-1 block descriptor.
+    Size1G = 0x4000_0000,
+}
+
+impl PageSize {
+    /// Whether this page size is a block (2M or 1G).
+    pub const fn is_huge(self) -> bool {
+        !matches!(self, Self::Size4K)
+    }
+
+    /// Whether `addr` is aligned to this page size.
+    pub const fn is_aligned(self, addr: usize) -> bool {
+        addr & (self as usize - 1) == 0
+    }
+
+    /// Offset of `addr` within a page of this size.
+    pub const fn page_offset(self, addr: usize) -> usize {
+        addr & (self as usize - 1)
+    }
+}
+
+impl From<PageSize> for usize {
+    fn from(size: PageSize) -> usize {
+        size as usize
+    }
+}
+
 /// Guest virtual address.
 pub type GuestVirtAddr = usize;
 /// Guest physical address.
@@ -19,12 +58,112 @@ pub type HostPhysAddr = usize;
 
 bitflags::bitflags! {
     /// Permission and type of a guest physical memory region.
+    ///
+    /// Without any memory type flag, the region is Normal write-back memory,
+    /// Inner Shareable.
     pub struct MemFlags: u64 {
         const READ          = 1 << 0;
         const WRITE         = 1 << 1;
         const EXECUTE       = 1 << 2;
+        /// Device memory, Device-nGnRE unless refined by the next two flags.
         const DEVICE        = 1 << 3;
         const USER          = 1 << 4;
+        /// With `DEVICE`: no early write acknowledgement (Device-nGnRnE).
+        const NO_EARLY_ACK  = 1 << 5;
+        /// With `DEVICE`: gathering and reordering allowed (Device-GRE).
+        const GATHERING     = 1 << 6;
+        /// Normal memory, non-cacheable.
+        const UNCACHED      = 1 << 7;
+        /// Normal memory, write-through cacheable.
+        const WRITE_THROUGH = 1 << 8;
+        /// Normal memory, Non-shareable.
+        const NON_SHAREABLE = 1 << 9;
+        /// Normal memory, Outer Shareable.
+        const OUTER_SHAREABLE = 1 << 10;
+
+        const DEVICE_NGNRNE = Self::DEVICE.bits | Self::NO_EARLY_ACK.bits;
+        const DEVICE_GRE    = Self::DEVICE.bits | Self::GATHERING.bits;
+    }
+}
+
+/// Memory types, as described by [`MemFlags`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemType {
+    DeviceNgnRnE,
+    DeviceNgnRE,
+    DeviceGre,
+    NormalNonCacheable,
+    NormalWriteThrough,
+    NormalWriteBack,
+}
+
+/// Shareability domains of Normal memory, Device memory is always Outer
+/// Shareable.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Shareability {
+    NonShareable,
+    OuterShareable,
+    InnerShareable,
+}
+
+impl MemFlags {
+    const MEM_TYPE_MASK: Self = Self::DEVICE_NGNRNE
+        .union(Self::GATHERING)
+        .union(Self::UNCACHED)
+        .union(Self::WRITE_THROUGH);
+    const SHAREABILITY_MASK: Self = Self::NON_SHAREABLE.union(Self::OUTER_SHAREABLE);
+
+    /// The memory type described by the flags.
+    pub fn mem_type(&self) -> MemType {
+        if self.contains(Self::DEVICE) {
+            if self.contains(Self::NO_EARLY_ACK) {
+                MemType::DeviceNgnRnE
+            } else if self.contains(Self::GATHERING) {
+                MemType::DeviceGre
+            } else {
+                MemType::DeviceNgnRE
+            }
+        } else if self.contains(Self::UNCACHED) {
+            MemType::NormalNonCacheable
+        } else if self.contains(Self::WRITE_THROUGH) {
+            MemType::NormalWriteThrough
+        } else {
+            MemType::NormalWriteBack
+        }
+    }
+
+    /// The shareability described by the flags, ignored for Device memory.
+    pub fn shareability(&self) -> Shareability {
+        if self.contains(Self::NON_SHAREABLE) {
+            Shareability::NonShareable
+        } else if self.contains(Self::OUTER_SHAREABLE) {
+            Shareability::OuterShareable
+        } else {
+            Shareability::InnerShareable
+        }
+    }
+
+    /// Replace the memory type flags with `mem_type`.
+    pub fn with_mem_type(self, mem_type: MemType) -> Self {
+        let type_flags = match mem_type {
+            MemType::DeviceNgnRnE => Self::DEVICE_NGNRNE,
+            MemType::DeviceNgnRE => Self::DEVICE,
+            MemType::DeviceGre => Self::DEVICE_GRE,
+            MemType::NormalNonCacheable => Self::UNCACHED,
+            MemType::NormalWriteThrough => Self::WRITE_THROUGH,
+            MemType::NormalWriteBack => Self::empty(),
+        };
+        (self - Self::MEM_TYPE_MASK) | type_flags
+    }
+
+    /// Replace the shareability flags with `shareability`.
+    pub fn with_shareability(self, shareability: Shareability) -> Self {
+        let sh_flags = match shareability {
+            Shareability::NonShareable => Self::NON_SHAREABLE,
+            Shareability::OuterShareable => Self::OUTER_SHAREABLE,
+            Shareability::InnerShareable => Self::empty(),
+        };
+        (self - Self::SHAREABILITY_MASK) | sh_flags
     }
 }
 
@@ -37,11 +176,12 @@ pub struct NestedPageFaultInfo {
     pub fault_guest_paddr: GuestPhysAddr,
 }
 
-/// A 4K-sized contiguous physical memory page, it will deallocate the page
-/// automatically on drop.
+/// One or more 4K-sized contiguous physical memory pages, it will deallocate
+/// the pages automatically on drop.
 #[derive(Debug)]
 pub struct PhysFrame<H: RvmHal> {
     start_paddr: HostPhysAddr,
+    num_pages: usize,
     _phantom: PhantomData<H>,
 }
 
@@ -53,6 +193,7 @@ impl<H: RvmHal> PhysFrame<H> {
         debug!("[RVM] allocated PhysFrame({:#x})", start_paddr);
         Ok(Self {
             start_paddr,
+            num_pages: 1,
             _phantom: PhantomData,
         })
     }
@@ -65,9 +206,34 @@ impl<H: RvmHal> PhysFrame<H> {
         Ok(f)
     }
 
+    /// Allocates `num_pages` zeroed contiguous pages, with the start address
+    /// aligned to `1 << align_log2` pages.
+    pub fn alloc_contiguous_zero(num_pages: usize, align_log2: usize) -> RvmResult<Self> {
+        let start_paddr = H::alloc_contiguous_pages(num_pages, align_log2)
+            .ok_or_else(|| rvm_err_type!(OutOfMemory, "allocate contiguous frames failed"))?;
+        assert_ne!(start_paddr, 0);
+        debug!(
+            "[RVM] allocated PhysFrame({:#x}, {} pages)",
+            start_paddr, num_pages
+        );
+        let mut f = Self {
+            start_paddr,
+            num_pages,
+            _phantom: PhantomData,
+        };
+        f.fill(0);
+        Ok(f)
+    }
+
+    /// A placeholder frame without memory, which deallocates nothing on drop.
+    ///
+    /// # Safety
+    ///
+    /// The frame must not be accessed.
     pub const unsafe fn uninit() -> Self {
         Self {
             start_paddr: 0,
+            num_pages: 0,
             _phantom: PhantomData,
         }
     }
@@ -81,14 +247,18 @@ impl<H: RvmHal> PhysFrame<H> {
     }
 
     pub fn fill(&mut self, byte: u8) {
-        unsafe { core::ptr::write_bytes(self.as_mut_ptr(), byte, PAGE_SIZE) }
+        unsafe { core::ptr::write_bytes(self.as_mut_ptr(), byte, PAGE_SIZE * self.num_pages) }
     }
 }
 
 impl<H: RvmHal> Drop for PhysFrame<H> {
     fn drop(&mut self) {
         if self.start_paddr > 0 {
-            H::dealloc_page(self.start_paddr);
+            if self.num_pages == 1 {
+                H::dealloc_page(self.start_paddr);
+            } else {
+                H::dealloc_contiguous_pages(self.start_paddr, self.num_pages);
+            }
             debug!("[RVM] deallocated PhysFrame({:#x})", self.start_paddr);
         }
     }
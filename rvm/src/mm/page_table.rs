@@ -1,37 +1,20 @@
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, vec::Vec};
 use core::{fmt::Debug, marker::PhantomData};
 
-use super::{MemFlags, PhysFrame, PAGE_SIZE};
+use super::{MemFlags, PageSize, PhysFrame, PAGE_SIZE};
 use crate::{RvmHal, RvmResult};
 
-const LEVELS: usize = 4;
+const LAST_LEVEL: usize = 3;
 const ENTRY_COUNT: usize = 512;
+/// At most 16 tables can be concatenated at the starting level.
+const MAX_ROOT_TABLES: usize = 16;
 
 type VirtAddr = super::GuestPhysAddr;
 type PhysAddr = super::HostPhysAddr;
 
-const fn p4_index(vaddr: VirtAddr) -> usize {
-    (vaddr >> (12 + 27)) & (ENTRY_COUNT - 1)
-}
-
-const fn p3_index(vaddr: VirtAddr) -> usize {
-    (vaddr >> (12 + 18)) & (ENTRY_COUNT - 1)
-}
-
-const fn p2_index(vaddr: VirtAddr) -> usize {
-    (vaddr >> (12 + 9)) & (ENTRY_COUNT - 1)
-}
-
-const fn p1_index(vaddr: VirtAddr) -> usize {
-    (vaddr >> 12) & (ENTRY_COUNT - 1)
-}
-
-const fn align_down(addr: usize) -> usize {
-    addr & !(PAGE_SIZE - 1)
-}
-
-const fn page_offset(addr: usize) -> usize {
-    addr & (PAGE_SIZE - 1)
+/// The lowest address bit translated by a table at `level`.
+const fn level_shift(level: usize) -> usize {
+    12 + (LAST_LEVEL - level) * 9
 }
 
 pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
@@ -55,50 +38,111 @@ pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
     fn clear(&mut self);
 }
 
-/// A generic 4-level page table structures.
+/// A generic page table structures with the 4K granule.
+///
+/// The table walk starts at `start_level` (0 to 2) and translates `va_bits`
+/// bits of input address. As in stage-2 translation, the root may consist of
+/// up to 16 concatenated tables, which saves one level of lookup.
 pub struct Level4PageTable<H: RvmHal, PTE: GenericPTE> {
-    root_paddr: PhysAddr,
-    intrm_tables: Vec<PhysFrame<H>>,
+    root_entries: usize,
+    start_level: usize,
+    va_bits: usize,
+    root_table: PhysFrame<H>,
+    intrm_tables: BTreeMap<PhysAddr, PhysFrame<H>>,
     _phantom: PhantomData<PTE>,
 }
 
 impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
-    /// Create a page table instance.
+    /// Create a 4-level page table instance with 48-bit input addresses.
     pub fn new() -> RvmResult<Self> {
-        let root_frame = PhysFrame::alloc_zero()?;
-        debug!("new page table.");
+        Self::new_with_geometry(48, 0)
+    }
+
+    /// Create a page table instance which translates `va_bits` bits of input
+    /// address, with the table walk starting at `start_level`.
+    pub fn new_with_geometry(va_bits: usize, start_level: usize) -> RvmResult<Self> {
+        if start_level >= LAST_LEVEL
+            || va_bits <= level_shift(start_level)
+            || va_bits > level_shift(start_level) + 9 + MAX_ROOT_TABLES.trailing_zeros() as usize
+        {
+            return rvm_err!(
+                InvalidParam,
+                format_args!(
+                    "unsupported page table geometry: {} bits from level {}",
+                    va_bits, start_level
+                )
+            );
+        }
+        let root_entries = 1 << (va_bits - level_shift(start_level));
+        let root_pages = (root_entries * core::mem::size_of::<PTE>() + PAGE_SIZE - 1) / PAGE_SIZE;
+        // Concatenated tables must be aligned to their total size.
+        let root_table =
+            PhysFrame::alloc_contiguous_zero(root_pages, root_pages.trailing_zeros() as usize)?;
+        debug!(
+            "new page table: {} bits from level {}, {} root page(s).",
+            va_bits, start_level, root_pages
+        );
         Ok(Self {
-            root_paddr: root_frame.start_paddr(),
-            intrm_tables: vec![root_frame],
+            root_entries,
+            start_level,
+            va_bits,
+            root_table,
+            intrm_tables: BTreeMap::new(),
             _phantom: PhantomData,
         })
     }
 
     /// Physical address of the page table root.
     pub fn root_paddr(&self) -> PhysAddr {
-        self.root_paddr
+        self.root_table.start_paddr()
+    }
+
+    /// The level at which the table walk starts.
+    pub fn start_level(&self) -> usize {
+        self.start_level
+    }
+
+    /// Number of input address bits translated by this page table.
+    pub fn va_bits(&self) -> usize {
+        self.va_bits
     }
 
     /// Create a mapping from the virtual address `vaddr` to the physical address
     /// `paddr`, with memory permissions and types described by `flags`.
-    pub fn map(&mut self, vaddr: VirtAddr, paddr: PhysAddr, flags: MemFlags) -> RvmResult {
-        let entry = self.get_entry_mut_or_create(vaddr)?;
+    ///
+    /// A huge `page_size` installs a level-1 (1G) or level-2 (2M) block
+    /// descriptor, both addresses must be aligned to it.
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        page_size: PageSize,
+        flags: MemFlags,
+    ) -> RvmResult {
+        if !page_size.is_aligned(vaddr) || !page_size.is_aligned(paddr) {
+            return rvm_err!(
+                InvalidParam,
+                format_args!(
+                    "try to map an unaligned {:?} page {:#x} -> {:#x}",
+                    page_size, vaddr, paddr
+                )
+            );
+        }
+        let entry = self.get_entry_mut_or_create(vaddr, page_size)?;
         if !entry.is_unused() {
             return rvm_err!(
                 InvalidParam,
                 format_args!("try to map an already mapped page {:#x}", vaddr)
             );
         }
-        *entry = GenericPTE::new_page(align_down(paddr), flags, false);
-        if vaddr == 0x8000 {
-            info!("vaddr 0x8000, paddr {:x} flags {:?}", paddr, flags);
-        }
+        *entry = GenericPTE::new_page(paddr, flags, page_size.is_huge());
         Ok(())
     }
 
-    /// Remove mappings for the virtual address `vaddr`.
-    pub fn unmap(&mut self, vaddr: VirtAddr) -> RvmResult<PhysAddr> {
-        let entry = self.get_entry_mut(vaddr)?;
+    /// Remove mappings for the virtual address `vaddr`, return the physical
+    /// address and the size of the page (or block) it was mapped to.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> RvmResult<(PhysAddr, PageSize)> {
+        let (entry, page_size) = self.get_entry_mut(vaddr)?;
         if entry.is_unused() {
             return rvm_err!(
                 InvalidParam,
@@ -107,21 +151,21 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
         }
         let paddr = entry.paddr();
         entry.clear();
-        Ok(paddr)
+        Ok((paddr, page_size))
     }
 
     /// Query the mapping target for the virtual address `vaddr`, return the
-    /// target physical address and memory permissions.
-    pub fn query(&self, vaddr: VirtAddr) -> RvmResult<(PhysAddr, MemFlags)> {
-        let entry = self.get_entry_mut(vaddr)?;
+    /// target physical address, memory permissions and the page size.
+    pub fn query(&self, vaddr: VirtAddr) -> RvmResult<(PhysAddr, MemFlags, PageSize)> {
+        let (entry, page_size) = self.get_entry_mut(vaddr)?;
         if entry.is_unused() {
             return rvm_err!(
                 InvalidParam,
                 format_args!("queried page {:#x} is not mapped", vaddr)
             );
         }
-        let off = page_offset(vaddr);
-        Ok((entry.paddr() + off, entry.flags()))
+        let off = page_size.page_offset(vaddr);
+        Ok((entry.paddr() + off, entry.flags(), page_size))
     }
 
     /// Update the mapping target for the virtual address `vaddr`.
@@ -131,10 +175,141 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
         paddr: Option<PhysAddr>,
         flags: Option<MemFlags>,
     ) -> RvmResult {
-        let entry = self.get_entry_mut(vaddr)?;
-        let paddr = align_down(paddr.unwrap_or_else(|| entry.paddr()));
+        let (entry, page_size) = self.get_entry_mut(vaddr)?;
+        let paddr = paddr.unwrap_or_else(|| entry.paddr()) & !(page_size as usize - 1);
         let flags = flags.unwrap_or_else(|| entry.flags());
-        *entry = GenericPTE::new_page(paddr, flags, entry.is_huge());
+        *entry = GenericPTE::new_page(paddr, flags, page_size.is_huge());
+        Ok(())
+    }
+
+    /// Map a contiguous region of `size` bytes from `vaddr` to `paddr`.
+    ///
+    /// If `allow_huge` is true, each step uses the largest block size that
+    /// the alignment of both addresses and the remaining size allow.
+    pub fn map_region(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: usize,
+        flags: MemFlags,
+        allow_huge: bool,
+    ) -> RvmResult {
+        if !PageSize::Size4K.is_aligned(vaddr | paddr | size) {
+            return rvm_err!(
+                InvalidParam,
+                format_args!(
+                    "try to map an unaligned region {:#x} -> {:#x}",
+                    vaddr, paddr
+                )
+            );
+        }
+        let (mut vaddr, mut paddr, mut size) = (vaddr, paddr, size);
+        while size > 0 {
+            let page_size = if allow_huge {
+                largest_page_size(vaddr, paddr, size, self.start_level)
+            } else {
+                PageSize::Size4K
+            };
+            self.map(vaddr, paddr, page_size, flags)?;
+            vaddr += page_size as usize;
+            paddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Remove mappings of a contiguous region of `size` bytes from `vaddr`.
+    ///
+    /// Blocks inside the region are removed as a whole, the region must not
+    /// cover a block partially. The whole region must be mapped, nothing is
+    /// unmapped otherwise.
+    ///
+    /// Intermediate tables which become empty are detached from the page
+    /// table and returned. They are deallocated on drop, which must be done
+    /// after invalidating the TLB entries of the region, as the table walker
+    /// may still cache them.
+    pub fn unmap_region(&mut self, vaddr: VirtAddr, size: usize) -> RvmResult<Vec<PhysFrame<H>>> {
+        let (start, end) = (vaddr, vaddr + size);
+        let mut vaddr = start;
+        while vaddr < end {
+            let (entry, page_size) = self.get_entry_mut(vaddr)?;
+            if entry.is_unused() {
+                return rvm_err!(
+                    InvalidParam,
+                    format_args!("try to unmap an unmapped page {:#x}", vaddr)
+                );
+            }
+            if !page_size.is_aligned(vaddr) || vaddr + page_size as usize > end {
+                return rvm_err!(
+                    InvalidParam,
+                    format_args!(
+                        "try to unmap a part of the {:?} block at {:#x}",
+                        page_size, vaddr
+                    )
+                );
+            }
+            vaddr += page_size as usize;
+        }
+        let mut vaddr = start;
+        while vaddr < end {
+            let (_, page_size) = self.unmap(vaddr)?;
+            vaddr += page_size as usize;
+        }
+        let mut freed = Vec::new();
+        self.reclaim_empty_tables(
+            self.root_table_mut(),
+            self.start_level,
+            0,
+            start,
+            end,
+            &mut freed,
+        );
+        debug!(
+            "unmapped [{:#x}, {:#x}), {} table(s) reclaimed.",
+            start,
+            end,
+            freed.len()
+        );
+        Ok(freed)
+    }
+
+    /// Change the memory permissions and types of all mappings in the region
+    /// of `size` bytes from `vaddr` to `flags`, keeping the mapping targets.
+    ///
+    /// Blocks which are only partially covered by the region are first split
+    /// into next-level tables, with break-before-make: the block entry is
+    /// invalidated, then `flush_block` is called with an address in the block
+    /// to invalidate its TLB entries, before the table is installed. The caller
+    /// is responsible for invalidating the TLB entries of the region afterwards.
+    pub fn protect(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: MemFlags,
+        mut flush_block: impl FnMut(VirtAddr),
+    ) -> RvmResult {
+        if !PageSize::Size4K.is_aligned(vaddr | size) {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("try to protect an unaligned region {:#x}", vaddr)
+            );
+        }
+        let (mut vaddr, end) = (vaddr, vaddr + size);
+        while vaddr < end {
+            let (entry, page_size) = self.get_entry_mut(vaddr)?;
+            if entry.is_unused() {
+                return rvm_err!(
+                    InvalidParam,
+                    format_args!("try to protect an unmapped page {:#x}", vaddr)
+                );
+            }
+            if !page_size.is_aligned(vaddr) || vaddr + page_size as usize > end {
+                self.split_block(vaddr, &mut flush_block)?;
+                continue;
+            }
+            *entry = GenericPTE::new_page(entry.paddr(), flags, page_size.is_huge());
+            vaddr += page_size as usize;
+        }
         Ok(())
     }
 
@@ -142,8 +317,8 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
     pub fn dump(&self, limit: usize) {
         info!("Root: {:x?}", self.root_paddr());
         self.walk(
-            self.table_of(self.root_paddr()),
-            0,
+            self.root_table_mut(),
+            self.start_level,
             0,
             limit,
             &|level: usize, idx: usize, vaddr: VirtAddr, entry: &PTE| {
@@ -157,9 +332,9 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
 }
 
 impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
-    fn table_of<'a>(&self, paddr: PhysAddr) -> &'a [PTE] {
-        let ptr = H::phys_to_virt(paddr) as *const PTE;
-        unsafe { core::slice::from_raw_parts(ptr, ENTRY_COUNT) }
+    fn root_table_mut<'a>(&self) -> &'a mut [PTE] {
+        let ptr = H::phys_to_virt(self.root_paddr()) as *mut PTE;
+        unsafe { core::slice::from_raw_parts_mut(ptr, self.root_entries) }
     }
 
     fn table_of_mut<'a>(&self, paddr: PhysAddr) -> &'a mut [PTE] {
@@ -190,38 +365,135 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
     fn alloc_intrm_table(&mut self) -> RvmResult<PhysAddr> {
         let frame = PhysFrame::alloc_zero()?;
         let paddr = frame.start_paddr();
-        self.intrm_tables.push(frame);
+        self.intrm_tables.insert(paddr, frame);
         Ok(paddr)
     }
 
-    fn get_entry_mut(&self, vaddr: VirtAddr) -> RvmResult<&mut PTE> {
-        let p4 = self.table_of_mut(self.root_paddr());
-        let p4e = &mut p4[p4_index(vaddr)];
-
-        let p3 = self.next_table_mut(p4e)?;
-        let p3e = &mut p3[p3_index(vaddr)];
-
-        let p2 = self.next_table_mut(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
+    /// Replace the block containing `vaddr` with a next-level table which maps
+    /// the same range with the same flags. The block entry is invalidated and
+    /// flushed with `flush_block` first, so that the TLB never holds both the
+    /// block and the pages replacing it.
+    fn split_block(&mut self, vaddr: VirtAddr, flush_block: impl FnOnce(VirtAddr)) -> RvmResult {
+        let (entry, page_size) = self.get_entry_mut(vaddr)?;
+        let sub_size = match page_size {
+            PageSize::Size1G => PageSize::Size2M,
+            PageSize::Size2M => PageSize::Size4K,
+            PageSize::Size4K => return rvm_err!(BadState, "try to split a 4K page"),
+        };
+        let (paddr, flags) = (entry.paddr(), entry.flags());
+        let table_paddr = self.alloc_intrm_table()?;
+        for (i, sub_entry) in self.table_of_mut(table_paddr).iter_mut().enumerate() {
+            *sub_entry =
+                GenericPTE::new_page(paddr + i * sub_size as usize, flags, sub_size.is_huge());
+        }
+        let entry = self.get_entry_mut(vaddr)?.0;
+        entry.clear();
+        flush_block(vaddr);
+        *entry = GenericPTE::new_table(table_paddr);
+        Ok(())
+    }
 
-        let p1 = self.next_table_mut(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok(p1e)
+    /// Detach the intermediate tables under `table` (which translates from
+    /// `base`) that cover part of `[start, end)` and have no entries left.
+    fn reclaim_empty_tables(
+        &mut self,
+        table: &mut [PTE],
+        level: usize,
+        base: VirtAddr,
+        start: VirtAddr,
+        end: VirtAddr,
+        freed: &mut Vec<PhysFrame<H>>,
+    ) {
+        if level == LAST_LEVEL {
+            return;
+        }
+        let shift = level_shift(level);
+        for (i, entry) in table.iter_mut().enumerate() {
+            let entry_start = base + (i << shift);
+            let entry_end = entry_start + (1 << shift);
+            if entry_end <= start || entry_start >= end || entry.is_unused() || is_block(entry) {
+                continue;
+            }
+            let next_paddr = entry.paddr();
+            let next_table = self.table_of_mut(next_paddr);
+            self.reclaim_empty_tables(next_table, level + 1, entry_start, start, end, freed);
+            if next_table.iter().all(|e| e.is_unused()) {
+                entry.clear();
+                freed.extend(self.intrm_tables.remove(&next_paddr));
+            }
+        }
     }
 
-    fn get_entry_mut_or_create(&mut self, vaddr: VirtAddr) -> RvmResult<&mut PTE> {
-        let p4 = self.table_of_mut(self.root_paddr());
-        let p4e = &mut p4[p4_index(vaddr)];
+    fn entry_index(&self, vaddr: VirtAddr, level: usize) -> usize {
+        let entries = if level == self.start_level {
+            self.root_entries
+        } else {
+            ENTRY_COUNT
+        };
+        (vaddr >> level_shift(level)) & (entries - 1)
+    }
 
-        let p3 = self.next_table_mut_or_create(p4e)?;
-        let p3e = &mut p3[p3_index(vaddr)];
+    fn check_vaddr(&self, vaddr: VirtAddr) -> RvmResult {
+        if vaddr >> self.va_bits != 0 {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("address {:#x} exceeds {} bits", vaddr, self.va_bits)
+            );
+        }
+        Ok(())
+    }
 
-        let p2 = self.next_table_mut_or_create(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
+    fn get_entry_mut(&self, vaddr: VirtAddr) -> RvmResult<(&mut PTE, PageSize)> {
+        self.check_vaddr(vaddr)?;
+        let mut table = self.root_table_mut();
+        let mut level = self.start_level;
+        loop {
+            let entry = &mut table[self.entry_index(vaddr, level)];
+            if level == LAST_LEVEL {
+                return Ok((entry, PageSize::Size4K));
+            }
+            if is_block(entry) {
+                return match level {
+                    1 => Ok((entry, PageSize::Size1G)),
+                    2 => Ok((entry, PageSize::Size2M)),
+                    _ => rvm_err!(BadState, "block entry at level 0"),
+                };
+            }
+            table = self.next_table_mut(entry)?;
+            level += 1;
+        }
+    }
 
-        let p1 = self.next_table_mut_or_create(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok(p1e)
+    fn get_entry_mut_or_create(
+        &mut self,
+        vaddr: VirtAddr,
+        page_size: PageSize,
+    ) -> RvmResult<&mut PTE> {
+        self.check_vaddr(vaddr)?;
+        let target_level = match page_size {
+            PageSize::Size4K => 3,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 1,
+        };
+        if target_level < self.start_level {
+            return rvm_err!(
+                InvalidParam,
+                format_args!(
+                    "{:?} blocks are not supported from level {}",
+                    page_size, self.start_level
+                )
+            );
+        }
+        let mut table = self.root_table_mut();
+        let mut level = self.start_level;
+        loop {
+            let entry = &mut table[self.entry_index(vaddr, level)];
+            if level == target_level {
+                return Ok(entry);
+            }
+            table = self.next_table_mut_or_create(entry)?;
+            level += 1;
+        }
     }
 
     fn walk(
@@ -234,10 +506,10 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
     ) {
         let mut n = 0;
         for (i, entry) in table.iter().enumerate() {
-            let vaddr = start_vaddr + (i << (12 + (LEVELS - 1 - level) * 9));
+            let vaddr = start_vaddr + (i << level_shift(level));
             if entry.is_present() {
                 func(level, i, vaddr, entry);
-                if level < LEVELS - 1 && !entry.is_huge() {
+                if level < LAST_LEVEL && !is_block(entry) {
                     let table_entry = self.next_table_mut(entry).unwrap();
                     self.walk(table_entry, level + 1, vaddr, limit, func);
                 }
@@ -249,3 +521,25 @@ impl<H: RvmHal, PTE: GenericPTE> Level4PageTable<H, PTE> {
         }
     }
 }
+
+/// Whether a non-last level entry is a block descriptor.
+fn is_block<PTE: GenericPTE>(entry: &PTE) -> bool {
+    !entry.is_unused() && entry.is_huge()
+}
+
+/// The largest page size that `vaddr`, `paddr` and the remaining `size` allow,
+/// in a table whose walk starts at `start_level`.
+fn largest_page_size(
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    size: usize,
+    start_level: usize,
+) -> PageSize {
+    [PageSize::Size1G, PageSize::Size2M]
+        .into_iter()
+        .filter(|&page_size| page_size != PageSize::Size1G || start_level <= 1)
+        .find(|&page_size| {
+            page_size.is_aligned(vaddr) && page_size.is_aligned(paddr) && size >= page_size as usize
+        })
+        .unwrap_or(PageSize::Size4K)
+}
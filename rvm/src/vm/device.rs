@@ -0,0 +1,145 @@
+//! Devices emulated for a VM: MMIO devices and trapped system registers.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ops::Range;
+
+use crate::arch::SysReg;
+use crate::mm::{GuestPhysAddr, GuestPhysMemorySet};
+use crate::{RvmHal, RvmResult, RvmVcpu};
+
+/// A device emulated on guest accesses to its MMIO registers.
+pub trait MmioDevice<H: RvmHal>: Send + Sync {
+    /// The guest physical address range of the registers.
+    fn mem_range(&self) -> Range<GuestPhysAddr>;
+    /// Emulate a read of `access_size` bytes at `addr` by the vCPU `vcpu_id`,
+    /// which selects the banked registers of per-CPU devices.
+    fn read(&self, vcpu_id: usize, addr: GuestPhysAddr, access_size: u8) -> RvmResult<u32>;
+    /// Emulate a write of `access_size` bytes at `addr` by the vCPU `vcpu_id`.
+    /// `gpm` is the memory of the VM, e.g. for devices doing DMA.
+    fn write(
+        &self,
+        vcpu_id: usize,
+        addr: GuestPhysAddr,
+        val: u32,
+        access_size: u8,
+        gpm: &GuestPhysMemorySet<H>,
+    ) -> RvmResult;
+}
+
+/// Emulation of trapped accesses to a system register.
+pub trait SysRegHandler<H: RvmHal>: Send + Sync {
+    fn read(&self, vcpu: &mut RvmVcpu<H>, reg: SysReg) -> RvmResult<u64>;
+    fn write(&self, vcpu: &mut RvmVcpu<H>, reg: SysReg, val: u64) -> RvmResult;
+}
+
+/// The emulated devices of a VM, looked up on VM exits.
+pub struct DeviceBus<H: RvmHal> {
+    mmio_devices: Vec<Arc<dyn MmioDevice<H>>>,
+    sysreg_handlers: BTreeMap<SysReg, Arc<dyn SysRegHandler<H>>>,
+}
+
+impl<H: RvmHal> DeviceBus<H> {
+    /// Create a bus without devices.
+    pub fn new() -> Self {
+        Self {
+            mmio_devices: Vec::new(),
+            sysreg_handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Add an MMIO device, whose registers must not overlap the ones of the
+    /// other devices.
+    pub fn add_mmio_device(&mut self, dev: Arc<dyn MmioDevice<H>>) -> RvmResult {
+        let range = dev.mem_range();
+        if let Some(other) = self.mmio_devices.iter().find(|d| {
+            let r = d.mem_range();
+            r.start < range.end && range.start < r.end
+        }) {
+            return rvm_err!(
+                AlreadyExists,
+                format_args!(
+                    "MMIO range {:#x?} overlaps {:#x?}",
+                    range,
+                    other.mem_range()
+                )
+            );
+        }
+        self.mmio_devices.push(dev);
+        Ok(())
+    }
+
+    /// The MMIO device whose registers contain `addr`.
+    pub fn find_mmio_device(&self, addr: GuestPhysAddr) -> Option<&Arc<dyn MmioDevice<H>>> {
+        self.mmio_devices
+            .iter()
+            .find(|dev| dev.mem_range().contains(&addr))
+    }
+
+    /// Register `handler` to emulate the trapped accesses to `reg`, replacing
+    /// the previous handler of `reg`.
+    pub fn register_sysreg_handler(&mut self, reg: SysReg, handler: Arc<dyn SysRegHandler<H>>) {
+        self.sysreg_handlers.insert(reg, handler);
+    }
+
+    /// The handler of the system register `reg`.
+    pub fn sysreg_handler(&self, reg: SysReg) -> Option<&Arc<dyn SysRegHandler<H>>> {
+        self.sysreg_handlers.get(&reg)
+    }
+}
+
+impl<H: RvmHal> Default for DeviceBus<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::HeapHal;
+
+    struct Dummy(Range<GuestPhysAddr>);
+
+    impl MmioDevice<HeapHal> for Dummy {
+        fn mem_range(&self) -> Range<GuestPhysAddr> {
+            self.0.clone()
+        }
+
+        fn read(&self, _: usize, addr: GuestPhysAddr, _access_size: u8) -> RvmResult<u32> {
+            Ok((addr - self.0.start) as u32)
+        }
+
+        fn write(
+            &self,
+            _: usize,
+            _: GuestPhysAddr,
+            _: u32,
+            _: u8,
+            _: &GuestPhysMemorySet<HeapHal>,
+        ) -> RvmResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mmio_devices() {
+        let mut bus = DeviceBus::<HeapHal>::new();
+        bus.add_mmio_device(Arc::new(Dummy(0x0900_0000..0x0900_1000)))
+            .unwrap();
+        bus.add_mmio_device(Arc::new(Dummy(0x0800_0000..0x0801_0000)))
+            .unwrap();
+        assert!(bus
+            .add_mmio_device(Arc::new(Dummy(0x0800_f000..0x0801_1000)))
+            .is_err());
+        // Adjacent ranges do not overlap.
+        bus.add_mmio_device(Arc::new(Dummy(0x0801_0000..0x0802_0000)))
+            .unwrap();
+
+        let dev = bus.find_mmio_device(0x0900_0018).unwrap();
+        assert_eq!(dev.read(0, 0x0900_0018, 4).unwrap(), 0x18);
+        let dev = bus.find_mmio_device(0x0801_0000).unwrap();
+        assert_eq!(dev.mem_range(), 0x0801_0000..0x0802_0000);
+        assert!(bus.find_mmio_device(0x0900_1000).is_none());
+        assert!(bus.find_mmio_device(0x07ff_ffff).is_none());
+    }
+}
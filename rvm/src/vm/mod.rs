@@ -0,0 +1,357 @@
+//! Virtual machines: the guest memory, vCPUs and devices of a guest.
+
+mod device;
+
+use alloc::vec::Vec;
+
+use spin::{Mutex, MutexGuard, Once};
+
+pub use self::device::{DeviceBus, MmioDevice, SysRegHandler};
+use crate::arch::{GuestTraps, VmCounter};
+use crate::mm::{GuestPhysAddr, GuestPhysMemorySet};
+use crate::{RvmHal, RvmPerCpu, RvmResult, RvmVcpu};
+
+/// Largest number of vCPUs of a VM, the vCPU ID is the Aff0 field of MPIDR.
+pub const MAX_VCPUS: usize = 256;
+
+/// Configuration of a VM.
+#[derive(Debug, Clone, Copy)]
+pub struct RvmVmConfig {
+    /// Number of vCPUs.
+    pub num_vcpus: usize,
+    /// Size of the guest physical address space, in bits.
+    pub ipa_bits: usize,
+    /// Entry point of the boot vCPU, vCPU 0.
+    pub entry: GuestPhysAddr,
+    /// Value of x0 on the boot vCPU, e.g. the address of the device tree.
+    pub boot_arg: u64,
+    /// Guest operations trapped to the hypervisor, on all vCPUs.
+    pub traps: GuestTraps,
+}
+
+/// Lifecycle state of a VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// Created, vCPUs and devices can be set up.
+    Created,
+    /// Started, its vCPUs can be powered on.
+    Running,
+    /// Paused, its vCPUs do not run guest code and the virtual counter stops.
+    Paused,
+    /// Stopped, all vCPUs are powered off for good.
+    Stopped,
+}
+
+/// Power state of a vCPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuState {
+    /// Not running guest code.
+    Off,
+    /// Powered on, but not yet resumed by the physical CPU running it.
+    OnPending,
+    /// Running guest code.
+    On,
+}
+
+struct VcpuPower {
+    state: VcpuState,
+    entry: GuestPhysAddr,
+    arg: u64,
+}
+
+/// A created vCPU, and the physical CPU running it.
+struct VcpuCell<H: RvmHal> {
+    cpu_id: usize,
+    vcpu: Mutex<RvmVcpu<H>>,
+}
+
+struct VcpuSlot<H: RvmHal> {
+    vcpu: Once<VcpuCell<H>>,
+    power: Mutex<VcpuPower>,
+}
+
+/// A VM, which owns its guest physical memory, vCPUs and emulated devices.
+///
+/// Each vCPU is created and run on its own physical CPU, the VM is shared
+/// between them. A physical CPU only holds the lock of its vCPU while running
+/// it, and does not run it while the VM is paused.
+pub struct RvmVm<H: RvmHal> {
+    config: RvmVmConfig,
+    state: Mutex<VmState>,
+    gpm: Mutex<GuestPhysMemorySet<H>>,
+    counter: Mutex<VmCounter>,
+    vcpus: Vec<VcpuSlot<H>>,
+    devices: DeviceBus<H>,
+}
+
+impl<H: RvmHal> RvmVm<H> {
+    /// Create a VM without memory and devices, and with all vCPUs powered off.
+    /// A VMID is allocated, and the virtual counter starts at zero.
+    pub fn new(config: RvmVmConfig) -> RvmResult<Self> {
+        if config.num_vcpus == 0 || config.num_vcpus > MAX_VCPUS {
+            return rvm_err!(
+                InvalidParam,
+                format_args!("invalid number of vCPUs {}", config.num_vcpus)
+            );
+        }
+        let vcpus = (0..config.num_vcpus)
+            .map(|_| VcpuSlot {
+                vcpu: Once::new(),
+                power: Mutex::new(VcpuPower {
+                    state: VcpuState::Off,
+                    entry: 0,
+                    arg: 0,
+                }),
+            })
+            .collect();
+        Ok(Self {
+            config,
+            state: Mutex::new(VmState::Created),
+            gpm: Mutex::new(GuestPhysMemorySet::new(config.ipa_bits)?),
+            counter: Mutex::new(VmCounter::new()),
+            vcpus,
+            devices: DeviceBus::new(),
+        })
+    }
+
+    pub fn config(&self) -> &RvmVmConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> VmState {
+        *self.state.lock()
+    }
+
+    /// The VMID tagging the stage-2 translations of the VM.
+    pub fn vmid(&self) -> u16 {
+        self.gpm.lock().vmid()
+    }
+
+    /// The guest physical memory, and the stage-2 page table mapping it.
+    pub fn gpm(&self) -> MutexGuard<GuestPhysMemorySet<H>> {
+        self.gpm.lock()
+    }
+
+    /// The virtual counter shared by the vCPUs.
+    pub fn counter(&self) -> MutexGuard<VmCounter> {
+        self.counter.lock()
+    }
+
+    pub fn devices(&self) -> &DeviceBus<H> {
+        &self.devices
+    }
+
+    /// The devices, which can only be changed before the VM is shared.
+    pub fn devices_mut(&mut self) -> &mut DeviceBus<H> {
+        &mut self.devices
+    }
+
+    pub fn num_vcpus(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    fn slot(&self, vcpu_id: usize) -> RvmResult<&VcpuSlot<H>> {
+        match self.vcpus.get(vcpu_id) {
+            Some(slot) => Ok(slot),
+            None => rvm_err!(InvalidParam, format_args!("invalid vCPU ID {}", vcpu_id)),
+        }
+    }
+
+    /// Create the vCPU `vcpu_id` on the current physical CPU, which will run
+    /// it. It is powered off until [`RvmVm::power_on_vcpu`].
+    ///
+    /// The vCPU gets the traps of the VM, MPIDR_EL1 with `vcpu_id` as Aff0,
+    /// and the counter offset of the VM.
+    pub fn create_vcpu(&self, vcpu_id: usize, percpu: &RvmPerCpu<H>) -> RvmResult {
+        let slot = self.slot(vcpu_id)?;
+        // Not created if another CPU created it first.
+        let mut created = false;
+        slot.vcpu.try_call_once(|| {
+            let mut vcpu = {
+                let gpm = self.gpm.lock();
+                percpu.create_vcpu(
+                    0,
+                    gpm.nest_page_table_root(),
+                    &gpm.stage2_config(),
+                    gpm.vmid(),
+                )?
+            };
+            vcpu.set_traps(self.config.traps);
+            vcpu.set_mpidr(vcpu_id as u64);
+            vcpu.set_counter_offset(self.counter.lock().offset());
+            created = true;
+            Ok(VcpuCell {
+                cpu_id: vcpu.cpu_id as usize,
+                vcpu: Mutex::new(vcpu),
+            })
+        })?;
+        if !created {
+            return rvm_err!(
+                AlreadyExists,
+                format_args!("vCPU {} is already created", vcpu_id)
+            );
+        }
+        Ok(())
+    }
+
+    /// The vCPU `vcpu_id`, if it has been created. It stays locked by its
+    /// physical CPU while running.
+    pub fn vcpu(&self, vcpu_id: usize) -> Option<&Mutex<RvmVcpu<H>>> {
+        Some(&self.vcpus.get(vcpu_id)?.vcpu.get()?.vcpu)
+    }
+
+    /// The vCPUs created so far.
+    fn created_vcpus(&self) -> impl Iterator<Item = &VcpuCell<H>> {
+        self.vcpus.iter().filter_map(|slot| slot.vcpu.get())
+    }
+
+    /// The power state of the vCPU `vcpu_id`.
+    pub fn vcpu_state(&self, vcpu_id: usize) -> Option<VcpuState> {
+        Some(self.vcpus.get(vcpu_id)?.power.lock().state)
+    }
+
+    /// Power on the vCPU `vcpu_id`, to start at `entry` with `arg` in x0, e.g.
+    /// on a PSCI CPU_ON. Returns the previous power state, the request only
+    /// takes effect if it is [`VcpuState::Off`].
+    ///
+    /// The physical CPU of the vCPU picks the request with
+    /// [`RvmVm::take_power_on`].
+    pub fn power_on_vcpu(
+        &self,
+        vcpu_id: usize,
+        entry: GuestPhysAddr,
+        arg: u64,
+    ) -> RvmResult<VcpuState> {
+        // Held so that the VM is not stopped meanwhile.
+        let state = self.state.lock();
+        if *state != VmState::Running {
+            return rvm_err!(BadState, "the VM is not running");
+        }
+        let mut power = self.slot(vcpu_id)?.power.lock();
+        let prev = power.state;
+        if prev == VcpuState::Off {
+            *power = VcpuPower {
+                state: VcpuState::OnPending,
+                entry,
+                arg,
+            };
+        }
+        Ok(prev)
+    }
+
+    /// Take the pending power-on request of the vCPU `vcpu_id`, and mark it
+    /// as running. Returns its entry point and the value of x0.
+    pub fn take_power_on(&self, vcpu_id: usize) -> Option<(GuestPhysAddr, u64)> {
+        let mut power = self.vcpus.get(vcpu_id)?.power.lock();
+        if power.state != VcpuState::OnPending {
+            return None;
+        }
+        power.state = VcpuState::On;
+        Some((power.entry, power.arg))
+    }
+
+    /// Power off the vCPU `vcpu_id`, e.g. on a PSCI CPU_OFF. Its physical CPU
+    /// stops running it on the next VM exit.
+    pub fn power_off_vcpu(&self, vcpu_id: usize) -> RvmResult {
+        self.slot(vcpu_id)?.power.lock().state = VcpuState::Off;
+        Ok(())
+    }
+
+    /// Start the VM, by powering on the boot vCPU.
+    pub fn start(&self) -> RvmResult {
+        {
+            let mut state = self.state.lock();
+            if *state != VmState::Created {
+                return rvm_err!(BadState, format_args!("can not start a {:?} VM", *state));
+            }
+            *state = VmState::Running;
+        }
+        self.power_on_vcpu(0, self.config.entry, self.config.boot_arg)?;
+        Ok(())
+    }
+
+    /// Restart the running VM, e.g. on a PSCI SYSTEM_RESET: power off all its
+    /// vCPUs, then power on the boot vCPU at the entry point again. The guest
+    /// memory and the devices are kept.
+    pub fn reset(&self) -> RvmResult {
+        {
+            let state = self.state.lock();
+            if *state != VmState::Running {
+                return rvm_err!(BadState, format_args!("can not reset a {:?} VM", *state));
+            }
+            for slot in &self.vcpus {
+                slot.power.lock().state = VcpuState::Off;
+            }
+        }
+        for cell in self.created_vcpus() {
+            H::kick_cpu(cell.cpu_id);
+        }
+        self.power_on_vcpu(0, self.config.entry, self.config.boot_arg)?;
+        Ok(())
+    }
+
+    /// Pause the running VM, and stop its virtual counter. Returns once no vCPU
+    /// runs guest code.
+    ///
+    /// It waits for the locks of the vCPUs, so it must not be called while
+    /// holding one, e.g. on a VM exit of the VM.
+    pub fn pause(&self) -> RvmResult {
+        {
+            let mut state = self.state.lock();
+            if *state != VmState::Running {
+                return rvm_err!(BadState, format_args!("can not pause a {:?} VM", *state));
+            }
+            *state = VmState::Paused;
+        }
+        for cell in self.created_vcpus() {
+            H::kick_cpu(cell.cpu_id);
+            // Held by the physical CPU until the vCPU exits.
+            drop(cell.vcpu.lock());
+        }
+        self.counter.lock().pause();
+        Ok(())
+    }
+
+    /// Resume the paused VM. The virtual counter restarts from where it was
+    /// paused, and the counter offsets of all vCPUs are updated.
+    pub fn resume(&self) -> RvmResult {
+        let state = self.state();
+        if state != VmState::Paused {
+            return rvm_err!(BadState, format_args!("can not resume a {:?} VM", state));
+        }
+        let offset = {
+            let mut counter = self.counter.lock();
+            counter.resume();
+            counter.offset()
+        };
+        for cell in self.created_vcpus() {
+            cell.vcpu.lock().set_counter_offset(offset);
+        }
+        {
+            let mut state = self.state.lock();
+            // Unless stopped meanwhile.
+            if *state == VmState::Paused {
+                *state = VmState::Running;
+            }
+        }
+        for cell in self.created_vcpus() {
+            H::kick_cpu(cell.cpu_id);
+        }
+        Ok(())
+    }
+
+    /// Stop the VM, and power off all its vCPUs. It can not be started again.
+    /// The running vCPUs are kicked, to stop on their next VM exit.
+    pub fn stop(&self) {
+        {
+            let mut state = self.state.lock();
+            *state = VmState::Stopped;
+            for slot in &self.vcpus {
+                slot.power.lock().state = VcpuState::Off;
+            }
+        }
+        for cell in self.created_vcpus() {
+            H::kick_cpu(cell.cpu_id);
+        }
+    }
+}